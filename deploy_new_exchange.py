@@ -44,15 +44,19 @@ def setup_topics(topic_name, creds):
     pubsub_service.projects().subscriptions().create(name=f'{book_topic}-sub' % 'subscriptions',
                                                      body={'topic': book_topic % 'topics'}).execute()
 
-    # output of matching engine
+    # public market data from the matching engine
     events_topic = f'{book_topic}-Events'
     pubsub_service.projects().topics().create(name=events_topic % 'topics').execute()
     pubsub_service.projects().subscriptions().create(name=f'{events_topic}-price-sub' % 'subscriptions',
                                                      body={'topic': events_topic % 'topics'}).execute()
-    pubsub_service.projects().subscriptions().create(name=f'{events_topic}-account-sub' % 'subscriptions',
-                                                     body={'topic': events_topic % 'topics'}).execute()
 
-    return book_topic, events_topic
+    # drop copy of every execution report, per owner report topics are created by the engine as needed
+    reports_topic = f'{book_topic}-Reports'
+    pubsub_service.projects().topics().create(name=reports_topic % 'topics').execute()
+    pubsub_service.projects().subscriptions().create(name=f'{reports_topic}-account-sub' % 'subscriptions',
+                                                     body={'topic': reports_topic % 'topics'}).execute()
+
+    return book_topic, events_topic, reports_topic
 
 
 def events_bucket_route(name, events_topic, bucket_name, creds):
@@ -100,11 +104,11 @@ def events_price_route(name, events_topic, bucket_name, creds):
                                                    body=job_data).execute() # job to automatically pipe output from matches to a log
 
 
-def events_account_route(name, events_topic, bucket_name, creds):
+def events_account_route(name, reports_topic, bucket_name, creds):
     dataflow_service = googleapiclient.discovery.build('dataflow', 'v1b3', credentials=creds)
     gcs_path = "gs://dataflow-templates-us-central1/latest/Cloud_PubSub_to_Cloud_PubSub"
     job_data = {
-        "jobName": f"{name}-Events-to-account-updates",
+        "jobName": f"{name}-Reports-to-account-updates",
         "environment": {
             "bypassTempDirValidation": False,
             "tempLocation": f"gs://{bucket_name}/account-temp",
@@ -112,7 +116,7 @@ def events_account_route(name, events_topic, bucket_name, creds):
             "additionalExperiments": []
         },
         "parameters": {
-            "inputSubscription": f"{events_topic % 'subscriptions'}-account-sub",
+            "inputSubscription": f"{reports_topic % 'subscriptions'}-account-sub",
             "outputTopic": "projects/project-steelieman/topics/account-updates"
         }
     }
@@ -211,14 +215,17 @@ def create_price_table(name):
     insert_query = """
         CREATE table {}_price
         (
+            resolution  varchar(2) not null,
             market_time bigint   not null,
             low         float not null,
             high        float not null,
             open        float not null,
             close       float not null,
-            volume      bigint   not null,
+            volume      float not null,
+            vwap        float null,
+            trades      bigint   not null,
             constraint {}_price_pk
-                primary key (market_time)
+                primary key (resolution, market_time)
         );
     """.format(name.lower(), name.lower())
 
@@ -231,17 +238,17 @@ if __name__ == '__main__':
     credentials = service_account.Credentials.from_service_account_file(filename=keypath)
 
     print('Setting up topics')
-    book_topic, events_topic = setup_topics(asset, credentials)
-    print('Set up topics:', ', '.join([book_topic, events_topic]))
+    book_topic, events_topic, reports_topic = setup_topics(asset, credentials)
+    print('Set up topics:', ', '.join([book_topic, events_topic, reports_topic]))
 
     print('Setting up bucket')
     bucket_name = setup_bucket(asset)
     print('Setup bucket:', bucket_name)
 
     print('Setting up event routes')
-    events_bucket_route(asset, events_topic, bucket_name, credentials)
+    events_bucket_route(asset, reports_topic, bucket_name, credentials)
     events_price_route(asset, events_topic, bucket_name, credentials)
-    events_account_route(asset, events_topic, bucket_name, credentials)
+    events_account_route(asset, reports_topic, bucket_name, credentials)
     print('Done setting up event routes')
 
     print('Setting up orderbook vm.')
@@ -0,0 +1,86 @@
+use std::{fmt, io, time::Duration};
+use google_cloud::pubsub;
+use tonic::Code;
+
+// Everything that can stop the engine, by the layer it came from.
+#[derive(Debug)]
+pub enum Error {
+    Config(String),                // an argument or environment variable the engine can't run with
+    Credentials(String),           // pub/sub credentials that are missing or can't be read
+    Transport(Box<pubsub::Error>), // talking to pub/sub, boxed as it dwarfs the others
+    MissingSubscription(String),   // the subscription requests are read from doesn't exist
+    Receiving,                     // the task pulling from the subscription stopped
+    Engine(io::Error),             // journaling or snapshotting the book
+    Encoding(serde_json::Error),   // an envelope that can't be serialized
+    Metrics(hyper::Error),         // serving /metrics
+}
+
+impl Error {
+    // Failures that may go away if tried again: pub/sub being unavailable, overloaded or timing out, the connection
+    // to it dropping. A request pub/sub turns down for what it is (too large, not allowed, for something that
+    // doesn't exist), bad credentials, configuration or local storage won't get better by waiting.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Transport(err) => match &**err {
+                pubsub::Error::Status(status) => matches!(status.code(),
+                    Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted | Code::Internal),
+                pubsub::Error::Transport(_) | pubsub::Error::IO(_) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // pub/sub has no topic or subscription by the name asked for, looking one up fails rather than finding nothing
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::Transport(err) if matches!(&**err, pubsub::Error::Status(status) if status.code() == Code::NotFound))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(err) => write!(f, "configuration: {}", err),
+            Error::Credentials(err) => write!(f, "credentials: {}", err),
+            Error::Transport(err) => write!(f, "pub/sub: {}", err),
+            Error::MissingSubscription(name) => write!(f, "pub/sub: no subscription named {}", name),
+            Error::Receiving => write!(f, "pub/sub: stopped receiving from the subscription"),
+            Error::Engine(err) => write!(f, "engine: {}", err),
+            Error::Encoding(err) => write!(f, "encoding: {}", err),
+            Error::Metrics(err) => write!(f, "metrics: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<pubsub::Error> for Error {
+    fn from(err: pubsub::Error) -> Self { Error::Transport(Box::new(err)) }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self { Error::Engine(err) }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self { Error::Encoding(err) }
+}
+
+// How transient failures are retried, the delay doubles with every attempt up to max_delay.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub(crate) retries: u32,
+    pub(crate) initial_delay: Duration,
+    pub(crate) max_delay: Duration,
+}
+
+impl Backoff {
+    // how long to wait before trying again after the given failed attempt (counting from 0), None to give up
+    pub fn delay(&self, attempt: u32, err: &Error) -> Option<Duration> {
+        if attempt >= self.retries || !err.is_transient() {
+            return None;
+        }
+
+        Some(self.initial_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay))
+    }
+}
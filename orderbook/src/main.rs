@@ -1,95 +1,391 @@
 mod orderbook;
+mod error;
+mod metrics;
 
-use std::{env, io::{self, Write}};
+use std::{collections::{HashMap, hash_map::Entry}, env, net::SocketAddr, path::Path, process, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::future::try_join_all;
 use google_cloud::pubsub;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 use google_cloud::authorize::ApplicationCredentials;
-use serde::{Serialize, Deserialize};
 
 use orderbook::book::OrderBook;
-use crate::orderbook::book::{BookRequest, BookResult};
-
-macro_rules! assert_ok {
-    ($expr:expr) => {
-        match $expr {
-            Ok(value) => value,
-            Err(err) => {
-                panic!("asserted result is an Error: {}", err);
-            }
-        }
-    };
-}
+use crate::orderbook::engine::{Engine, Batch, Publication, Route};
+use crate::orderbook::journal::Journal;
+use crate::orderbook::order::timestamp;
+use crate::orderbook::allocation::{Allocation, ProRata};
+use crate::orderbook::risk::RiskLimits;
+use crate::orderbook::fees::FeeSchedule;
+use crate::orderbook::candles::Interval;
+use crate::error::{Error, Backoff};
+use crate::metrics::Metrics;
 
-macro_rules! assert_some {
-    ($expr:expr) => {
-        match $expr {
-            Some(value) => value,
-            None => {
-                panic!("asserted result is None");
+// Run an operation until it succeeds, waiting longer after every transient failure. Gives up with the error once
+// the retries are used up or on a failure trying again won't fix.
+macro_rules! retry {
+    ($backoff:expr, $what:expr, $op:expr) => {{
+        let mut attempt = 0;
+        loop {
+            match $op.await.map_err(Error::from) {
+                Ok(value) => break Ok(value),
+                Err(err) => match $backoff.delay(attempt, &err) {
+                    Some(delay) => {
+                        eprintln!("{} failed ({}), retrying in {:?}", $what, err, delay);
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    },
+                    None => break Err(err),
+                },
             }
         }
-    };
-}
-
-#[derive(Serialize, Deserialize)]
-struct Events {
-    asset: String,
-    events: Vec<BookResult>
+    }};
 }
 
 #[tokio::main]
 async fn main() {
-    let asset = env::args().nth(1).expect("error: expected asset name as argument");
+    if let Err(err) = run().await {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Error> {
+    let asset = env::args().nth(1).ok_or_else(|| Error::Config("expected asset name as argument".to_string()))?;
+    let backoff = backoff_from_env();
 
     println!("Setting up Google pub/sub for the asset {}.", asset);
-    io::stdout().flush().unwrap();
     let sub_name = format!("{}-sub", asset);
-    let events_topic = format!("{}-Events", asset);
 
-    let mut client = assert_ok!(setup_client().await);
-    let mut topic = assert_some!(assert_ok!(client.topic(&events_topic).await));
-    let mut subscription = assert_some!(assert_ok!(client.subscription(&sub_name).await));
+    let mut client = retry!(backoff, "connecting to pub/sub", setup_client())?;
+    let subscription = found(retry!(backoff, "looking up the subscription", client.subscription(&sub_name)))?
+        .ok_or(Error::MissingSubscription(sub_name))?;
 
     println!("Creating orderbook for asset {}", asset);
-    io::stdout().flush().unwrap();
-    let mut orderbook = OrderBook::new();
+    let mut orderbook = allocation_from_env().map_or_else(OrderBook::new, OrderBook::with_allocation);
+    // the limits every owner starts with, they can be changed while running with a SetLimits admin request
+    orderbook.set_limits(None, risk_limits_from_env());
+    // balances are always kept, orders the owner can't cover are only rejected when asked to
+    if env::var("LEDGER_ENFORCED").is_ok_and(|enforced| enforced == "true") {
+        orderbook.enforce_balances();
+    }
+    // the fee schedule is given as JSON, e.g. {"tiers": [{"min_volume": "0", "maker_rate": "-0.0002", "taker_rate": "0.0005"}], "minimum_fee": "0.01"}
+    if let Ok(schedule) = env::var("FEE_SCHEDULE") {
+        orderbook.set_fees(serde_json::from_str::<FeeSchedule>(&schedule)
+            .map_err(|err| Error::Config(format!("FEE_SCHEDULE is not a valid fee schedule: {}", err)))?);
+    }
+    // comma separated, e.g. 1s,1m,1h, every interval but 1s unless set
+    let intervals = match env::var("CANDLE_INTERVALS") {
+        Ok(intervals) => intervals.split(',')
+            .map(|interval| interval.trim().parse::<Interval>())
+            .collect::<Result<Vec<Interval>, String>>()
+            .map_err(Error::Config)?,
+        Err(_) => Interval::DEFAULT.to_vec(),
+    };
+    orderbook.set_candle_intervals(&intervals);
+
+    // every published envelope is journaled so it can be retransmitted, and numbering survives a restart
+    let journal_path = env::var("JOURNAL_PATH").unwrap_or_else(|_| format!("{}.journal", asset));
+    let journal = Journal::open(Path::new(&journal_path))?;
+    // redelivered requests carrying the same client order or request id are bounced within the window
+    let dedup_window = env::var("DEDUP_WINDOW_SECS").ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .unwrap_or(60);
+    // how many of the most recent trades are kept for trade queries
+    let tape_capacity = env::var("TAPE_CAPACITY").ok()
+        .and_then(|capacity| capacity.parse::<usize>().ok())
+        .unwrap_or(100_000);
+    // the book is snapshotted after every batch, a restart carries on from the last one
+    let snapshot_path = env::var("SNAPSHOT_PATH").unwrap_or_else(|_| format!("{}.snapshot", asset));
+    let mut engine = Engine::recover(asset.clone(), orderbook, journal, Path::new(&snapshot_path), dedup_window, tape_capacity)?;
+    println!("Restored orderbook from {}", snapshot_path);
+
+    // scraped by Prometheus, e.g. 0.0.0.0:9100
+    let metrics_addr = env::var("METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:9100".to_string()).parse::<SocketAddr>()
+        .map_err(|err| Error::Config(format!("METRICS_ADDR is not a socket address: {}", err)))?;
+    let metrics = Arc::new(Mutex::new(Metrics::new(asset.clone())));
+    metrics::lock(&metrics).update(&engine);
+    metrics::serve(metrics_addr, metrics.clone())?;
+
+    serve(&mut engine, &mut client, subscription, &asset, Path::new(&snapshot_path), backoff, &metrics).await
+}
+
+// handle requests and sweep expired orders until asked to stop, or until something fails for good
+async fn serve(engine: &mut Engine, client: &mut pubsub::Client, subscription: pubsub::Subscription, asset: &str, snapshot_path: &Path, backoff: Backoff, metrics: &Mutex<Metrics>) -> Result<(), Error> {
+    let mut topics = owner_topics(client, asset, backoff).await?;
+    let mut dead_letters = topic(client, &format!("{}-DeadLetters", asset), backoff).await?;
+
+    // owners' topics are provisioned along with their accounts, so the list is read again every so often
+    let refresh_secs = env::var("OWNER_TOPICS_REFRESH_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(60);
+    let mut refresh = tokio::time::interval(Duration::from_secs(refresh_secs));
+    refresh.tick().await; // the first tick is immediate and the list was just read
+
+    // expired orders are swept on a timer so they leave the book even when no requests come in
+    let sweep_secs = env::var("EXPIRY_SWEEP_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(1);
+    let mut sweep = tokio::time::interval(Duration::from_secs(sweep_secs));
+
+    // Requests are handled a batch at a time, up to BATCH_SIZE of them arriving within BATCH_LINGER_MS of the
+    // first. Whatever a batch publishes goes out as one message per route. No linger only takes what has been
+    // pulled already.
+    let batch_size = env::var("BATCH_SIZE").ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(100);
+    let batch_linger = Duration::from_millis(env::var("BATCH_LINGER_MS").ok()
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .unwrap_or(0));
+    let pull = pubsub::ReceiveOptions { return_immediately: false, max_messages: batch_size.min(i32::MAX as usize) as i32 };
+    let name = subscription.id().to_string();
+    let mut received = receive(subscription, pull, batch_size);
+
+    // Receiving swallows pub/sub failures and just pulls again, so once nothing has come in for RECEIVE_TIMEOUT_SECS
+    // the subscription is looked up to tell a quiet book from one that can't reach pub/sub. The lookup is retried
+    // with the usual backoff and gives up like any other call once the retries are used up.
+    let receive_timeout = Duration::from_secs(env::var("RECEIVE_TIMEOUT_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(60));
+    let mut idle = tokio::time::interval(receive_timeout);
+    let mut last_received = Instant::now();
+
+    let mut terminate = signal(SignalKind::terminate())?;
 
     loop {
-        if let Some(mut msg) = subscription.receive().await {
-            assert_ok!(msg.ack().await);
+        tokio::select! {
+            first = received.recv() => {
+                let mut messages = vec![first.ok_or(Error::Receiving)?];
+                let linger = tokio::time::Instant::now() + batch_linger;
+
+                while messages.len() < batch_size {
+                    match tokio::time::timeout_at(linger, received.recv()).await {
+                        Ok(Some(received)) => messages.push(received),
+                        _ => break,
+                    }
+                }
+
+                last_received = Instant::now();
+
+                // handled in the order they arrived, messages that aren't requests for this book are bounced
+                // back to whoever sent them
+                let mut publications = Vec::new();
+                for (msg, _) in &messages {
+                    publications.append(&mut engine.process_message(msg.id(), msg.data())?);
+                }
 
-            // read the message as a book request
-            if let Ok(book_request) = serde_json::from_slice::<BookRequest>(msg.data()) {
-                let events = Events {
-                    asset: asset.clone(),
-                    events: orderbook.process_request(book_request),
-                };
+                // Checkpointed before anything goes out. Stopping before this rolls the batch back on the next
+                // start, its messages are redelivered and handled as if for the first time.
+                engine.checkpoint(snapshot_path)?;
 
-                let out_msg = assert_ok!(serde_json::to_vec(&events));
+                publish_all(client, &mut topics, &mut dead_letters, asset, publications, backoff).await?;
+
+                {
+                    let mut metrics = metrics::lock(metrics);
+                    metrics.update(engine);
+
+                    for (msg, received) in &messages {
+                        metrics.observe_latency(received.elapsed());
+                        metrics.observe_queue_lag(queue_lag(msg, *received));
+                    }
+                }
+
+                // Only acked once everything they caused is out. Stopping before then leaves the messages to be
+                // redelivered, what they published the first time is journaled and published again.
+                try_join_all(messages.iter_mut().map(|(msg, _)| async move {
+                    retry!(backoff, "acknowledging a message", msg.ack())
+                })).await?;
+
+                println!("Processed {} requests", messages.len());
+            },
+            _ = sweep.tick() => {
+                // Expiries journaled before the engine stopped but never published are only in the journal,
+                // consumers find them missing from the event numbering and ask for a retransmission.
+                let publications = engine.expire_orders(timestamp())?;
+                engine.checkpoint(snapshot_path)?;
+
+                publish_all(client, &mut topics, &mut dead_letters, asset, publications, backoff).await?;
+
+                metrics::lock(metrics).update(engine);
+            },
+            _ = refresh.tick() => {
+                let owners = owner_topics(client, asset, backoff).await?;
+                topics.retain(|route, _| !matches!(route, Route::Owner(_)));
+                topics.extend(owners);
+            },
+            _ = idle.tick() => {
+                if last_received.elapsed() >= receive_timeout {
+                    found(retry!(backoff, "checking the subscription", client.subscription(&name)))?
+                        .ok_or_else(|| Error::MissingSubscription(name.clone()))?;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                println!("Interrupted, shutting down");
+                return Ok(());
+            },
+            _ = terminate.recv() => {
+                println!("Terminated, shutting down");
+                return Ok(());
+            },
+        }
+    }
+}
 
-                assert_ok!(topic.publish(out_msg).await);
+// Pull from the subscription in a task of its own, so a pull in flight is never dropped halfway when the sweep
+// or another timer fires first. Messages come out in the order they were received, with when they were.
+fn receive(mut subscription: pubsub::Subscription, pull: pubsub::ReceiveOptions, capacity: usize) -> mpsc::Receiver<(pubsub::Message, Instant)> {
+    let (sender, received) = mpsc::channel(capacity);
 
-                println!("Processed!");
-            } else {
-                println!("Failed to parse {} into a book request", std::str::from_utf8(msg.data()).unwrap());
+    tokio::spawn(async move {
+        while let Some(msg) = subscription.receive_with_options(pull.clone()).await {
+            if sender.send((msg, Instant::now())).await.is_err() {
+                break; // the engine has stopped
             }
         }
+    });
+
+    received
+}
+
+// how long a message waited on the subscription before it was received, nothing if the clocks disagree
+fn queue_lag(msg: &pubsub::Message, received: Instant) -> Duration {
+    let waited = chrono::Utc::now().naive_utc() - msg.publish_time();
+    waited.to_std().unwrap_or_default().saturating_sub(received.elapsed())
+}
+
+// Public market data goes to {asset}-Events, the drop copy to {asset}-Reports and each owner's private reports
+// to {asset}-Reports-{owner}. The first two are looked up once and created if missing. Owners' topics never are,
+// anyone can put an owner in a request: they are provisioned with the owner's account and read by owner_topics.
+// Reports for an owner without one only go out on the drop copy, which carries everything anyway, and can be
+// retransmitted to them once their topic is there.
+async fn publish(client: &mut pubsub::Client, topics: &mut HashMap<Route, pubsub::Topic>, asset: &str, batch: Batch, backoff: Backoff) -> Result<(), Error> {
+    let topic = match topics.entry(batch.route) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let name = match batch.route {
+                Route::Public => format!("{}-Events", asset),
+                Route::DropCopy => format!("{}-Reports", asset),
+                Route::Owner(_) => return Ok(()), // not provisioned
+            };
+
+            entry.insert(topic(client, &name, backoff).await?)
+        },
+    };
+
+    // a JSON array of envelopes, oldest first
+    let out_msg = serde_json::to_vec(&batch.envelopes)?;
+
+    retry!(backoff, "publishing", topic.publish(out_msg.clone()))
+}
+
+// Publish what a batch of requests or a sweep caused, route by route. A batch pub/sub turns down for good (rather
+// than one it can't take right now) is reported on {asset}-DeadLetters and left out, instead of stopping the engine
+// with messages that would only be turned down again once redelivered. Its envelopes are still journaled, so
+// consumers find them missing from the numbering and ask for them again.
+async fn publish_all(client: &mut pubsub::Client, topics: &mut HashMap<Route, pubsub::Topic>, dead_letters: &mut pubsub::Topic, asset: &str, publications: Vec<Publication>, backoff: Backoff) -> Result<(), Error> {
+    for batch in Batch::of(publications) {
+        let route = batch.route;
+        let envelopes: Vec<u64> = batch.envelopes.iter().map(|envelope| envelope.sequence).collect();
+
+        match publish(client, topics, asset, batch, backoff).await {
+            Err(err) if !err.is_transient() => {
+                eprintln!("publishing envelopes {:?} on {:?} failed for good ({}), sending them to dead letters", envelopes, route, err);
+
+                let letter = serde_json::to_vec(&serde_json::json!({
+                    "asset": asset,
+                    "route": format!("{:?}", route),
+                    "envelopes": envelopes,
+                    "error": err.to_string(),
+                }))?;
+
+                retry!(backoff, "publishing a dead letter", dead_letters.publish(letter.clone()))?;
+            },
+            published => published?,
+        }
+    }
+
+    Ok(())
+}
+
+// the topic by that name, created if it isn't there yet
+async fn topic(client: &mut pubsub::Client, name: &str, backoff: Backoff) -> Result<pubsub::Topic, Error> {
+    match found(retry!(backoff, "looking up a topic", client.topic(name)))? {
+        Some(topic) => Ok(topic),
+        None => retry!(backoff, "creating a topic", client.create_topic(name, pubsub::TopicConfig::default())),
     }
+}
 
+// Looking up a topic or subscription that isn't there fails with NotFound, taken here as finding nothing.
+fn found<T>(lookup: Result<Option<T>, Error>) -> Result<Option<T>, Error> {
+    match lookup {
+        Err(err) if err.is_not_found() => Ok(None),
+        lookup => lookup,
+    }
+}
+
+// every provisioned owner topic for the asset, named {asset}-Reports-{owner}
+async fn owner_topics(client: &mut pubsub::Client, asset: &str, backoff: Backoff) -> Result<HashMap<Route, pubsub::Topic>, Error> {
+    let prefix = format!("{}-Reports-", asset);
+    let topics = retry!(backoff, "listing topics", client.topics())?;
+
+    Ok(topics.into_iter()
+        .filter_map(|topic| {
+            let owner = topic.id().strip_prefix(&prefix)?.parse().ok()?;
+            Some((Route::Owner(owner), topic))
+        })
+        .collect())
+}
+
+// each asset runs in its own process, so the allocation strategy is configured per instrument,
+// None keeps the default price time priority
+fn allocation_from_env() -> Option<Box<dyn Allocation>> {
+    match env::var("ALLOCATION").as_deref() {
+        Ok("pro-rata") => Some(Box::new(ProRata {
+            top_order: env::var("PRO_RATA_TOP_ORDER").is_ok_and(|top| top == "true"),
+            min_allocation: env::var("PRO_RATA_MIN_ALLOCATION").ok()
+                .and_then(|min| min.parse().ok())
+                .unwrap_or_default(),
+        })),
+        _ => None,
+    }
+}
+
+// each limit is off unless its variable is set
+fn risk_limits_from_env() -> RiskLimits {
+    RiskLimits {
+        max_order_size: env::var("MAX_ORDER_SIZE").ok().and_then(|size| size.parse().ok()),
+        max_notional: env::var("MAX_NOTIONAL").ok().and_then(|notional| notional.parse().ok()),
+        max_open_orders: env::var("MAX_OPEN_ORDERS").ok().and_then(|orders| orders.parse().ok()),
+        max_position: env::var("MAX_POSITION").ok().and_then(|position| position.parse().ok()),
+        max_messages_per_sec: env::var("MAX_MESSAGES_PER_SEC").ok().and_then(|messages| messages.parse().ok()),
+    }
+}
+
+// how often and how patiently transient pub/sub failures are retried before the engine gives up
+fn backoff_from_env() -> Backoff {
+    Backoff {
+        retries: env::var("RETRY_ATTEMPTS").ok().and_then(|retries| retries.parse().ok()).unwrap_or(5),
+        initial_delay: Duration::from_millis(env::var("RETRY_BACKOFF_MS").ok().and_then(|ms| ms.parse().ok()).unwrap_or(100)),
+        max_delay: Duration::from_millis(env::var("RETRY_MAX_BACKOFF_MS").ok().and_then(|ms| ms.parse().ok()).unwrap_or(10_000)),
+    }
 }
 
-fn load_creds() -> ApplicationCredentials {
-    let pth = std::path::Path::new("./pubsub_keys.json");
+fn load_creds() -> Result<ApplicationCredentials, Error> {
+    let pth = Path::new("./pubsub_keys.json");
     if pth.exists() {
-        let data = assert_ok!(std::fs::read_to_string(pth));
+        let data = std::fs::read_to_string(pth)
+            .map_err(|err| Error::Credentials(format!("can't read {}: {}", pth.display(), err)))?;
         std::env::set_var("RUST_GOOGLE_APPLICATION_CREDENTIALS", data);
     }
     let creds = std::env::var("RUST_GOOGLE_APPLICATION_CREDENTIALS")
-        .expect("env RUST_GOOGLE_APPLICATION_CREDENTIALS not set");
+        .map_err(|_| Error::Credentials("env RUST_GOOGLE_APPLICATION_CREDENTIALS not set".to_string()))?;
     serde_json::from_str::<ApplicationCredentials>(&creds)
-        .expect("incorrect application credentials format")
+        .map_err(|err| Error::Credentials(format!("incorrect application credentials format: {}", err)))
 }
 
-async fn setup_client() -> Result<pubsub::Client, pubsub::Error> {
-    let creds = load_creds();
-    pubsub::Client::from_credentials("project-steelieman", creds).await
+async fn setup_client() -> Result<pubsub::Client, Error> {
+    let creds = load_creds()?;
+    Ok(pubsub::Client::from_credentials("project-steelieman", creds).await?)
 }
@@ -0,0 +1,184 @@
+use std::{convert::Infallible, fmt::Write, net::SocketAddr, sync::{Arc, Mutex, MutexGuard, PoisonError}, time::Duration};
+use hyper::{Body, Request, Response, Server, StatusCode, header};
+use hyper::service::{make_service_fn, service_fn};
+
+use crate::error::Error;
+use crate::orderbook::book::Depth;
+use crate::orderbook::engine::Engine;
+use crate::orderbook::order::OrderDirection;
+use crate::orderbook::stats::Stats;
+
+// seconds from a message being received to everything it caused being published
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// seconds a message waited on the subscription, a backlog can take far longer to work off
+const LAG_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>, // per bucket, the last one for anything above every bound
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: vec![0; bounds.len() + 1], sum: 0.0 }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self.bounds.iter().position(|bound| secs <= *bound).unwrap_or(self.bounds.len());
+
+        self.counts[bucket] += 1;
+        self.sum += secs;
+    }
+
+    // buckets are cumulative in the exposition format
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut count = 0;
+
+        for (bound, bucket) in self.bounds.iter().map(f64::to_string).chain(["+Inf".to_string()]).zip(&self.counts) {
+            count += bucket;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+// What /metrics answers with. The engine's counters and the state of the book are copied in after everything it
+// handles, latencies are observed by the serve loop as messages go through.
+#[derive(Debug)]
+pub struct Metrics {
+    asset: String,
+    stats: Stats,
+    depth: [(OrderDirection, Depth); 2],
+    latency: Histogram,
+    queue_lag: Histogram,
+}
+
+impl Metrics {
+    pub fn new(asset: String) -> Self {
+        Metrics {
+            asset,
+            stats: Stats::new(),
+            depth: [(OrderDirection::Bid, Depth::default()), (OrderDirection::Ask, Depth::default())],
+            latency: Histogram::new(LATENCY_BUCKETS),
+            queue_lag: Histogram::new(LAG_BUCKETS),
+        }
+    }
+
+    pub fn update(&mut self, engine: &Engine) {
+        self.stats = engine.stats().clone();
+
+        for (direction, depth) in self.depth.iter_mut() {
+            *depth = engine.depth(*direction);
+        }
+    }
+
+    pub fn observe_latency(&mut self, latency: Duration) {
+        self.latency.observe(latency);
+    }
+
+    pub fn observe_queue_lag(&mut self, lag: Duration) {
+        self.queue_lag.observe(lag);
+    }
+
+    // the Prometheus text format, every series labelled with the asset
+    pub fn render(&self) -> String {
+        let asset = format!("asset=\"{}\"", self.asset);
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP orderbook_requests_total Requests handled, by type.");
+        let _ = writeln!(out, "# TYPE orderbook_requests_total counter");
+        for (kind, count) in &self.stats.requests {
+            let _ = writeln!(out, "orderbook_requests_total{{{},type=\"{}\"}} {}", asset, kind, count);
+        }
+
+        let _ = writeln!(out, "# HELP orderbook_bounces_total Requests turned down, by reason.");
+        let _ = writeln!(out, "# TYPE orderbook_bounces_total counter");
+        for (reason, count) in &self.stats.bounces {
+            let _ = writeln!(out, "orderbook_bounces_total{{{},reason=\"{}\"}} {}", asset, reason, count);
+        }
+
+        let _ = writeln!(out, "# HELP orderbook_fills_total Trades, each filling a buy and a sell order.");
+        let _ = writeln!(out, "# TYPE orderbook_fills_total counter");
+        let _ = writeln!(out, "orderbook_fills_total{{{}}} {}", asset, self.stats.fills);
+
+        let _ = writeln!(out, "# HELP orderbook_filled_quantity_total Quantity traded.");
+        let _ = writeln!(out, "# TYPE orderbook_filled_quantity_total counter");
+        let _ = writeln!(out, "orderbook_filled_quantity_total{{{}}} {}", asset, self.stats.filled_quantity);
+
+        self.render_depth(&mut out, &asset, "orderbook_depth_levels", "Price levels with resting orders, by side.", |depth| depth.levels.to_string());
+        self.render_depth(&mut out, &asset, "orderbook_depth_quantity", "Visible quantity resting on the book, by side.", |depth| depth.size.to_string());
+        self.render_depth(&mut out, &asset, "orderbook_resting_orders", "Orders resting on the book, by side.", |depth| depth.orders.to_string());
+
+        let _ = writeln!(out, "# HELP orderbook_processing_latency_seconds From receiving a request to publishing its events.");
+        let _ = writeln!(out, "# TYPE orderbook_processing_latency_seconds histogram");
+        self.latency.render(&mut out, "orderbook_processing_latency_seconds", &asset);
+
+        let _ = writeln!(out, "# HELP orderbook_queue_lag_seconds From a request being published to the subscription to it being received.");
+        let _ = writeln!(out, "# TYPE orderbook_queue_lag_seconds histogram");
+        self.queue_lag.render(&mut out, "orderbook_queue_lag_seconds", &asset);
+
+        out
+    }
+
+    // a gauge for each side of the book
+    fn render_depth(&self, out: &mut String, asset: &str, name: &str, help: &str, value: impl Fn(&Depth) -> String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+
+        for (direction, depth) in &self.depth {
+            let side = match direction {
+                OrderDirection::Bid => "bid",
+                OrderDirection::Ask => "ask",
+            };
+
+            let _ = writeln!(out, "{}{{{},side=\"{}\"}} {}", name, asset, side, value(depth));
+        }
+    }
+}
+
+// metrics are only ever written whole, so whatever a panic left behind is still fine to serve
+pub fn lock(metrics: &Mutex<Metrics>) -> MutexGuard<'_, Metrics> {
+    metrics.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Answer GET /metrics on addr in the background, anything else is not found. Only binding can fail here, once
+// the server is up an error is logged and metrics are gone until the next start.
+pub fn serve(addr: SocketAddr, metrics: Arc<Mutex<Metrics>>) -> Result<(), Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let metrics = metrics.clone();
+
+                async move {
+                    let response = match request.uri().path() {
+                        "/metrics" => Response::builder()
+                            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(lock(&metrics).render())),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty()),
+                    };
+
+                    response
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr).map_err(Error::Metrics)?.serve(make_service);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            eprintln!("metrics: {}", err);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::fmt::Debug;
+
+use rust_decimal::prelude::{Decimal, Zero};
+
+use crate::orderbook::order::LimitOrder;
+
+// Splits an incoming quantity among the resting orders at a single price level. Orders are given in time
+// priority and only the ones able to take a fill are passed in, the result holds how much each one gets in
+// the same order. A non all or none order gets at most its shown size, an all or none order its whole total
+// or nothing. Whatever isn't allocated moves on to the next level.
+pub trait Allocation: Debug + Send {
+    fn allocate(&self, orders: &[LimitOrder], quantity: Decimal) -> Vec<Decimal>;
+}
+
+// the most a resting order can be allocated in one go
+fn capacity(order: &LimitOrder) -> Decimal {
+    if order.all_or_none { order.total() } else { order.size }
+}
+
+// hand out quantity in time priority on top of what has been allocated already
+fn allocate_in_time_priority(orders: &[LimitOrder], allocated: &mut [Decimal], quantity: &mut Decimal) {
+    for (order, allocation) in orders.iter().zip(allocated.iter_mut()) {
+        let size = if order.all_or_none {
+            if *allocation == Decimal::zero() && order.total() <= *quantity { order.total() } else { Decimal::zero() }
+        } else {
+            (capacity(order) - *allocation).min(*quantity)
+        };
+
+        *allocation += size;
+        *quantity -= size;
+    }
+}
+
+// price time priority, the first order in the queue is filled before the next one gets anything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fifo;
+
+impl Allocation for Fifo {
+    fn allocate(&self, orders: &[LimitOrder], mut quantity: Decimal) -> Vec<Decimal> {
+        let mut allocated = vec![Decimal::zero(); orders.len()];
+        allocate_in_time_priority(orders, &mut allocated, &mut quantity);
+        allocated
+    }
+}
+
+// Pro rata allocation as offered on futures exchanges:
+//  1) with top order priority the first order in the queue is filled first
+//  2) the rest is shared in proportion to each order's size, rounded down to whole units,
+//     shares below the minimum allocation are dropped
+//  3) whatever rounding and the minimum left over goes out in time priority
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProRata {
+    pub(crate) top_order: bool,
+    pub(crate) min_allocation: Decimal,
+}
+
+impl Allocation for ProRata {
+    fn allocate(&self, orders: &[LimitOrder], mut quantity: Decimal) -> Vec<Decimal> {
+        let mut allocated = vec![Decimal::zero(); orders.len()];
+
+        if self.top_order && !orders.is_empty() {
+            allocate_in_time_priority(&orders[..1], &mut allocated[..1], &mut quantity);
+        }
+
+        let unfilled: Vec<Decimal> = orders.iter().zip(allocated.iter())
+            .map(|(order, allocation)| capacity(order) - *allocation)
+            .collect();
+        let total: Decimal = unfilled.iter().sum();
+
+        if total > Decimal::zero() {
+            let pool = quantity;
+
+            for ((order, allocation), unfilled) in orders.iter().zip(allocated.iter_mut()).zip(unfilled) {
+                let share = (pool * unfilled / total).floor().min(unfilled);
+
+                // an all or none order can't be given part of itself
+                if share == Decimal::zero() || share < self.min_allocation || (order.all_or_none && share < unfilled) {
+                    continue;
+                }
+
+                *allocation += share;
+                quantity -= share;
+            }
+        }
+
+        allocate_in_time_priority(orders, &mut allocated, &mut quantity);
+
+        allocated
+    }
+}
@@ -0,0 +1,54 @@
+use rust_decimal::prelude::{Decimal, Zero};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uncross {
+    pub(crate) price: Decimal,
+    pub(crate) volume: Decimal,
+    pub(crate) surplus: Decimal, // demand - supply at the price, positive means buy side surplus
+}
+
+// Find the single price a call auction uncrosses at. Levels are (price, size) pairs for each side.
+//
+// Candidates are every limit price on either side and are picked by, in order:
+//  1) maximum executable volume
+//  2) minimum absolute surplus
+//  3) market pressure, the highest price if every remaining candidate has a buy surplus,
+//     the lowest price if every remaining candidate has a sell surplus
+//  4) closest to the reference price (the last traded price)
+//  5) the lowest price, so the result never depends on iteration order
+pub fn equilibrium(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)], reference: Option<Decimal>) -> Option<Uncross> {
+    let mut prices: Vec<Decimal> = bids.iter().chain(asks.iter()).map(|(price, _)| *price).collect();
+    prices.sort();
+    prices.dedup();
+
+    let mut candidates: Vec<Uncross> = prices.into_iter().map(|price| {
+        let demand: Decimal = bids.iter().filter(|(p, _)| *p >= price).map(|(_, size)| *size).sum();
+        let supply: Decimal = asks.iter().filter(|(p, _)| *p <= price).map(|(_, size)| *size).sum();
+
+        Uncross {
+            price,
+            volume: demand.min(supply),
+            surplus: demand - supply,
+        }
+    }).filter(|candidate| candidate.volume > Decimal::zero()).collect();
+
+    let max_volume = candidates.iter().map(|c| c.volume).max()?;
+    candidates.retain(|c| c.volume == max_volume);
+
+    let min_surplus = candidates.iter().map(|c| c.surplus.abs()).min()?;
+    candidates.retain(|c| c.surplus.abs() == min_surplus);
+
+    if candidates.iter().all(|c| c.surplus > Decimal::zero()) {
+        return candidates.last().copied();
+    }
+
+    if candidates.iter().all(|c| c.surplus < Decimal::zero()) {
+        return candidates.first().copied();
+    }
+
+    // candidates are still in ascending price order, so min_by_key keeps the lowest price on ties
+    match reference {
+        Some(reference) => candidates.into_iter().min_by_key(|c| (c.price - reference).abs()),
+        None => candidates.first().copied(),
+    }
+}
@@ -1,15 +1,38 @@
 use std::collections::{BTreeMap, BTreeSet, btree_set};
+use std::fmt;
 
 use uuid::Uuid;
 use rust_decimal::prelude::{Decimal, Zero};
+use rust_decimal_macros::dec;
 use serde::{Serialize, Deserialize};
 
-use crate::orderbook::order::{timestamp, generate_uuid, OrderDirection, LimitOrder};
+use crate::orderbook::order::{timestamp, generate_uuid, OrderDirection, OrderType, TimeInForce, Trail, LimitOrder};
+use crate::orderbook::session::TradingPhase;
+use crate::orderbook::auction;
+use crate::orderbook::allocation::{Allocation, Fifo};
+use crate::orderbook::stops::{StopBook, TrailingStop};
+use crate::orderbook::risk::{RiskCheck, RiskLimits};
+use crate::orderbook::ledger::{Ledger, Balance};
+use crate::orderbook::fees::{Fees, FeeSchedule, Liquidity};
+use crate::orderbook::candles::{Candles, CandleEvent, Interval};
+use crate::orderbook::history::OrderHistory;
+use crate::orderbook::snapshot::BookSnapshot;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BookRequest {
     Open(OpenEvent),
     Cancel(CancelEvent),
+    Admin(AdminRequest),
+    OrderStatus(OrderStatusRequest),
+    OpenOrders(OpenOrdersRequest),
+}
+
+// operator requests, these are never published by the rest frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminRequest {
+    SetPhase(PhaseEvent),
+    SetLimits(LimitsEvent),
+    Transfer(TransferEvent),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,11 +41,190 @@ pub enum BookResult {
     Filled(FilledEvent),
     Canceled(CanceledEvent),
     Bounce(BounceEvent),
+    PhaseChanged(PhaseChangedEvent),
+    Indicative(IndicativeEvent),
+    Triggered(TriggeredEvent),
+    Expired(ExpiredEvent),
+    Replaced(ReplacedEvent),
+    TriggerMoved(TriggerMovedEvent),
+    LimitsChanged(LimitsChangedEvent),
+    Traded(TradeEvent),
+    Transferred(TransferredEvent),
+    Candle(CandleEvent),
+    Trades(TradesEvent),
+    OrderStatus(OrderStatusEvent),
+    OpenOrders(OpenOrdersEvent),
+}
+
+impl BookRequest {
+    pub fn request_id(&self) -> Option<Uuid> {
+        match self {
+            BookRequest::Open(open_event) => open_event.request_id,
+            BookRequest::Cancel(cancel_event) => cancel_event.request_id,
+            _ => None,
+        }
+    }
+}
+
+impl BookResult {
+    // the owner of the order the event is about, None for market wide events
+    pub fn owner(&self) -> Option<Uuid> {
+        match self {
+            BookResult::Opened(e) => Some(e.owner),
+            BookResult::Filled(e) => Some(e.owner),
+            BookResult::Canceled(e) => Some(e.owner),
+            BookResult::Bounce(e) => Some(e.owner),
+            BookResult::Triggered(e) => Some(e.owner),
+            BookResult::Expired(e) => Some(e.owner),
+            BookResult::Replaced(e) => Some(e.owner),
+            BookResult::TriggerMoved(e) => Some(e.owner),
+            // the owner whose limits changed, or the operator that changed everyone's
+            BookResult::LimitsChanged(e) => Some(e.trader.unwrap_or(e.owner)),
+            BookResult::Transferred(e) => Some(e.trader),
+            BookResult::Trades(e) => Some(e.owner),
+            BookResult::OrderStatus(e) => Some(e.owner),
+            BookResult::OpenOrders(e) => Some(e.owner),
+            // both sides of a trade get their own FilledEvent
+            BookResult::PhaseChanged(_) | BookResult::Indicative(_) | BookResult::Traded(_) | BookResult::Candle(_) => None,
+        }
+    }
+
+    // The event as public market data, None if it is private to the owner. Owners and client ids are blanked,
+    // only what is visible on the book is kept: orders resting on it and changes to them, trades and the session.
+    // Bounces, stops and their triggers and market orders that never rest are only ever reported privately.
+    pub fn anonymized(&self) -> Option<BookResult> {
+        let mut event = self.clone();
+
+        match &mut event {
+            BookResult::Opened(e) => {
+                if matches!(e.order_type, OrderType::Market | OrderType::Stop { .. } | OrderType::StopLimit { .. } | OrderType::TrailingStop { .. }) {
+                    return None;
+                }
+
+                e.owner = Uuid::nil();
+                e.client_id = None;
+                e.hidden = Decimal::zero();
+            },
+            BookResult::Filled(e) => {
+                // what is left and done of the order would give away an iceberg's hidden reserve, the fee the
+                // owner's fee tier
+                e.owner = Uuid::nil();
+                e.client_id = None;
+                e.leaves = Decimal::zero();
+                e.filled = Decimal::zero();
+                e.fee = Decimal::zero();
+            },
+            BookResult::Canceled(e) => { e.owner = Uuid::nil(); e.client_id = None; },
+            BookResult::Expired(e) => { e.owner = Uuid::nil(); e.client_id = None; },
+            BookResult::Replaced(e) => { e.owner = Uuid::nil(); e.client_id = None; },
+            BookResult::Traded(e) => {
+                e.buyer = Uuid::nil();
+                e.seller = Uuid::nil();
+                e.buyer_fee = Decimal::zero();
+                e.seller_fee = Decimal::zero();
+            },
+            BookResult::PhaseChanged(_) | BookResult::Indicative(_) | BookResult::Candle(_) => {},
+            BookResult::Bounce(_) | BookResult::Triggered(_) | BookResult::TriggerMoved(_) | BookResult::LimitsChanged(_)
+            | BookResult::Transferred(_) | BookResult::Trades(_) | BookResult::OrderStatus(_) | BookResult::OpenOrders(_) => return None,
+        }
+
+        Some(event)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum BounceReason {
     OrderNotFound,
+    MarketClosed,
+    InvalidPhaseTransition,
+    MatchingSuspended,
+    AlreadyExpired,
+    NoReferencePrice,
+    DuplicateRequest,
+    // requests that could never have been accepted, whatever the state of the book
+    MalformedMessage,  // not a request at all, the text says what was wrong with it
+    UnknownInstrument, // meant for another asset's book
+    InvalidPrice,      // a price, trigger, cap or trail that isn't positive or is past MAX_PRICE, or an offset past it
+    InvalidSize,       // a size, peak or minimum quantity that isn't positive or is past MAX_SIZE
+    // requests the book turned down as it stands
+    TradingHalted,
+    NotOwner,          // the order belongs to someone else
+    DuplicateClientId, // the owner already has a live order with the same client id
+    // pre-trade risk, each carries the limit that was hit
+    OrderSizeLimit { limit: Decimal },
+    NotionalLimit { limit: Decimal },
+    OpenOrderLimit { limit: usize },
+    PositionLimit { limit: Decimal },
+    MessageRateLimit { limit: u32 },
+    // balances, only once the ledger is enforced
+    InsufficientBalance { required: Decimal, available: Decimal },
+    NoPriceBound, // a bid that could pay any price can't be covered, e.g. a stop that becomes a market order
+}
+
+impl fmt::Display for BounceReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BounceReason::OrderNotFound => write!(f, "no such order is live on the book"),
+            BounceReason::MarketClosed => write!(f, "the market is closed"),
+            BounceReason::InvalidPhaseTransition => write!(f, "the book can't move to that phase from the current one"),
+            BounceReason::MatchingSuspended => write!(f, "market orders are only accepted during continuous trading"),
+            BounceReason::AlreadyExpired => write!(f, "the order expired before it reached the book"),
+            BounceReason::NoReferencePrice => write!(f, "there is no price to peg the order to"),
+            BounceReason::DuplicateRequest => write!(f, "the request was already handled"),
+            BounceReason::MalformedMessage => write!(f, "the message is not a valid request"),
+            BounceReason::UnknownInstrument => write!(f, "the request is for another instrument"),
+            BounceReason::InvalidPrice => write!(f, "prices, triggers, caps and trails have to be positive and at most {}", MAX_PRICE),
+            BounceReason::InvalidSize => write!(f, "sizes, peaks and minimum quantities have to be positive and at most {}", MAX_SIZE),
+            BounceReason::TradingHalted => write!(f, "trading is halted"),
+            BounceReason::NotOwner => write!(f, "the order belongs to someone else"),
+            BounceReason::DuplicateClientId => write!(f, "a live order already has that client id"),
+            BounceReason::OrderSizeLimit { limit } => write!(f, "the order is larger than the limit of {}", limit),
+            BounceReason::NotionalLimit { limit } => write!(f, "the order is worth more than the limit of {}", limit),
+            BounceReason::OpenOrderLimit { limit } => write!(f, "already at the limit of {} open orders", limit),
+            BounceReason::PositionLimit { limit } => write!(f, "the order could take the position past the limit of {}", limit),
+            BounceReason::MessageRateLimit { limit } => write!(f, "more than {} messages a second", limit),
+            BounceReason::InsufficientBalance { required, available } => write!(f, "{} is needed but only {} is available", required, available),
+            BounceReason::NoPriceBound => write!(f, "the order could pay any price, so the balance can't cover it"),
+        }
+    }
+}
+
+impl BounceReason {
+    // the variant alone, without the limits some reasons carry
+    pub fn name(&self) -> &'static str {
+        match self {
+            BounceReason::OrderNotFound => "OrderNotFound",
+            BounceReason::MarketClosed => "MarketClosed",
+            BounceReason::InvalidPhaseTransition => "InvalidPhaseTransition",
+            BounceReason::MatchingSuspended => "MatchingSuspended",
+            BounceReason::AlreadyExpired => "AlreadyExpired",
+            BounceReason::NoReferencePrice => "NoReferencePrice",
+            BounceReason::DuplicateRequest => "DuplicateRequest",
+            BounceReason::MalformedMessage => "MalformedMessage",
+            BounceReason::UnknownInstrument => "UnknownInstrument",
+            BounceReason::InvalidPrice => "InvalidPrice",
+            BounceReason::InvalidSize => "InvalidSize",
+            BounceReason::TradingHalted => "TradingHalted",
+            BounceReason::NotOwner => "NotOwner",
+            BounceReason::DuplicateClientId => "DuplicateClientId",
+            BounceReason::OrderSizeLimit { .. } => "OrderSizeLimit",
+            BounceReason::NotionalLimit { .. } => "NotionalLimit",
+            BounceReason::OpenOrderLimit { .. } => "OpenOrderLimit",
+            BounceReason::PositionLimit { .. } => "PositionLimit",
+            BounceReason::MessageRateLimit { .. } => "MessageRateLimit",
+            BounceReason::InsufficientBalance { .. } => "InsufficientBalance",
+            BounceReason::NoPriceBound => "NoPriceBound",
+        }
+    }
+}
+
+// The largest price and size an order can have. Their product and the running totals of such products (balances,
+// volumes, candle values) stay far below where Decimal arithmetic overflows.
+pub const MAX_PRICE: Decimal = dec!(10_000_000_000);
+pub const MAX_SIZE: Decimal = dec!(10_000_000_000);
+
+fn valid_price(price: Decimal) -> bool {
+    price > Decimal::zero() && price <= MAX_PRICE
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -33,18 +235,75 @@ pub struct OpenEvent {
     pub(crate) direction: OrderDirection,
     pub(crate) timestamp: i64,
     pub(crate) uuid: Option<Uuid>,
+    #[serde(default)]
+    pub(crate) order_type: OrderType,
+    #[serde(default)]
+    pub(crate) display: Option<Decimal>, // iceberg peak size, only this much of the order is shown at a time
+    #[serde(default)]
+    pub(crate) time_in_force: TimeInForce,
+    #[serde(default)]
+    pub(crate) min_qty: Option<Decimal>, // don't trade on entry unless at least this much executes
+    #[serde(default)]
+    pub(crate) all_or_none: bool,
+    #[serde(default)]
+    pub(crate) client_id: Option<Uuid>,  // the client's own id for the order, echoed on every event about it
+    #[serde(default)]
+    pub(crate) request_id: Option<Uuid>, // identifies this request when it is sent more than once
+}
+
+impl OpenEvent {
+    // whether the order makes sense at all, before anything about the book is looked at
+    fn validate(&self) -> Option<BounceReason> {
+        let price = valid_price;
+        let size = |value: Decimal| value > Decimal::zero() && value <= MAX_SIZE;
+
+        let prices_valid = match self.order_type {
+            OrderType::Limit => price(self.price),
+            OrderType::Market => true, // the price is never looked at
+            OrderType::Stop { trigger } => price(trigger),
+            OrderType::StopLimit { trigger } => price(trigger) && price(self.price),
+            OrderType::Peg { offset, cap, .. } => offset.abs() <= MAX_PRICE && cap.is_none_or(price),
+            OrderType::TrailingStop { trail: Trail::Amount(trail) | Trail::Percent(trail) } => price(trail),
+        };
+
+        if !prices_valid {
+            return Some(BounceReason::InvalidPrice);
+        }
+
+        let sizes_valid = size(self.size)
+            && self.display.is_none_or(size)
+            && self.min_qty.is_none_or(size); // one above the size is held to the size
+
+        if !sizes_valid {
+            return Some(BounceReason::InvalidSize);
+        }
+
+        None
+    }
 }
 
 impl From<OpenEvent> for LimitOrder {
     fn from(open_event: OpenEvent) -> Self {
+        let size = open_event.display.map_or(open_event.size, |peak| peak.min(open_event.size));
+
         Self {
             id: open_event.uuid.unwrap(),
             parent: None,
+            root: open_event.uuid.unwrap(),
             owner: open_event.owner,
             price: open_event.price,
-            size: open_event.size,
+            size,
             direction: open_event.direction,
             timestamp: open_event.timestamp,
+            sequence: 0, // assigned by the order book when the order is placed
+            order_type: open_event.order_type,
+            display: open_event.display,
+            hidden: open_event.size - size,
+            time_in_force: open_event.time_in_force,
+            min_qty: open_event.min_qty,
+            all_or_none: open_event.all_or_none,
+            client_id: open_event.client_id,
+            filled: Decimal::zero(),
         }
     }
 }
@@ -53,11 +312,17 @@ impl From<OpenEvent> for LimitOrder {
 pub struct OpenedEvent { // exactly the same as LimitOrder, just a different name. Hacky!
     pub(crate) id: Uuid,
     pub(crate) parent: Option<Uuid>,
+    #[serde(default = "Uuid::nil")]
+    pub(crate) root: Uuid, // the id the order was placed under, nil in events from before it was reported
     pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
     pub(crate) price: Decimal,
     pub(crate) size: Decimal,
     pub(crate) direction: OrderDirection,
     pub(crate) timestamp: i64,
+    pub(crate) order_type: OrderType,
+    #[serde(default)]
+    pub(crate) hidden: Decimal, // iceberg reserve behind the size, blanked on the public stream
 }
 
 impl From<LimitOrder> for OpenedEvent {
@@ -65,11 +330,15 @@ impl From<LimitOrder> for OpenedEvent {
         Self {
             id: order.id,
             parent: order.parent,
+            root: order.root,
             owner: order.owner,
+            client_id: order.client_id,
             price: order.price,
             size: order.size,
             direction: order.direction,
             timestamp: order.timestamp,
+            order_type: order.order_type,
+            hidden: order.hidden,
         }
     }
 }
@@ -78,10 +347,19 @@ impl From<LimitOrder> for OpenedEvent {
 pub struct FilledEvent {
     pub(crate) id: Uuid,
     pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
     pub(crate) parent: Option<Uuid>,
     pub(crate) price: Decimal,
     pub(crate) size: Decimal,
     pub(crate) timestamp: i64,
+    #[serde(default)]
+    pub(crate) fee: Decimal, // of every trade in the fill, negative for a rebate
+    #[serde(default = "Uuid::nil")]
+    pub(crate) root: Uuid,
+    #[serde(default)]
+    pub(crate) leaves: Decimal, // what is still open of the order after the fill
+    #[serde(default)]
+    pub(crate) filled: Decimal, // cumulative quantity executed since the order was placed, this fill included
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -89,22 +367,253 @@ pub struct CancelEvent {
     pub(crate) id: Uuid,
     pub(crate) owner: Uuid,
     pub(crate) timestamp: i64,
+    #[serde(default)]
+    pub(crate) client_id: Option<Uuid>,
+    #[serde(default)]
+    pub(crate) request_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct CanceledEvent {
     pub(crate) id: Uuid,
     pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) parent: Option<Uuid>,
+    #[serde(default = "Uuid::nil")]
+    pub(crate) root: Uuid,
+    pub(crate) timestamp: i64,
+}
+
+// a pegged order moved to a new price, it keeps its id but starts over at the back of the queue
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReplacedEvent {
+    pub(crate) id: Uuid,
+    pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
     pub(crate) parent: Option<Uuid>,
+    #[serde(default = "Uuid::nil")]
+    pub(crate) root: Uuid,
+    pub(crate) previous_price: Decimal,
+    pub(crate) price: Decimal,
+    pub(crate) size: Decimal,
     pub(crate) timestamp: i64,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ExpiredEvent {
+    pub(crate) id: Uuid,
+    pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) parent: Option<Uuid>,
+    #[serde(default = "Uuid::nil")]
+    pub(crate) root: Uuid,
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BounceEvent {
     pub(crate) id: Option<Uuid>,
     pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
     pub(crate) reason: BounceReason,
     pub(crate) timestamp: i64,
+    #[serde(default)]
+    pub(crate) request_id: Option<Uuid>, // of the request that was bounced, when it had one
+    #[serde(default)]
+    pub(crate) text: String,             // what went wrong, for people
+}
+
+impl BounceEvent {
+    pub fn new(id: Option<Uuid>, owner: Uuid, client_id: Option<Uuid>, reason: BounceReason, timestamp: i64) -> Self {
+        BounceEvent {
+            id,
+            owner,
+            client_id,
+            reason,
+            timestamp,
+            request_id: None,
+            text: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PhaseEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) phase: TradingPhase,
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PhaseChangedEvent {
+    pub(crate) previous: TradingPhase,
+    pub(crate) phase: TradingPhase,
+    pub(crate) timestamp: i64,
+}
+
+// new risk limits for one trader, or for everyone when trader is None
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LimitsEvent {
+    pub(crate) owner: Uuid,
+    #[serde(default)]
+    pub(crate) trader: Option<Uuid>,
+    pub(crate) limits: RiskLimits,
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LimitsChangedEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) trader: Option<Uuid>,
+    pub(crate) limits: RiskLimits,
+    pub(crate) timestamp: i64,
+}
+
+// Cash or holdings moved in (positive) or out (negative) of a trader's account by an operator. A transfer of
+// nothing just reports the balance.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TransferEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) trader: Uuid,
+    #[serde(default)]
+    pub(crate) cash: Decimal,
+    #[serde(default)]
+    pub(crate) holdings: Decimal,
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TransferredEvent {
+    pub(crate) trader: Uuid,
+    pub(crate) cash: Decimal,
+    pub(crate) holdings: Decimal,
+    pub(crate) balance: Balance, // after the transfer
+    pub(crate) timestamp: i64,
+}
+
+// one execution between a buyer and a seller at the price it actually happened at
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TradeEvent {
+    pub(crate) id: Uuid,
+    pub(crate) price: Decimal,
+    pub(crate) size: Decimal,
+    pub(crate) buy_order: Uuid,
+    pub(crate) sell_order: Uuid,
+    pub(crate) buyer: Uuid,
+    pub(crate) seller: Uuid,
+    pub(crate) aggressor: Option<OrderDirection>, // None when the trade came out of an auction
+    pub(crate) timestamp: i64,
+    #[serde(default)]
+    pub(crate) buyer_fee: Decimal,
+    #[serde(default)]
+    pub(crate) seller_fee: Decimal,
+}
+
+// the answer to a query of the trade tape, only ever sent to whoever asked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradesEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) trades: Vec<TradeEvent>, // oldest first, without buyers or sellers
+    pub(crate) truncated: bool,         // older trades that fall in the range are no longer kept
+    #[serde(default)]
+    pub(crate) next: Option<Uuid>,      // more of the range is left, ask again for the trades after this one
+    pub(crate) timestamp: i64,
+}
+
+// One of the owner's live orders, looked up by its current id or the root id it was placed under. Answered with an
+// OrderStatusEvent, or bounced if the owner has no such order on the book.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OrderStatusRequest {
+    pub(crate) owner: Uuid,
+    pub(crate) id: Uuid,
+}
+
+// every live order of the owner, answered with an OpenOrdersEvent
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OpenOrdersRequest {
+    pub(crate) owner: Uuid,
+}
+
+// where a live order stands, resting on the book or waiting for its trigger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderState {
+    pub(crate) id: Uuid,
+    pub(crate) root: Uuid,
+    pub(crate) parents: Vec<Uuid>, // the ids it went by before, oldest first so the root leads
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) price: Decimal,
+    pub(crate) direction: OrderDirection,
+    pub(crate) order_type: OrderType,
+    pub(crate) remaining: Decimal, // including an iceberg's reserve
+    pub(crate) filled: Decimal,
+    pub(crate) average_price: Option<Decimal>, // of every fill so far, None until it has traded
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderStatusEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) order: OrderState,
+    pub(crate) timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenOrdersEvent {
+    pub(crate) owner: Uuid,
+    pub(crate) orders: Vec<OrderState>, // bids then asks in book order, then stops
+    pub(crate) timestamp: i64,
+}
+
+// a stop order reached its trigger and entered the book as a market or limit order
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TriggeredEvent {
+    pub(crate) id: Uuid,
+    pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) trigger: Decimal,
+    pub(crate) last_price: Decimal,
+    pub(crate) timestamp: i64,
+}
+
+// a trailing stop's trigger followed the market
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TriggerMovedEvent {
+    pub(crate) id: Uuid,
+    pub(crate) owner: Uuid,
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) trigger: Decimal,
+    pub(crate) watermark: Decimal,
+    pub(crate) timestamp: i64,
+}
+
+impl TriggerMovedEvent {
+    fn from_stop(stop: &TrailingStop, timestamp: i64) -> Option<Self> {
+        Some(Self {
+            id: stop.order.id,
+            owner: stop.order.owner,
+            client_id: stop.order.client_id,
+            trigger: stop.trigger()?,
+            watermark: stop.watermark?,
+            timestamp,
+        })
+    }
+}
+
+// price and volume the book would uncross at if the call ended now
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct IndicativeEvent {
+    pub(crate) price: Option<Decimal>,
+    pub(crate) volume: Decimal,
+    pub(crate) surplus: Decimal,
+    pub(crate) timestamp: i64,
+}
+
+// what rests on one side of the book, the visible part of icebergs only
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Depth {
+    pub(crate) levels: usize,
+    pub(crate) size: Decimal,
+    pub(crate) orders: usize,
 }
 
 #[derive(Debug, Default)]
@@ -124,6 +633,16 @@ pub struct OrderBook {
     bid_book: Book,
     ask_book: Book,
     counter: u16,
+    sequence: u64,
+    phase: TradingPhase,
+    last_price: Option<Decimal>,
+    stops: StopBook,
+    allocation: Box<dyn Allocation>, // how a level is shared among its orders, price time priority unless configured
+    risk: RiskCheck,
+    ledger: Ledger,
+    fees: Fees,
+    candles: Candles,
+    history: OrderHistory,
 }
 
 impl BookLevel {
@@ -136,28 +655,25 @@ impl BookLevel {
     }
 
     fn find_order_with_id(&self, id: &Uuid) -> Option<&LimitOrder> {
-        for order in self.orders.iter() {
-            if order.id == *id {
-                return Some(order);
-            }
-        }
-        None
+        self.orders.iter().find(|order| order.id == *id)
     }
 
     pub fn remove_order(&mut self, id: &Uuid) -> Option<LimitOrder> {
-        if let Some(order_to_remove) = self.find_order_with_id(id).cloned() {
-            if self.orders.remove(&order_to_remove) {
-                return Some(order_to_remove)
-            } else {
-                panic!("Could not remove order even though it was found!");
-            }
+        let order = self.find_order_with_id(id).copied()?;
+
+        // it was found on the level by its id, so it goes even if looking it up by priority misses it
+        if !self.orders.remove(&order) {
+            self.orders.retain(|resting| resting.id != *id);
         }
-        None
+
+        self.size -= order.size;
+        Some(order)
     }
 
     pub fn iter(&self) -> btree_set::Iter<'_, LimitOrder> {
         self.orders.iter()
     }
+
 }
 
 impl Book {
@@ -188,291 +704,1131 @@ impl Book {
         None
     }
 
+    fn find_order(&self, id: &Uuid) -> Option<&LimitOrder> {
+        let (price, _) = self.price_id_sets.iter().find(|(_, id_set)| id_set.contains(id))?;
+        self.price_books.get(price)?.find_order_with_id(id)
+    }
+
     fn mut_price_level(&mut self, price: &Decimal) -> Option<&mut BookLevel> {
         self.price_books.get_mut(price)
     }
+
+    fn orders(&self) -> impl Iterator<Item = &LimitOrder> {
+        self.price_books.values().flat_map(BookLevel::iter)
+    }
+
+    fn depth(&self) -> Depth {
+        self.price_books.values()
+            .filter(|lvl| lvl.iter().next().is_some())
+            .fold(Depth::default(), |depth, lvl| Depth {
+                levels: depth.levels + 1,
+                size: depth.size + lvl.iter().map(|order| order.size).sum::<Decimal>(),
+                orders: depth.orders + lvl.iter().count(),
+            })
+    }
+
+    // best price on this side of the book among orders that aren't pegged
+    fn best_unpegged_price(&self, direction: OrderDirection) -> Option<Decimal> {
+        let mut prices = self.price_books.iter()
+            .filter(|(_, lvl)| lvl.iter().any(|order| !order.is_pegged()))
+            .map(|(price, _)| *price);
+
+        match direction {
+            OrderDirection::Bid => prices.next_back(),
+            OrderDirection::Ask => prices.next(),
+        }
+    }
+
+    // (price, size) of every non empty level, lowest price first, iceberg reserves included as an uncross can
+    // execute them. All or none orders sit out call auctions, a single uncrossing price can't promise to fill them
+    // completely, so they aren't counted.
+    fn levels(&self) -> Vec<(Decimal, Decimal)> {
+        self.price_books.iter()
+            .map(|(price, lvl)| (*price, lvl.iter().filter(|order| !order.all_or_none).map(LimitOrder::total).sum()))
+            .filter(|(_, size)| *size > Decimal::zero())
+            .collect()
+    }
+
 }
 
 impl OrderBook {
     pub fn new() -> Self {
+        OrderBook::with_allocation(Box::new(Fifo))
+    }
+
+    pub fn with_allocation(allocation: Box<dyn Allocation>) -> Self {
         OrderBook {
             bid_book: Book::new(),
             ask_book: Book::new(),
             counter: 0,
+            sequence: 0,
+            phase: TradingPhase::default(),
+            last_price: None,
+            stops: StopBook::new(),
+            allocation,
+            risk: RiskCheck::new(),
+            ledger: Ledger::new(),
+            fees: Fees::new(),
+            candles: Candles::default(),
+            history: OrderHistory::new(),
         }
     }
 
+    // reject orders and transfers the owner's balance can't cover
+    pub fn enforce_balances(&mut self) {
+        self.ledger.enforce();
+    }
+
+    pub fn set_fees(&mut self, schedule: FeeSchedule) {
+        self.fees.set_schedule(schedule);
+    }
+
+    pub fn set_candle_intervals(&mut self, intervals: &[Interval]) {
+        self.candles = Candles::new(intervals);
+    }
+
+    pub fn snapshot(&self) -> BookSnapshot {
+        BookSnapshot {
+            phase: self.phase,
+            last_price: self.last_price,
+            orders: self.bid_book.orders().chain(self.ask_book.orders()).copied().collect(),
+            stops: self.stops.orders().map(|order| (*order, self.stops.watermark(&order.id))).collect(),
+            history: self.history.clone(),
+        }
+    }
+
+    pub fn depth(&self, direction: OrderDirection) -> Depth {
+        self.book(direction).depth()
+    }
+
+    // Put the orders of a snapshot back where they were, with their priority, their reservations and counting
+    // towards their owners' open orders. Nothing is matched, the book was already uncrossed when it was taken.
+    pub fn restore_snapshot(&mut self, snapshot: BookSnapshot) {
+        self.phase = snapshot.phase;
+        self.last_price = snapshot.last_price;
+        self.history = snapshot.history;
+
+        let mut restored = Vec::new();
+
+        for order in snapshot.orders {
+            self.book_mut(order.direction).open_order(order);
+            restored.push(order);
+        }
+
+        for (order, watermark) in snapshot.stops {
+            match order.order_type {
+                OrderType::Stop { trigger } | OrderType::StopLimit { trigger } => self.stops.insert(trigger, order),
+                OrderType::TrailingStop { trail } => { self.stops.insert_trailing(trail, order, watermark); },
+                _ => continue,
+            }
+
+            restored.push(order);
+        }
+
+        for order in &restored {
+            self.sequence = self.sequence.max(order.sequence);
+            self.ledger.reserve(order.id, order.owner, order.direction, order.total(), self.price_bound(order).unwrap_or_default());
+        }
+
+        let opened: Vec<BookResult> = restored.into_iter().map(|order| BookResult::Opened(OpenedEvent::from(order))).collect();
+        self.risk.apply(&opened);
+    }
+
+    // Rebuild balances, trading volumes, positions and limits changed at runtime from previously published
+    // events. Orders still live at the end count against their owners once, however they come back on the book.
+    pub fn restore<'a>(&mut self, events: impl Iterator<Item = &'a BookResult>) {
+        for event in events {
+            self.ledger.settle(event);
+            self.risk.apply(std::slice::from_ref(event));
+
+            match event {
+                BookResult::Traded(trade_event) => {
+                    for owner in [trade_event.buyer, trade_event.seller] {
+                        self.fees.record(owner, trade_event.price.saturating_mul(trade_event.size), trade_event.timestamp);
+                    }
+                },
+                BookResult::LimitsChanged(limits_event) => self.set_limits(limits_event.trader, limits_event.limits),
+                _ => {},
+            }
+        }
+    }
+
+    // None sets the limits for every owner, Some just for that owner
+    pub fn set_limits(&mut self, trader: Option<Uuid>, limits: RiskLimits) {
+        self.risk.set_limits(trader, limits);
+    }
+
+    // requests the engine answers itself count against their owner's message rate like any other
+    pub fn check_message(&mut self, owner: Uuid, ts: i64) -> Option<BounceReason> {
+        self.risk.check_message(owner, ts)
+    }
+
     fn get_counter(&mut self) -> u16 {
         let c = self.counter;
 
-        self.counter += 1;
+        // only has to tell apart uuids generated within the same clock tick
+        self.counter = self.counter.wrapping_add(1);
 
-        return c;
+        c
     }
 
-    fn set_counter(&mut self, counter: u16) {
-        self.counter = counter;
+    // Orders are ranked by arrival sequence rather than timestamp, so priority matches arrival order exactly
+    // however many orders arrive within the same second. An order gets a new sequence, and so goes to the back
+    // of the queue, whenever it enters a level afresh: placed, re-priced, activated or replenished.
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
     }
 
     pub fn process_request(&mut self, book_msg: BookRequest) -> Vec<BookResult> {
         let ts = timestamp();
-        match book_msg {
+
+        // make sure nothing that expired since the last sweep gets a chance to trade
+        let mut events = self.expire(ts);
+        let request_id = book_msg.request_id();
+
+        let mut handled = match book_msg {
             BookRequest::Open(mut open_event) => {
                 open_event.uuid = Some(generate_uuid(self.get_counter()));
                 open_event.timestamp = ts;
-                self.place_order(open_event)
+
+                match self.admit(&open_event) {
+                    Some(reason) => vec![BookResult::Bounce(BounceEvent::new(open_event.uuid, open_event.owner, open_event.client_id, reason, ts))],
+                    None => {
+                        let events = self.place_order(open_event);
+
+                        // the order never made it onto the book
+                        if let [BookResult::Bounce(_)] = events[..] {
+                            self.ledger.release(&open_event.uuid.unwrap());
+                        }
+
+                        events
+                    },
+                }
             },
             BookRequest::Cancel(mut cancel_event) => {
                 cancel_event.timestamp = ts;
-                self.cancel_order(cancel_event) },
+
+                match self.risk.check_message(cancel_event.owner, ts) {
+                    Some(reason) => vec![BookResult::Bounce(BounceEvent::new(Some(cancel_event.id), cancel_event.owner, cancel_event.client_id, reason, ts))],
+                    None => {
+                        let mut events = self.cancel_order(cancel_event);
+                        self.push_indicative(&mut events, ts);
+                        events
+                    },
+                }
+            },
+            BookRequest::Admin(AdminRequest::SetPhase(mut phase_event)) => {
+                phase_event.timestamp = ts;
+                self.set_phase(phase_event)
+            },
+            BookRequest::Admin(AdminRequest::SetLimits(limits_event)) => {
+                self.set_limits(limits_event.trader, limits_event.limits);
+
+                vec![BookResult::LimitsChanged(LimitsChangedEvent{
+                    owner: limits_event.owner,
+                    trader: limits_event.trader,
+                    limits: limits_event.limits,
+                    timestamp: ts,
+                })]
+            },
+            BookRequest::Admin(AdminRequest::Transfer(transfer_event)) => {
+                match self.ledger.check_transfer(&transfer_event.trader, transfer_event.cash, transfer_event.holdings) {
+                    Some(reason) => vec![BookResult::Bounce(BounceEvent::new(None, transfer_event.owner, None, reason, ts))],
+                    None => {
+                        let mut balance = self.ledger.balance(&transfer_event.trader);
+                        balance.cash += transfer_event.cash;
+                        balance.holdings += transfer_event.holdings;
+
+                        vec![BookResult::Transferred(TransferredEvent{
+                            trader: transfer_event.trader,
+                            cash: transfer_event.cash,
+                            holdings: transfer_event.holdings,
+                            balance,
+                            timestamp: ts,
+                        })]
+                    },
+                }
+            },
+            BookRequest::OrderStatus(status_request) => {
+                let order = match self.risk.check_message(status_request.owner, ts) {
+                    Some(reason) => Err(reason),
+                    None => self.owned_order(&status_request.id, &status_request.owner),
+                };
+
+                match order {
+                    Ok(order) => vec![BookResult::OrderStatus(OrderStatusEvent{
+                        owner: status_request.owner,
+                        order: self.order_state(&order),
+                        timestamp: ts,
+                    })],
+                    Err(reason) => vec![BookResult::Bounce(BounceEvent::new(Some(status_request.id), status_request.owner, None, reason, ts))],
+                }
+            },
+            BookRequest::OpenOrders(orders_request) => {
+                match self.risk.check_message(orders_request.owner, ts) {
+                    Some(reason) => vec![BookResult::Bounce(BounceEvent::new(None, orders_request.owner, None, reason, ts))],
+                    None => vec![BookResult::OpenOrders(OpenOrdersEvent{
+                        owner: orders_request.owner,
+                        orders: self.bid_book.orders()
+                            .chain(self.ask_book.orders())
+                            .chain(self.stops.orders())
+                            .filter(|order| order.owner == orders_request.owner)
+                            .map(|order| self.order_state(order))
+                            .collect(),
+                        timestamp: ts,
+                    })],
+                }
+            },
+        };
+
+        // bounces echo the request they turned down
+        for event in handled.iter_mut() {
+            if let BookResult::Bounce(bounce_event) = event {
+                bounce_event.request_id = request_id;
+            }
+        }
+
+        events.append(&mut handled);
+
+        if self.phase.matches_orders() {
+            events.append(&mut self.reprice_pegs(ts));
         }
+
+        self.settle(&mut events, ts);
+
+        events
     }
 
-    fn place_order(&mut self, open_event: OpenEvent) -> Vec<BookResult> {
-        match open_event.direction {
-            OrderDirection::Bid => { self.fill_bid(LimitOrder::from(open_event)) },
-            OrderDirection::Ask => { self.fill_ask(LimitOrder::from(open_event)) },
+    // also finishes candles whose interval has ended, it is called on a timer so they go out without trades
+    pub fn expire_orders(&mut self, now: i64) -> Vec<BookResult> {
+        let mut events = self.expire(now);
+        self.settle(&mut events, now);
+        events
+    }
+
+    // Fills carry the fees of the trades they came from, an aggressor that walked the book pays for every one of
+    // them. Exposure and balances then follow what is about to be published, and the trades go into the candles.
+    fn settle(&mut self, events: &mut Vec<BookResult>, now: i64) {
+        for i in 0..events.len() {
+            let id = match &events[i] {
+                BookResult::Filled(filled_event) => filled_event.id,
+                _ => continue,
+            };
+
+            let fee = events[..i].iter().filter_map(|event| match event {
+                BookResult::Traded(trade_event) if trade_event.buy_order == id => Some(trade_event.buyer_fee),
+                BookResult::Traded(trade_event) if trade_event.sell_order == id => Some(trade_event.seller_fee),
+                _ => None,
+            }).sum();
+
+            if let BookResult::Filled(filled_event) = &mut events[i] {
+                filled_event.fee = fee;
+            }
+        }
+
+        self.risk.apply(events);
+        self.ledger.apply(events);
+        self.history.apply(events);
+
+        let mut candles: Vec<CandleEvent> = events.iter().flat_map(|event| match event {
+            BookResult::Traded(trade_event) => self.candles.trade(trade_event.price, trade_event.size, trade_event.timestamp),
+            _ => Vec::new(),
+        }).collect();
+        candles.append(&mut self.candles.close(now));
+
+        events.extend(candles.into_iter().map(BookResult::Candle));
+    }
+
+    // a live order the owner may act on, by its current id or its root id
+    fn owned_order(&self, id: &Uuid, owner: &Uuid) -> Result<LimitOrder, BounceReason> {
+        match self.live_order(id) {
+            Some(order) if order.owner == *owner => Ok(order),
+            Some(_) => Err(BounceReason::NotOwner),
+            None => Err(BounceReason::OrderNotFound),
         }
     }
 
-    fn fill_bid(&mut self, bid: LimitOrder) -> Vec<BookResult> {
-        // keep track of filled orders and record the opening of the initial trade
-        let mut filled_asks: BTreeMap<Decimal, Vec<Uuid>> = BTreeMap::new();
-        let mut events: Vec<BookResult> = vec![self.bid_book.open_order(bid)];
+    // an order on the book or a stop waiting for its trigger, by its current id or its root id
+    fn live_order(&self, id: &Uuid) -> Option<LimitOrder> {
+        let id = self.history.current(id);
 
-        let (bid_replacement, ask_replacement) = self.book_walk( bid, &mut events, &mut filled_asks);
+        self.bid_book.find_order(&id)
+            .or_else(|| self.ask_book.find_order(&id))
+            .or_else(|| self.stops.orders().find(|order| order.id == id))
+            .copied()
+    }
 
-        // remove all filled asks
-        filled_asks.iter().for_each(|(price_key, ids)| {
-            ids.iter().for_each(|order_id| {
-                OrderBook::remove_order(&mut self.ask_book, price_key, order_id);
-            });
-        });
+    fn order_state(&self, order: &LimitOrder) -> OrderState {
+        let lineage = self.history.lineage(&order.id).cloned().unwrap_or_default();
 
-        // if an ask is partially filled put the remainder back on the orderbook
-        if let Some(ask_replacement) = ask_replacement {
-            events.push(self.ask_book.open_order(ask_replacement));
+        OrderState {
+            id: order.id,
+            root: order.root,
+            average_price: lineage.average_price(order.filled),
+            parents: lineage.parents,
+            client_id: order.client_id,
+            price: order.price,
+            direction: order.direction,
+            order_type: order.order_type,
+            remaining: order.total(),
+            filled: order.filled,
+            timestamp: order.timestamp,
         }
+    }
 
-        // if the opened bid is at all filled remove the order from the orderbook, record the event,
-        // and put the remainder of the order back on the orderbook if it exists
-        if let Some(bid_replacement) = bid_replacement {
-            OrderBook::remove_order(&mut self.bid_book, &bid.price, &bid.id);
+    // Pre-trade checks, the order itself first, then risk limits and then the balance, which is reserved if the
+    // order gets through.
+    fn admit(&mut self, open_event: &OpenEvent) -> Option<BounceReason> {
+        if let Some(reason) = open_event.validate() {
+            return Some(reason);
+        }
 
-            events.push(BookResult::Filled(FilledEvent{
-                id: bid.id,
-                owner: bid.owner,
-                parent: bid.parent,
-                price: bid.price,
-                size: bid.size - bid_replacement.size,
-                timestamp: bid.timestamp,
-            }));
+        // client ids tell the owner's live orders apart
+        let client_id_taken = open_event.client_id.is_some_and(|client_id| self.bid_book.orders()
+            .chain(self.ask_book.orders())
+            .chain(self.stops.orders())
+            .any(|order| order.owner == open_event.owner && order.client_id == Some(client_id)));
+
+        if client_id_taken {
+            return Some(BounceReason::DuplicateClientId);
+        }
+
+        let order = LimitOrder::from(*open_event);
+        let price = self.price_bound(&order);
+
+        // valued at what it could trade at, the price sent with an order that isn't a limit is never looked at
+        if let Some(reason) = self.risk.check_order(open_event, price.filter(|price| *price > Decimal::zero()).or(self.last_price)) {
+            return Some(reason);
+        }
+
+        if let Some(reason) = self.ledger.check_order(&order.owner, order.direction, order.total(), price) {
+            return Some(reason);
+        }
+
+        self.ledger.reserve(order.id, order.owner, order.direction, order.total(), price.unwrap_or_default());
+
+        None
+    }
+
+    // The most a bid can pay per unit. Market orders are bounded by the price they would have to go down to
+    // on the book right now, pegs by their cap, stops that become market orders by nothing.
+    fn price_bound(&self, order: &LimitOrder) -> Option<Decimal> {
+        match order.order_type {
+            OrderType::Limit | OrderType::StopLimit { .. } => Some(order.price),
+            OrderType::Peg { cap, .. } => cap,
+            OrderType::Market => {
+                let mut remainder = order.total();
+                let mut bound = Decimal::zero(); // nothing will trade on an empty book
+
+                for level in self.crossing_levels(order) {
+                    bound = level.iter().next().map_or(bound, |resting| resting.price);
+                    // all or none orders may be passed over, so they aren't counted on to fill it
+                    remainder -= level.iter().filter(|resting| !resting.all_or_none).map(LimitOrder::total).sum::<Decimal>();
+
+                    if remainder <= Decimal::zero() {
+                        break;
+                    }
+                }
 
-            if bid_replacement.size > Decimal::zero() {
-                events.push(self.bid_book.open_order(bid_replacement));
+                Some(bound)
+            },
+            OrderType::Stop { .. } | OrderType::TrailingStop { .. } => None,
+        }
+    }
+
+    // Remove every order whose time in force has run out. Good till date orders expire once now reaches their
+    // expiry, day orders expire once the session is closed.
+    fn expire(&mut self, now: i64) -> Vec<BookResult> {
+        let session_closed = self.phase == TradingPhase::Closed;
+
+        let expired: Vec<LimitOrder> = self.bid_book.orders()
+            .chain(self.ask_book.orders())
+            .chain(self.stops.orders())
+            .filter(|order| order.time_in_force.expired(now, session_closed))
+            .copied()
+            .collect();
+
+        let mut events: Vec<BookResult> = expired.iter().map(|order| {
+            if self.stops.remove(&order.id).is_none() {
+                OrderBook::remove_order(self.book_mut(order.direction), &order.price, &order.id);
             }
+
+            BookResult::Expired(ExpiredEvent{
+                id: order.id,
+                owner: order.owner,
+                client_id: order.client_id,
+                parent: order.parent,
+                root: order.root,
+                timestamp: now,
+            })
+        }).collect();
+
+        // expiries can change the top of the book
+        if !events.is_empty() && self.phase.matches_orders() {
+            events.append(&mut self.reprice_pegs(now));
         }
 
         events
     }
 
-    fn fill_ask(&mut self, ask: LimitOrder) ->  Vec<BookResult> {
-        // keep track of filled orders and record the opening of the initial trade
-        let mut filled_bids: BTreeMap<Decimal, Vec<Uuid>> = BTreeMap::new();
-        let mut events: Vec<BookResult> = vec![self.ask_book.open_order(ask)];
+    // best bid and ask among orders that aren't pegged, pegs never follow each other
+    fn reference_quote(&self) -> (Option<Decimal>, Option<Decimal>) {
+        (self.bid_book.best_unpegged_price(OrderDirection::Bid), self.ask_book.best_unpegged_price(OrderDirection::Ask))
+    }
 
-        let (ask_replacement, bid_replacement) = self.book_walk(ask, &mut events, &mut filled_bids);
+    // Move every pegged order to where its reference says it should be. A re-priced peg goes to the back of the
+    // queue at its new price and trades if it now crosses the other side. Trades can move the reference again,
+    // so keep going until every peg is where it belongs. Pegs are visited bids first, each side in book order.
+    fn reprice_pegs(&mut self, ts: i64) -> Vec<BookResult> {
+        let mut events = Vec::new();
+
+        loop {
+            let (best_bid, best_ask) = self.reference_quote();
+
+            let moves: Vec<(LimitOrder, Decimal)> = self.bid_book.orders()
+                .chain(self.ask_book.orders())
+                .filter_map(|order| {
+                    // a peg the reference has pushed past the prices the book takes stays where it is
+                    order.peg_price(best_bid, best_ask)
+                        .filter(|price| *price != order.price && valid_price(*price))
+                        .map(|price| (*order, price))
+                })
+                .collect();
+
+            if moves.is_empty() {
+                break;
+            }
 
-        // remove all filled bids
-        filled_bids.iter().for_each(|(price_key, ids)| {
-           ids.iter().for_each(|order_id| {
-               OrderBook::remove_order(&mut self.bid_book, price_key, order_id);
-           });
-        });
+            for (peg, price) in moves {
+                // an earlier peg in this pass may have traded with it already
+                let peg = match OrderBook::remove_order(self.book_mut(peg.direction), &peg.price, &peg.id) {
+                    Some(peg) => peg,
+                    None => continue,
+                };
+
+                let repriced = LimitOrder{
+                    price,
+                    timestamp: ts,
+                    sequence: self.next_sequence(),
+                    ..peg
+                };
+
+                self.book_mut(repriced.direction).open_order(repriced);
+
+                events.push(BookResult::Replaced(ReplacedEvent{
+                    id: repriced.id,
+                    owner: repriced.owner,
+                    client_id: repriced.client_id,
+                    parent: repriced.parent,
+                    root: repriced.root,
+                    previous_price: peg.price,
+                    price,
+                    size: repriced.size,
+                    timestamp: ts,
+                }));
+
+                self.match_order(repriced, &mut events);
+            }
 
-        // if there is a partially filled bid then put it on the orderbook
-        if let Some(bid_replacement) = bid_replacement {
-            events.push(self.bid_book.open_order(bid_replacement));
+            events.append(&mut self.trigger_stops(ts));
         }
 
-        // if the opened ask is (partially) filled, then remove it from the orderbook
-        // and replace it with the remainder (if it exists)
-        if let Some(ask_replacement) = ask_replacement {
-            OrderBook::remove_order(&mut self.ask_book, &ask.price, &ask.id);
+        events
+    }
 
-            events.push(BookResult::Filled(FilledEvent{
-                id: ask.id,
-                owner: ask.owner,
-                parent: ask.parent,
-                price: ask.price,
-                size: ask.size - ask_replacement.size,
-                timestamp: ask.timestamp,
-            }));
+    fn place_order(&mut self, open_event: OpenEvent) -> Vec<BookResult> {
+        if !self.phase.accepts_orders() {
+            return vec![BookResult::Bounce(BounceEvent::new(open_event.uuid, open_event.owner, open_event.client_id, BounceReason::MarketClosed, open_event.timestamp))];
+        }
+
+        let mut order = LimitOrder::from(open_event);
+        order.sequence = self.next_sequence();
+
+        if order.time_in_force.expired(order.timestamp, false) {
+            return vec![BookResult::Bounce(BounceEvent::new(Some(order.id), order.owner, order.client_id, BounceReason::AlreadyExpired, order.timestamp))];
+        }
 
-            if ask_replacement.size > Decimal::zero() {
-                events.push(self.ask_book.open_order(ask_replacement));
+        match order.order_type {
+            OrderType::Stop { trigger } | OrderType::StopLimit { trigger } => {
+                return self.place_stop(trigger, order);
+            },
+            OrderType::TrailingStop { trail } => {
+                return self.place_trailing_stop(trail, order);
+            },
+            OrderType::Peg { .. } => {
+                let (best_bid, best_ask) = self.reference_quote();
+
+                match order.peg_price(best_bid, best_ask) {
+                    Some(price) if valid_price(price) => order.price = price,
+                    Some(_) => {
+                        return vec![BookResult::Bounce(BounceEvent::new(Some(order.id), order.owner, order.client_id, BounceReason::InvalidPrice, order.timestamp))];
+                    },
+                    None => {
+                        return vec![BookResult::Bounce(BounceEvent::new(Some(order.id), order.owner, order.client_id, BounceReason::NoReferencePrice, order.timestamp))];
+                    },
+                }
+            },
+            // market orders can't wait on the book for matching to resume
+            OrderType::Market if !self.phase.matches_orders() => {
+                let reason = match self.phase {
+                    TradingPhase::Halted => BounceReason::TradingHalted,
+                    _ => BounceReason::MatchingSuspended,
+                };
+
+                return vec![BookResult::Bounce(BounceEvent::new(Some(order.id), order.owner, order.client_id, reason, order.timestamp))];
+            },
+            _ => {},
+        }
+
+        // outside of continuous trading orders just queue up on the book until matching resumes
+        if !self.phase.matches_orders() {
+            let mut events = vec![self.book_mut(order.direction).open_order(order)];
+            self.push_indicative(&mut events, order.timestamp);
+            return events;
+        }
+
+        let mut events = self.fill_order(order);
+        events.append(&mut self.trigger_stops(order.timestamp));
+        events
+    }
+
+    fn place_stop(&mut self, trigger: Decimal, order: LimitOrder) -> Vec<BookResult> {
+        self.stops.insert(trigger, order);
+
+        let mut events = vec![BookResult::Opened(OpenedEvent::from(order))];
+
+        // a stop that is already through the last price triggers right away
+        if self.phase.matches_orders() {
+            events.append(&mut self.trigger_stops(order.timestamp));
+        }
+
+        events
+    }
+
+    fn place_trailing_stop(&mut self, trail: Trail, order: LimitOrder) -> Vec<BookResult> {
+        let stop = self.stops.insert_trailing(trail, order, self.last_price);
+
+        let mut events = vec![BookResult::Opened(OpenedEvent::from(order))];
+        events.extend(TriggerMovedEvent::from_stop(stop, order.timestamp).map(BookResult::TriggerMoved));
+
+        if self.phase.matches_orders() {
+            events.append(&mut self.trigger_stops(order.timestamp));
+        }
+
+        events
+    }
+
+    // record an execution price, trailing stops follow every one of them
+    fn record_trade(&mut self, price: Decimal, events: &mut Vec<BookResult>, ts: i64) {
+        self.last_price = Some(price);
+
+        events.extend(self.stops.track(price).into_iter()
+            .filter_map(|stop| TriggerMovedEvent::from_stop(stop, ts))
+            .map(BookResult::TriggerMoved));
+    }
+
+    // Activate every stop the last price has reached. Each activation can trade and move the last price
+    // far enough to trigger more stops, so keep going until nothing else triggers.
+    fn trigger_stops(&mut self, ts: i64) -> Vec<BookResult> {
+        let mut events = Vec::new();
+
+        while let Some(last_price) = self.last_price {
+            let triggered = self.stops.take_triggered(last_price);
+
+            if triggered.is_empty() {
+                break;
+            }
+
+            for (trigger, mut order) in triggered {
+                events.push(BookResult::Triggered(TriggeredEvent{
+                    id: order.id,
+                    owner: order.owner,
+                    client_id: order.client_id,
+                    trigger,
+                    last_price,
+                    timestamp: ts,
+                }));
+
+                order.order_type = match order.order_type {
+                    OrderType::StopLimit { .. } => OrderType::Limit,
+                    _ => OrderType::Market,
+                };
+                order.timestamp = ts; // time priority starts when the stop activates
+                order.sequence = self.next_sequence();
+
+                events.append(&mut self.fill_order(order));
             }
         }
 
         events
     }
 
-    fn cancel_order(&mut self, cancel_event: CancelEvent) -> Vec<BookResult> {
-        let ts = timestamp();
-        if let Some(canceled_order) = self.bid_book.cancel_order(cancel_event) {
-            return vec![BookResult::Canceled(CanceledEvent{
-                id: canceled_order.id,
-                owner: canceled_order.owner,
-                parent: canceled_order.parent,
-                timestamp: ts,
-            })];
-        }
-
-        if let Some(canceled_order) = self.ask_book.cancel_order(cancel_event) {
-            return vec![BookResult::Canceled(CanceledEvent{
-                id: canceled_order.id,
-                owner: canceled_order.owner,
-                parent: canceled_order.parent,
-                timestamp: ts,
-            })];
-        }
-
-        let bounce_event = BounceEvent{
-            id: Some(cancel_event.id),
-            owner: cancel_event.owner,
-            reason: BounceReason::OrderNotFound,
+    fn set_phase(&mut self, phase_event: PhaseEvent) -> Vec<BookResult> {
+        if !self.phase.can_transition_to(phase_event.phase) {
+            return vec![BookResult::Bounce(BounceEvent::new(None, phase_event.owner, None, BounceReason::InvalidPhaseTransition, phase_event.timestamp))];
+        }
+
+        let previous = self.phase;
+        self.phase = phase_event.phase;
+
+        let mut events = vec![BookResult::PhaseChanged(PhaseChangedEvent{
+            previous,
+            phase: self.phase,
+            timestamp: phase_event.timestamp,
+        })];
+
+        // orders that queued up during the call (or halt) may now cross
+        if previous.uncrosses_into(self.phase) {
+            events.append(&mut self.uncross(phase_event.timestamp));
+        }
+
+        if self.phase.matches_orders() {
+            events.append(&mut self.match_crossed());
+            events.append(&mut self.trigger_stops(phase_event.timestamp));
+        }
+
+        // day orders end with the session
+        events.append(&mut self.expire(phase_event.timestamp));
+
+        self.push_indicative(&mut events, phase_event.timestamp);
+
+        events
+    }
+
+    fn book(&self, direction: OrderDirection) -> &Book {
+        match direction {
+            OrderDirection::Bid => &self.bid_book,
+            OrderDirection::Ask => &self.ask_book,
+        }
+    }
+
+    fn book_mut(&mut self, direction: OrderDirection) -> &mut Book {
+        match direction {
+            OrderDirection::Bid => &mut self.bid_book,
+            OrderDirection::Ask => &mut self.ask_book,
+        }
+    }
+
+    fn push_indicative(&self, events: &mut Vec<BookResult>, ts: i64) {
+        if !self.phase.is_call() {
+            return;
+        }
+
+        let uncross = auction::equilibrium(&self.bid_book.levels(), &self.ask_book.levels(), self.last_price);
+
+        events.push(BookResult::Indicative(IndicativeEvent{
+            price: uncross.map(|u| u.price),
+            volume: uncross.map(|u| u.volume).unwrap_or_default(),
+            surplus: uncross.map(|u| u.surplus).unwrap_or_default(),
             timestamp: ts,
+        }));
+    }
+
+    // execute every crossing order at the single equilibrium price
+    fn uncross(&mut self, ts: i64) -> Vec<BookResult> {
+        let mut events = Vec::new();
+
+        let uncross = match auction::equilibrium(&self.bid_book.levels(), &self.ask_book.levels(), self.last_price) {
+            Some(uncross) => uncross,
+            None => return events,
         };
 
-        vec![BookResult::Bounce(bounce_event)]
+        // crossing orders on each side in priority order, highest bids and lowest asks first
+        let bids: Vec<LimitOrder> = self.bid_book.price_books.range(uncross.price..).rev()
+            .flat_map(|(_, lvl)| lvl.iter().filter(|order| !order.all_or_none).cloned())
+            .collect();
+        let asks: Vec<LimitOrder> = self.ask_book.price_books.range(..=uncross.price)
+            .flat_map(|(_, lvl)| lvl.iter().filter(|order| !order.all_or_none).cloned())
+            .collect();
+
+        events.append(&mut self.auction_trades(&bids, &asks, &uncross, ts));
+
+        self.auction_fill(bids, &uncross, &mut events, ts);
+        self.auction_fill(asks, &uncross, &mut events, ts);
+
+        self.record_trade(uncross.price, &mut events, ts);
+
+        events
     }
 
-    fn calculate_fill(order_match: &LimitOrder, remainder: &mut Decimal, all_events: &mut Vec<BookResult>, filled_ids: &mut Vec<Uuid>, ts: i64, counter: u16) -> Option<LimitOrder> {
-        if order_match.size <= *remainder {
-            // full fill of the order_match
-            all_events.push(BookResult::Filled(FilledEvent{
-                id: order_match.id,
-                owner: order_match.owner,
-                parent: order_match.parent,
-                price: order_match.price,
-                size: order_match.size,
-                timestamp: ts,
-            }));
+    // Whatever still crosses once matching resumes trades as it would in continuous matching: iceberg slices an
+    // uncross refilled from the reserve and all or none orders that sat it out. Each order still crossing the
+    // other side takes its turn in priority order, bids first, and is only tried once.
+    fn match_crossed(&mut self) -> Vec<BookResult> {
+        let mut events = Vec::new();
+        let mut tried = BTreeSet::new();
+
+        for direction in [OrderDirection::Bid, OrderDirection::Ask] {
+            while let Some(order) = self.crossed(direction, &tried) {
+                tried.insert(order.id);
+                self.match_order(order, &mut events);
+            }
+        }
+
+        events
+    }
 
-            *remainder -= order_match.size;
+    // the first order on one side in priority order that crosses an order on the other and hasn't been tried yet
+    fn crossed(&self, direction: OrderDirection, tried: &BTreeSet<Uuid>) -> Option<LimitOrder> {
+        let levels = self.book(direction).price_books.values();
 
-            filled_ids.push(order_match.id);
+        let mut levels: Box<dyn Iterator<Item = &BookLevel>> = match direction {
+            OrderDirection::Bid => Box::new(levels.rev()), // highest bids first
+            OrderDirection::Ask => Box::new(levels),       // lowest offers first
+        };
 
-            None
+        levels.find_map(|lvl| lvl.iter()
+            .filter(|order| !tried.contains(&order.id))
+            .find(|order| self.crossing_levels(order).any(|other| other.iter().next().is_some()))
+            .copied())
+    }
+
+    // Pair up the bids and asks filled by an uncross, in priority order on both sides. Each side fills in
+    // priority order until the volume runs out, exactly as auction_fill does.
+    fn auction_trades(&mut self, bids: &[LimitOrder], asks: &[LimitOrder], uncross: &auction::Uncross, ts: i64) -> Vec<BookResult> {
+        let executions = |orders: &[LimitOrder]| {
+            let mut remainder = uncross.volume;
+
+            orders.iter().map(|order| {
+                let size = order.total().min(remainder);
+                remainder -= size;
+                (*order, size)
+            }).filter(|(_, size)| *size > Decimal::zero()).collect::<Vec<_>>()
+        };
+
+        let mut bids = executions(bids).into_iter();
+        let mut asks = executions(asks).into_iter();
+        let mut trades = Vec::new();
+
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        while let (Some((bid_order, bid_size)), Some((ask_order, ask_size))) = (bid, ask) {
+            let size = bid_size.min(ask_size);
+            trades.push(self.traded(&bid_order, &ask_order, uncross.price, size, None, ts));
+
+            bid = if bid_size > size { Some((bid_order, bid_size - size)) } else { bids.next() };
+            ask = if ask_size > size { Some((ask_order, ask_size - size)) } else { asks.next() };
+        }
+
+        trades
+    }
+
+    fn traded(&mut self, order: &LimitOrder, other: &LimitOrder, price: Decimal, size: Decimal, aggressor: Option<OrderDirection>, ts: i64) -> BookResult {
+        let (buy, sell) = match order.direction {
+            OrderDirection::Bid => (order, other),
+            OrderDirection::Ask => (other, order),
+        };
 
+        let (buy_liquidity, sell_liquidity) = match aggressor {
+            Some(OrderDirection::Bid) => (Liquidity::Taker, Liquidity::Maker),
+            Some(OrderDirection::Ask) => (Liquidity::Maker, Liquidity::Taker),
+            None => (Liquidity::Taker, Liquidity::Taker),
+        };
+
+        let value = price.saturating_mul(size);
+
+        BookResult::Traded(TradeEvent{
+            id: generate_uuid(self.get_counter()),
+            price,
+            size,
+            buy_order: buy.id,
+            sell_order: sell.id,
+            buyer: buy.owner,
+            seller: sell.owner,
+            aggressor,
+            timestamp: ts,
+            buyer_fee: self.fees.charge(buy.owner, value, buy_liquidity, ts),
+            seller_fee: self.fees.charge(sell.owner, value, sell_liquidity, ts),
+        })
+    }
+
+    // an uncross executes icebergs from their reserve too, whatever is left of one is back on the book as a new slice
+    fn auction_fill(&mut self, orders: Vec<LimitOrder>, uncross: &auction::Uncross, events: &mut Vec<BookResult>, ts: i64) {
+        let mut remainder = uncross.volume;
+
+        for order in orders {
+            if remainder == Decimal::zero() {
+                break;
+            }
+
+            OrderBook::remove_order(self.book_mut(order.direction), &order.price, &order.id);
+
+            if let Some(replacement) = self.calculate_fill(&order, order.total(), &mut remainder, uncross.price, events, ts) {
+                events.push(self.book_mut(order.direction).open_order(replacement));
+            }
+        }
+    }
+
+    fn fill_order(&mut self, order: LimitOrder) -> Vec<BookResult> {
+        // record the opening of the initial trade, market orders never rest on the book so they are only announced
+        let mut events: Vec<BookResult> = if order.order_type != OrderType::Market {
+            vec![self.book_mut(order.direction).open_order(order)]
         } else {
-            // partially fill the order_match
-            all_events.push(BookResult::Filled(FilledEvent{
-                id: order_match.id,
-                price: order_match.price,
-                owner: order_match.owner,
-                parent: order_match.parent,
-                size: *remainder,
-                timestamp: ts,
-            }));
+            vec![BookResult::Opened(OpenedEvent::from(order))]
+        };
 
-            // return a new limit order to represent the remainder of the other order
-            let replacement = LimitOrder{
-                id: generate_uuid(counter),
-                parent: Some(order_match.id),
-                owner: order_match.owner,
-                price: order_match.price,
-                size: order_match.size - *remainder,
-                direction: order_match.direction,
-                timestamp: order_match.timestamp
-            };
+        self.match_order(order, &mut events);
+
+        events
+    }
 
-            *remainder = Decimal::zero();
+    // match an order that was just opened against the other side of the book
+    fn match_order(&mut self, order: LimitOrder, events: &mut Vec<BookResult>) {
+        let resting = order.order_type != OrderType::Market;
 
-            filled_ids.push(order_match.id);
+        // an order with a minimum quantity or all or none doesn't trade at all unless enough of it can
+        let required = order.required_qty();
+        let order_replacement = if required == Decimal::zero() || self.executable(&order) >= required {
+            self.book_walk(order, events)
+        } else {
+            None
+        };
+
+        // if the opened order is at all filled remove the order from the orderbook, record the event,
+        // and put the remainder of the order back on the orderbook if it exists
+        if let Some(order_replacement) = order_replacement {
+            if resting {
+                OrderBook::remove_order(self.book_mut(order.direction), &order.price, &order.id);
+            }
+
+            events.push(BookResult::Filled(FilledEvent{
+                id: order.id,
+                owner: order.owner,
+                client_id: order.client_id,
+                parent: order.parent,
+                price: if resting { order.price } else { self.last_price.unwrap_or(order.price) },
+                size: order.total() - order_replacement.total(),
+                timestamp: order.timestamp,
+                fee: Decimal::zero(), // filled in once every trade is known
+                root: order.root,
+                leaves: order_replacement.total(),
+                filled: order_replacement.filled,
+            }));
 
-            Some(replacement)
+            if order_replacement.total() > Decimal::zero() {
+                if resting {
+                    events.push(self.book_mut(order.direction).open_order(order_replacement));
+                } else {
+                    events.push(OrderBook::canceled(&order_replacement, order.timestamp));
+                }
+            }
+        } else if !resting {
+            events.push(OrderBook::canceled(&order, order.timestamp));
         }
     }
 
-    fn book_walk(&mut self, order: LimitOrder, all_events: &mut Vec<BookResult>, filled_ids: &mut BTreeMap<Decimal, Vec<Uuid>>) -> (Option<LimitOrder>, Option<LimitOrder>) {
+    fn canceled(order: &LimitOrder, ts: i64) -> BookResult {
+        BookResult::Canceled(CanceledEvent{
+            id: order.id,
+            owner: order.owner,
+            client_id: order.client_id,
+            parent: order.parent,
+            root: order.root,
+            timestamp: ts,
+        })
+    }
+
+    // An order can be canceled by its root id however often it has been replaced since, the cancel reports the
+    // id it is on the book under now. Only the owner can cancel an order.
+    fn cancel_order(&mut self, cancel_event: CancelEvent) -> Vec<BookResult> {
         let ts = timestamp();
-        let mut remainder = order.size;
-        let mut partial_order_fill: Option<LimitOrder> = None;
-        let mut partial_match_fill: Option<LimitOrder> = None;
-
-        let mut counter = self.get_counter();
-
-        // Get the books that are compatible with trade in the correct price order
-        // filter out any empty prices
-        let level_iter: Vec<(&Decimal, &BookLevel)> = match order.direction {
-            OrderDirection::Bid => {
-                let book= &self.ask_book;
-                // get the lowest priced offers first
-                book.price_books.iter().filter(
-                    |(p, lvl)| {
-                        **p <= order.price && lvl.size > Decimal::zero()
-                    }).collect()
-            },
-            OrderDirection::Ask => {
-                // reverse this iterator since we are walking down the bid orderbook
-                // so we want to get the highest bids first
-                let book = &self.bid_book;
-                book.price_books.iter().rev().filter(
-                    |(p, lvl)| {
-                        **p >= order.price && lvl.size > Decimal::zero()
-                    }).collect() // IDK an easy way to get around the fact that .rev() messes with the return type enough I have to collect everything to a vec first >:(
+
+        let current = match self.owned_order(&cancel_event.id, &cancel_event.owner) {
+            Ok(order) => CancelEvent { id: order.id, ..cancel_event },
+            Err(reason) => {
+                return vec![BookResult::Bounce(BounceEvent::new(Some(cancel_event.id), cancel_event.owner, cancel_event.client_id, reason, ts))];
             },
         };
 
-        // iterate through the valid price levels in the correct order
-        for (price, level) in level_iter {
-            for order_match in level.iter() {
-                let mut remove_ids = Vec::new();
+        let canceled_order = self.bid_book.cancel_order(current)
+            .or_else(|| self.ask_book.cancel_order(current))
+            .or_else(|| self.stops.remove(&current.id));
 
-                partial_match_fill = OrderBook::calculate_fill(order_match, &mut remainder, all_events, &mut remove_ids, ts, counter);
-                counter += 1;
+        // a live order is on the book or among the stops, should they ever disagree the cancel is bounced
+        match canceled_order {
+            Some(canceled_order) => vec![OrderBook::canceled(&canceled_order, ts)],
+            None => vec![BookResult::Bounce(BounceEvent::new(Some(cancel_event.id), cancel_event.owner, cancel_event.client_id, BounceReason::OrderNotFound, ts))],
+        }
+    }
 
-                filled_ids.insert(*price, remove_ids);
+    // Fill a resting order with as much of the remainder as it can take, up to fillable: its visible size when
+    // matching continuously, all of it in an uncross. Whatever is left of the order comes back as a new child
+    // order: the rest of a partially filled slice keeps its time priority, while a fresh slice from an iceberg's
+    // reserve starts at the back of the queue.
+    fn calculate_fill(&mut self, order_match: &LimitOrder, fillable: Decimal, remainder: &mut Decimal, price: Decimal, all_events: &mut Vec<BookResult>, ts: i64) -> Option<LimitOrder> {
+        let size = fillable.min(*remainder);
+
+        all_events.push(BookResult::Filled(FilledEvent{
+            id: order_match.id,
+            owner: order_match.owner,
+            client_id: order_match.client_id,
+            parent: order_match.parent,
+            price,
+            size,
+            timestamp: ts,
+            fee: Decimal::zero(),
+            root: order_match.root,
+            leaves: order_match.total() - size,
+            filled: order_match.filled + size,
+        }));
 
-                // check if the matching order was partially filled (
-                // or if the submitted order is completely filled
-                if partial_match_fill.is_some() || remainder == Decimal::zero() {
-                    break
-                }
-            }
+        *remainder -= size;
 
-            if partial_match_fill.is_some() || remainder == Decimal::zero() {
+        if size < order_match.size {
+            // partially filled the order_match
+            Some(LimitOrder{
+                id: generate_uuid(self.get_counter()),
+                parent: Some(order_match.id),
+                size: order_match.size - size,
+                filled: order_match.filled + size,
+                ..*order_match
+            })
+        } else if order_match.total() > size {
+            // the visible slice is gone, replenish it from what is left of the reserve
+            Some(order_match.remaining(generate_uuid(self.get_counter()), order_match.total() - size, ts, self.next_sequence()))
+        } else {
+            None
+        }
+    }
+
+    // Walk the other side of the book filling the order, returns a replacement order for the remainder if the
+    // order was at all filled. Each pass takes the best crossing level and lets the allocation strategy split the
+    // remainder among its orders. Anything put back (the rest of a partial fill, a replenished iceberg slice) is
+    // allocated again on the next pass like everything else.
+    fn book_walk(&mut self, order: LimitOrder, all_events: &mut Vec<BookResult>) -> Option<LimitOrder> {
+        let ts = timestamp();
+        let mut remainder = order.total();
+
+        while remainder > Decimal::zero() {
+            let matches = match self.best_level(&order, remainder) {
+                Some(matches) => matches,
+                None => break,
+            };
+
+            let allocations = self.allocation.allocate(&matches, remainder);
+
+            if allocations.iter().all(|size| *size == Decimal::zero()) {
                 break;
             }
-        }
 
-        self.set_counter(counter);
+            for (mut order_match, mut size) in matches.into_iter().zip(allocations) {
+                if size == Decimal::zero() {
+                    continue;
+                }
+
+                OrderBook::remove_order(self.book_mut(order_match.direction), &order_match.price, &order_match.id);
+
+                // an all or none iceberg executes in full right away rather than a slice at a time
+                if order_match.all_or_none {
+                    order_match.size = order_match.total();
+                    order_match.hidden = Decimal::zero();
+                }
+
+                remainder -= size;
+
+                let trade = self.traded(&order, &order_match, order_match.price, order_match.size.min(size), Some(order.direction), ts);
+                all_events.push(trade);
+
+                if let Some(replacement) = self.calculate_fill(&order_match, order_match.size, &mut size, order_match.price, all_events, ts) {
+                    all_events.push(self.book_mut(replacement.direction).open_order(replacement));
+                }
+
+                self.record_trade(order_match.price, all_events, ts);
+            }
+        }
 
         // partially filled the submitting order
-        // generate a replacement order
-        if remainder < order.size {
-            partial_order_fill = Some(LimitOrder {
-                id: generate_uuid(self.get_counter()),
-                parent: Some(order.id),
-                owner: order.owner,
-                price: order.price,
-                size: remainder, // if this is 0 then we know that the order is completely filled
-                direction: order.direction,
-                timestamp: ts,
-            });
+        // generate a replacement order, if its size is 0 then we know that the order is completely filled
+        if remainder < order.total() {
+            return Some(order.remaining(generate_uuid(self.get_counter()), remainder, ts, order.sequence));
         }
 
-        (partial_order_fill, partial_match_fill)
+        None
     }
 
-    fn remove_order(book: &mut Book, price: &Decimal, id: &Uuid) {
+    // the crossing levels on the other side of the book, best price first
+    fn crossing_levels<'a>(&'a self, order: &'a LimitOrder) -> impl Iterator<Item = &'a BookLevel> + 'a {
+        let levels = self.book(order.direction.opposite()).price_books.iter();
+
+        let levels: Box<dyn Iterator<Item = (&Decimal, &BookLevel)>> = match order.direction {
+            OrderDirection::Bid => Box::new(levels),       // get the lowest priced offers first
+            OrderDirection::Ask => Box::new(levels.rev()), // get the highest bids first
+        };
+
+        levels.take_while(|(price, _)| order.crosses(**price)).map(|(_, lvl)| lvl)
+    }
+
+    // The orders in time priority at the best level on the other side of the book that the order crosses and
+    // that will take a fill out of the remainder. All or none orders too big for the remainder are passed over,
+    // everything behind them keeps its place in the queue.
+    fn best_level(&self, order: &LimitOrder, remainder: Decimal) -> Option<Vec<LimitOrder>> {
+        self.crossing_levels(order)
+            .map(|lvl| OrderBook::eligible(lvl.iter(), remainder))
+            .find(|matches| !matches.is_empty())
+    }
+
+    fn eligible<'a>(orders: impl Iterator<Item = &'a LimitOrder>, remainder: Decimal) -> Vec<LimitOrder> {
+        orders.filter(|order_match| order_match.accepts_fill(remainder)).copied().collect()
+    }
+
+    // How much of the order would execute if it walked the book right now. Plays the walk out level by level
+    // with the same allocation strategy without touching the book: the rest of a partial fill keeps its place,
+    // and a filled iceberg slice goes to the back of its level with the next slice from the reserve.
+    fn executable(&self, order: &LimitOrder) -> Decimal {
+        let mut remainder = order.total();
+
+        for level in self.crossing_levels(order) {
+            let mut queue: Vec<LimitOrder> = level.iter().copied().collect();
+
+            loop {
+                let matches = OrderBook::eligible(queue.iter(), remainder);
+                let allocations = self.allocation.allocate(&matches, remainder);
+
+                if allocations.iter().all(|size| *size == Decimal::zero()) {
+                    break;
+                }
+
+                let mut replenished = Vec::new();
+
+                for (resting, size) in matches.iter().zip(allocations) {
+                    if size == Decimal::zero() {
+                        continue;
+                    }
+
+                    let idx = match queue.iter().position(|queued| queued.id == resting.id) {
+                        Some(idx) => idx,
+                        None => continue,
+                    };
+
+                    remainder -= size;
+
+                    if size < resting.size {
+                        queue[idx].size -= size;
+                    } else {
+                        queue.remove(idx);
+
+                        if !resting.all_or_none && resting.hidden > Decimal::zero() {
+                            replenished.push(resting.remaining(resting.id, resting.hidden, resting.timestamp, resting.sequence));
+                        }
+                    }
+                }
+
+                queue.append(&mut replenished);
+            }
+
+            if remainder == Decimal::zero() {
+                break;
+            }
+        }
+
+        order.total() - remainder
+    }
+
+    fn remove_order(book: &mut Book, price: &Decimal, id: &Uuid) -> Option<LimitOrder> {
         if let Some(id_set) = book.price_id_sets.get_mut(price) {
             id_set.remove(id);
         }
 
-        if let Some(level) = book.price_books.get_mut(price) {
-            level.remove_order(id);
-        }
+        book.price_books.get_mut(price).and_then(|level| level.remove_order(id))
     }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use rust_decimal::prelude::{Decimal, Zero};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interval {
+    #[serde(rename = "1s")]
+    Second,
+    #[serde(rename = "1m")]
+    Minute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hour,
+}
+
+impl Interval {
+    // Second bars have to be asked for: once anything has traded they are journaled, snapshotted and published
+    // every second, whether or not there is any trading.
+    pub const DEFAULT: [Interval; 3] = [Interval::Minute, Interval::FiveMinutes, Interval::Hour];
+
+    pub fn secs(&self) -> i64 {
+        match self {
+            Interval::Second => 1,
+            Interval::Minute => 60,
+            Interval::FiveMinutes => 5 * 60,
+            Interval::Hour => 60 * 60,
+        }
+    }
+
+    // the start of the interval ts falls in
+    fn start(&self, ts: i64) -> i64 {
+        ts - ts.rem_euclid(self.secs())
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1s" => Ok(Interval::Second),
+            "1m" => Ok(Interval::Minute),
+            "5m" => Ok(Interval::FiveMinutes),
+            "1h" => Ok(Interval::Hour),
+            _ => Err(format!("unknown candle interval {}, expected one of 1s, 1m, 5m or 1h", s)),
+        }
+    }
+}
+
+// A finished bar covering start..start + interval. An interval without trades is flat at the previous close,
+// with no volume, no trades and no VWAP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CandleEvent {
+    pub(crate) interval: Interval,
+    pub(crate) start: i64,
+    pub(crate) open: Decimal,
+    pub(crate) high: Decimal,
+    pub(crate) low: Decimal,
+    pub(crate) close: Decimal,
+    pub(crate) volume: Decimal,
+    pub(crate) vwap: Option<Decimal>,
+    pub(crate) trades: u64,
+}
+
+// the bar being built for one interval
+#[derive(Debug, Clone, Copy)]
+struct Bar {
+    candle: CandleEvent,
+    value: Decimal, // traded value, for the VWAP
+}
+
+impl Bar {
+    fn flat(interval: Interval, start: i64, close: Decimal) -> Self {
+        Bar {
+            candle: CandleEvent {
+                interval,
+                start,
+                open: close,
+                high: close,
+                low: close,
+                close,
+                volume: Decimal::zero(),
+                vwap: None,
+                trades: 0,
+            },
+            value: Decimal::zero(),
+        }
+    }
+
+    fn trade(&mut self, price: Decimal, size: Decimal) {
+        let candle = &mut self.candle;
+
+        if candle.trades == 0 {
+            candle.open = price;
+            candle.high = price;
+            candle.low = price;
+        }
+
+        candle.high = candle.high.max(price);
+        candle.low = candle.low.min(price);
+        candle.close = price;
+        candle.volume += size;
+        candle.trades += 1;
+
+        self.value += price.saturating_mul(size);
+        candle.vwap = Some(self.value / candle.volume);
+    }
+
+    fn end(&self) -> i64 {
+        self.candle.start + self.candle.interval.secs()
+    }
+}
+
+// Bars for each configured interval built from executions, none unless configured. Nothing is published for an
+// interval before its first trade, from then on every interval gets a bar once it has ended.
+#[derive(Debug, Default)]
+pub struct Candles {
+    bars: Vec<(Interval, Option<Bar>)>,
+}
+
+impl Candles {
+    pub fn new(intervals: &[Interval]) -> Self {
+        Candles { bars: intervals.iter().map(|interval| (*interval, None)).collect() }
+    }
+
+    pub fn trade(&mut self, price: Decimal, size: Decimal, ts: i64) -> Vec<CandleEvent> {
+        let candles = self.close(ts);
+
+        for (interval, bar) in self.bars.iter_mut() {
+            bar.get_or_insert_with(|| Bar::flat(*interval, interval.start(ts), price)).trade(price, size);
+        }
+
+        candles
+    }
+
+    // Finish every bar that has ended by now, intervals that went by without trades are filled in with flat bars.
+    // Intervals come in the order they were configured, each in time order.
+    pub fn close(&mut self, now: i64) -> Vec<CandleEvent> {
+        let mut candles = Vec::new();
+
+        for (interval, bar) in self.bars.iter_mut() {
+            while let Some(finished) = bar.filter(|bar| bar.end() <= now) {
+                candles.push(finished.candle);
+                *bar = Some(Bar::flat(*interval, finished.end(), finished.candle.close));
+            }
+        }
+
+        candles
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::Path;
+
+use uuid::Uuid;
+use serde::{Serialize, Deserialize};
+
+use crate::orderbook::book::{OrderBook, BookRequest, BookResult, BounceEvent, BounceReason, TradesEvent, Depth};
+use crate::orderbook::journal::Journal;
+use crate::orderbook::tape::Tape;
+use crate::orderbook::snapshot::Snapshot;
+use crate::orderbook::stats::Stats;
+use crate::orderbook::order::{OrderDirection, timestamp};
+
+// the most events a single retransmission goes back over
+pub const MAX_RETRANSMIT: u64 = 1_000;
+
+// the most trades a single answer to a trades query carries, so it stays well under what a message can hold
+pub const MAX_TRADES: usize = 1_000;
+
+// An event as published, the sequence number is assigned by the engine and has no gaps across the life of the
+// book. The event itself is flattened so consumers still find it under its variant name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedResult {
+    #[serde(flatten)]
+    pub(crate) event: BookResult,
+    pub(crate) sequence: u64,
+}
+
+// One published message, the events from handling a single request (or expiry sweep) in order.
+// Envelopes are numbered on their own, also without gaps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Events {
+    pub(crate) asset: String,
+    pub(crate) sequence: u64,
+    pub(crate) events: Vec<SequencedResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) origin: Option<Origin>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) owners: BTreeMap<Uuid, u64>, // the envelope's number on the route of each owner it concerns
+}
+
+// The message and request an envelope was recorded for, so deduplication carries on across a restart. It is
+// kept in the journal and on the drop copy, the public and owner routes leave it out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Origin {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>, // the Pub/Sub message id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request: Option<RequestKey>,
+    timestamp: i64,
+}
+
+// Where an envelope is published. The public route and the drop copy see every envelope under its own number,
+// each owner's route numbers the envelopes concerning that owner on its own, also without gaps. The events on a
+// route keep their engine sequence numbers, so the events it doesn't carry leave holes in the event numbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Route {
+    Public,       // anonymized market data, everyone can subscribe
+    Owner(Uuid),  // one owner's private execution reports
+    DropCopy,     // every event as journaled, for back office consumers like the owner service
+}
+
+#[derive(Debug, Clone)]
+pub struct Publication {
+    pub(crate) route: Route,
+    pub(crate) events: Events,
+}
+
+// Everything a batch of requests publishes on one route, sent as a single message unless that would be more than
+// MAX_BATCH_BYTES. The envelopes keep the order they were published in and each one still holds the events of a
+// single request.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub(crate) route: Route,
+    pub(crate) envelopes: Vec<Events>,
+}
+
+// pub/sub turns away messages over 10MB, batches are cut well short of that
+pub const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+impl Batch {
+    // one batch per route, in the order the routes were first published to
+    pub fn of(publications: Vec<Publication>) -> Vec<Batch> {
+        Batch::within(publications, MAX_BATCH_BYTES)
+    }
+
+    // A route whose envelopes come to more than max_bytes of JSON goes out as several batches, one after the other.
+    // An envelope bigger than that on its own still goes out, alone.
+    pub(crate) fn within(publications: Vec<Publication>, max_bytes: usize) -> Vec<Batch> {
+        let mut batches: Vec<(Batch, usize)> = Vec::new();
+
+        for publication in publications {
+            let bytes = serde_json::to_vec(&publication.events).map_or(0, |json| json.len() + 1);
+            let open = batches.iter().rposition(|(batch, _)| batch.route == publication.route)
+                .filter(|at| batches[*at].1 + bytes <= max_bytes);
+
+            match open {
+                Some(at) => {
+                    batches[at].0.envelopes.push(publication.events);
+                    batches[at].1 += bytes;
+                },
+                None => batches.push((Batch { route: publication.route, envelopes: vec![publication.events] }, bytes)),
+            }
+        }
+
+        batches.into_iter().map(|(batch, _)| batch).collect()
+    }
+}
+
+impl Events {
+    // split a journaled envelope into what goes out publicly, to each owner involved and to the drop copy,
+    // the public envelope goes out even when empty so market data consumers see every envelope number
+    fn routes(self) -> Vec<Publication> {
+        let public = self.only(|event| event.event.anonymized().map(|anonymized| SequencedResult {
+            event: anonymized,
+            sequence: event.sequence,
+        }));
+
+        let mut publications = vec![Publication { route: Route::Public, events: public }];
+
+        publications.extend(self.involved().into_iter().filter_map(|owner| Some(Publication {
+            route: Route::Owner(owner),
+            events: self.owned_by(owner)?,
+        })));
+
+        publications.push(Publication { route: Route::DropCopy, events: self });
+
+        publications
+    }
+
+    // the owners the events concern in the order they first appear, a bounce for a message nobody could be made
+    // out in only goes to the drop copy
+    fn involved(&self) -> Vec<Uuid> {
+        let mut owners: Vec<Uuid> = Vec::new();
+
+        for owner in self.events.iter().filter_map(|event| event.event.owner()).filter(|owner| !owner.is_nil()) {
+            if !owners.contains(&owner) {
+                owners.push(owner);
+            }
+        }
+
+        owners
+    }
+
+    // what goes out on the owner's route, under its number there
+    fn owned_by(&self, owner: Uuid) -> Option<Events> {
+        let sequence = *self.owners.get(&owner)?;
+
+        Some(Events {
+            sequence,
+            ..self.only(|event| Some(event.clone()).filter(|event| event.event.owner() == Some(owner)))
+        })
+    }
+
+    fn only(&self, f: impl Fn(&SequencedResult) -> Option<SequencedResult>) -> Events {
+        Events {
+            asset: self.asset.clone(),
+            sequence: self.sequence,
+            events: self.events.iter().filter_map(f).collect(),
+            origin: None,
+            owners: BTreeMap::new(),
+        }
+    }
+}
+
+// requests answered by the engine rather than the book
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EngineRequest {
+    Retransmit(RetransmitRequest),
+    Trades(TradesRequest),
+}
+
+// Republish every journaled envelope holding an event numbered from..=to on the drop copy, or just what of them
+// went to an owner on their route. Envelopes go out exactly as they were first published so consumers can drop
+// anything they have already seen by its sequence number. A range wider than MAX_RETRANSMIT is cut short, the rest
+// has to be asked for again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RetransmitRequest {
+    pub(crate) from: u64,
+    pub(crate) to: u64,
+    #[serde(default)]
+    pub(crate) owner: Option<Uuid>,
+}
+
+// The trades from..=to, by timestamp and with either end left open, or just the last of them. The answer is
+// published to the owner's reports, at most MAX_TRADES at a time. A range holding more is read a page at a time by
+// asking again for the trades after the last one of the previous answer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TradesRequest {
+    pub(crate) owner: Uuid,
+    #[serde(default)]
+    pub(crate) from: Option<i64>,
+    #[serde(default)]
+    pub(crate) to: Option<i64>,
+    #[serde(default)]
+    pub(crate) last: Option<usize>,
+    #[serde(default)]
+    pub(crate) after: Option<Uuid>,
+}
+
+// everything the engine reads from its subscription, told apart by the variant name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Request {
+    Book(BookRequest),
+    Engine(EngineRequest),
+}
+
+impl Request {
+    // what requests like this one are counted as
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Book(BookRequest::Open(_)) => "open",
+            Request::Book(BookRequest::Cancel(_)) => "cancel",
+            Request::Book(BookRequest::Admin(_)) => "admin",
+            Request::Book(BookRequest::OrderStatus(_)) => "order_status",
+            Request::Book(BookRequest::OpenOrders(_)) => "open_orders",
+            Request::Engine(EngineRequest::Retransmit(_)) => "retransmit",
+            Request::Engine(EngineRequest::Trades(_)) => "trades",
+        }
+    }
+}
+
+// A request as it arrives on the subscription. It may name the asset it is meant for, otherwise it is taken to be
+// for this one.
+#[derive(Debug, Deserialize)]
+struct Message {
+    #[serde(default)]
+    asset: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+// A client request that can be told apart from any other by the owner and the request id, or the client order
+// id when there is no request id. Opens and cancels are kept apart so cancelling by the same id isn't a repeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum RequestKey {
+    Open { owner: Uuid, id: Uuid },
+    Cancel { owner: Uuid, id: Uuid },
+}
+
+impl RequestKey {
+    fn of(book_request: &BookRequest) -> Option<Self> {
+        match book_request {
+            BookRequest::Open(open_event) => open_event.request_id.or(open_event.client_id)
+                .map(|id| RequestKey::Open { owner: open_event.owner, id }),
+            BookRequest::Cancel(cancel_event) => cancel_event.request_id.or(cancel_event.client_id)
+                .map(|id| RequestKey::Cancel { owner: cancel_event.owner, id }),
+            BookRequest::Admin(_) | BookRequest::OrderStatus(_) | BookRequest::OpenOrders(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Engine {
+    asset: String,
+    book: OrderBook,
+    journal: Journal,
+    next_event: u64,
+    next_envelope: u64,
+    dedup_window: i64,                     // seconds a request is remembered for, Pub/Sub delivers at least once
+    seen: HashSet<RequestKey>,
+    seen_at: VecDeque<(i64, RequestKey)>, // oldest first, for forgetting requests once the window has passed
+    delivered: HashMap<String, u64>,      // message ids handled within the window, to the first event they published
+    delivered_at: VecDeque<(i64, String)>,
+    delivery: Option<String>,              // the message being handled, journaled with what it publishes
+    next_owner_envelope: HashMap<Uuid, u64>, // the next number on each owner's route
+    checkpoint: u64,                       // the last event in the latest snapshot of the book
+    tape: Tape,
+    stats: Stats,
+}
+
+impl Engine {
+    // Numbering carries on from the last journaled envelope, balances and volumes from every journaled trade and
+    // transfer. The tape starts out with the most recent journaled trades, requests journaled within the window
+    // are still recognised as duplicates.
+    pub fn new(asset: String, mut book: OrderBook, journal: Journal, dedup_window: i64, tape_capacity: usize) -> io::Result<Self> {
+        let mut tape = Tape::new(tape_capacity);
+        let mut seen = HashSet::new();
+        let mut seen_at = VecDeque::new();
+        let mut delivered_at = VecDeque::new();
+        let mut next_owner_envelope = HashMap::new();
+        let now = timestamp();
+
+        for envelope in journal.replay()? {
+            let envelope = envelope?;
+
+            book.restore(envelope.events.iter().map(|event| &event.event));
+            Engine::record_trades(&mut tape, envelope.events.iter().map(|event| &event.event));
+
+            let first = envelope.events.first().map_or(0, |event| event.sequence);
+            next_owner_envelope.extend(envelope.owners.iter().map(|(owner, sequence)| (*owner, sequence + 1)));
+
+            if let Some(Origin { message, request, timestamp }) = envelope.origin {
+                if let Some(key) = request.filter(|key| timestamp + dedup_window > now && seen.insert(*key)) {
+                    seen_at.push_back((timestamp, key));
+                }
+
+                // messages handled within the window of the last one, they may not have been acked however long ago
+                // the engine stopped
+                if let Some(id) = message {
+                    delivered_at.push_back((timestamp, id, first));
+
+                    while delivered_at.front().is_some_and(|(handled, _, _)| handled + dedup_window <= timestamp) {
+                        delivered_at.pop_front();
+                    }
+                }
+            }
+        }
+
+        let delivered = delivered_at.iter().map(|(_, id, first)| (id.clone(), *first)).collect();
+        let delivered_at = delivered_at.into_iter().map(|(_, id, _)| (now, id)).collect();
+
+        let (next_event, next_envelope) = match journal.last() {
+            Some(envelope) => (
+                envelope.events.last().map_or(0, |event| event.sequence) + 1,
+                envelope.sequence + 1,
+            ),
+            None => (1, 1),
+        };
+
+        Ok(Engine {
+            asset,
+            book,
+            journal,
+            next_event,
+            next_envelope,
+            dedup_window,
+            seen,
+            seen_at,
+            delivered,
+            delivered_at,
+            delivery: None,
+            next_owner_envelope,
+            checkpoint: next_event - 1,
+            tape,
+            stats: Stats::new(),
+        })
+    }
+
+    // the envelopes to publish in order, every new one has been journaled already
+    pub fn process_request(&mut self, request: Request) -> io::Result<Vec<Publication>> {
+        self.stats.request(request.kind());
+
+        match request {
+            Request::Book(book_request) => {
+                let key = RequestKey::of(&book_request);
+
+                if self.is_duplicate(key) {
+                    let events = vec![Engine::duplicate(&book_request)];
+                    return Ok(self.record(events, None)?.map(Events::routes).unwrap_or_default());
+                }
+
+                let events = self.book.process_request(book_request);
+                Ok(self.record(events, key)?.map(Events::routes).unwrap_or_default())
+            },
+            Request::Engine(EngineRequest::Retransmit(retransmit)) => {
+                let to = retransmit.to.min(retransmit.from.saturating_add(MAX_RETRANSMIT - 1));
+                let envelopes = self.journal.range(retransmit.from, to)?.into_iter();
+
+                Ok(match retransmit.owner {
+                    Some(owner) => envelopes
+                        .filter_map(|envelope| envelope.owned_by(owner))
+                        .map(|events| Publication { route: Route::Owner(owner), events })
+                        .collect(),
+                    None => envelopes
+                        .map(|events| Publication { route: Route::DropCopy, events })
+                        .collect(),
+                })
+            },
+            Request::Engine(EngineRequest::Trades(query)) => {
+                let ts = timestamp();
+
+                let events = match self.book.check_message(query.owner, ts) {
+                    Some(reason) => vec![BookResult::Bounce(BounceEvent::new(None, query.owner, None, reason, ts))],
+                    None => {
+                        let (trades, truncated, next) = self.tape.query(query.from, query.to, query.last, query.after, MAX_TRADES);
+
+                        vec![BookResult::Trades(TradesEvent {
+                            owner: query.owner,
+                            trades,
+                            truncated,
+                            next,
+                            timestamp: ts,
+                        })]
+                    },
+                };
+
+                Ok(self.record(events, None)?.map(Events::routes).unwrap_or_default())
+            },
+        }
+    }
+
+    // A raw message off the subscription, by its Pub/Sub message id. A message the engine stopped before acking is
+    // delivered again, whatever it published the first time is published again rather than handling it twice.
+    // Anything that isn't a request for this asset is bounced back to its sender rather than dropped.
+    pub fn process_message(&mut self, id: &str, data: &[u8]) -> io::Result<Vec<Publication>> {
+        self.forget(timestamp());
+
+        if let Some(first) = self.delivered.get(id).copied() {
+            return Ok(self.journal.range(first, first)?.into_iter().flat_map(Events::routes).collect());
+        }
+
+        self.delivery = Some(id.to_string());
+        let publications = self.deliver(data);
+        self.delivery = None;
+
+        publications
+    }
+
+    fn deliver(&mut self, data: &[u8]) -> io::Result<Vec<Publication>> {
+        let event = match serde_json::from_slice::<Message>(data) {
+            Ok(message) if message.asset.as_ref().is_none_or(|asset| *asset == self.asset) => {
+                return self.process_request(message.request);
+            },
+            Ok(message) => {
+                self.stats.request(message.request.kind());
+
+                Engine::turned_down(data, BounceReason::UnknownInstrument, format!(
+                    "the request is for {}, this is the book for {}", message.asset.unwrap_or_default(), self.asset,
+                ))
+            },
+            Err(err) => {
+                self.stats.request("malformed");
+
+                Engine::turned_down(data, BounceReason::MalformedMessage, format!("{}: {}", BounceReason::MalformedMessage, err))
+            },
+        };
+
+        Ok(self.record(vec![event], None)?.map(Events::routes).unwrap_or_default())
+    }
+
+    // Carry on from the latest snapshot of the book. Anything journaled after it was never published and the
+    // messages it came from were never acked, so it is rolled back and handled again once they are redelivered.
+    // A journal with events but no snapshot, or one that ends before the snapshot, doesn't go with it.
+    pub fn recover(asset: String, book: OrderBook, mut journal: Journal, path: &Path, dedup_window: i64, tape_capacity: usize) -> io::Result<Self> {
+        let snapshot = Snapshot::load(path)?;
+
+        match &snapshot {
+            Some(snapshot) => journal.rollback(snapshot.sequence)?,
+            None if journal.last().is_some() => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("the journal has events but there is no snapshot of the book at {}", path.display()))),
+            None => {},
+        }
+
+        let mut engine = Engine::new(asset, book, journal, dedup_window, tape_capacity)?;
+
+        match snapshot {
+            Some(snapshot) => engine.book.restore_snapshot(snapshot.book),
+            None => engine.snapshot(path)?,
+        }
+
+        Ok(engine)
+    }
+
+    // The book as of the last event journaled, to carry on from on the next start. Taken after every batch before
+    // anything is published, nothing is written when nothing was journaled since the last one. What the batch
+    // journaled is synced first, once for the whole batch, so the snapshot never gets ahead of the journal.
+    pub fn checkpoint(&mut self, path: &Path) -> io::Result<()> {
+        if self.checkpoint == self.next_event - 1 {
+            return Ok(());
+        }
+
+        self.journal.sync()?;
+        self.snapshot(path)
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn depth(&self, direction: OrderDirection) -> Depth {
+        self.book.depth(direction)
+    }
+
+    pub fn expire_orders(&mut self, now: i64) -> io::Result<Vec<Publication>> {
+        let events = self.book.expire_orders(now);
+        Ok(self.record(events, None)?.map(Events::routes).unwrap_or_default())
+    }
+
+    fn snapshot(&mut self, path: &Path) -> io::Result<()> {
+        Snapshot { sequence: self.next_event - 1, book: self.book.snapshot() }.save(path)?;
+        self.checkpoint = self.next_event - 1;
+
+        Ok(())
+    }
+
+    // whether the same request was already handled within the window, remembers it if not
+    fn is_duplicate(&mut self, key: Option<RequestKey>) -> bool {
+        let now = timestamp();
+        self.forget(now);
+
+        let key = match key {
+            Some(key) => key,
+            None => return false,
+        };
+
+        if !self.seen.insert(key) {
+            return true;
+        }
+
+        self.seen_at.push_back((now, key));
+
+        false
+    }
+
+    // requests and messages handled longer than the window ago
+    fn forget(&mut self, now: i64) {
+        while let Some((seen, key)) = self.seen_at.front().copied() {
+            if seen + self.dedup_window > now {
+                break;
+            }
+
+            self.seen.remove(&key);
+            self.seen_at.pop_front();
+        }
+
+        while self.delivered_at.front().is_some_and(|(delivered, _)| delivered + self.dedup_window <= now) {
+            if let Some((_, id)) = self.delivered_at.pop_front() {
+                self.delivered.remove(&id);
+            }
+        }
+    }
+
+    // trades go on the tape anonymized, it is market data
+    fn record_trades<'a>(tape: &mut Tape, events: impl Iterator<Item = &'a BookResult>) {
+        for event in events.filter(|event| matches!(event, BookResult::Traded(_))) {
+            if let Some(BookResult::Traded(trade_event)) = event.anonymized() {
+                tape.record(trade_event);
+            }
+        }
+    }
+
+    fn duplicate(book_request: &BookRequest) -> BookResult {
+        let (id, owner, client_id) = match book_request {
+            BookRequest::Open(open_event) => (None, open_event.owner, open_event.client_id),
+            BookRequest::Cancel(cancel_event) => (Some(cancel_event.id), cancel_event.owner, cancel_event.client_id),
+            BookRequest::Admin(_) | BookRequest::OrderStatus(_) | BookRequest::OpenOrders(_) => unreachable!("only opens and cancels are deduplicated"),
+        };
+
+        let mut bounce_event = BounceEvent::new(id, owner, client_id, BounceReason::DuplicateRequest, timestamp());
+        bounce_event.request_id = book_request.request_id();
+
+        BookResult::Bounce(bounce_event)
+    }
+
+    // A message that was turned down before it could be read as a request. Whatever ids can still be made out of
+    // its body go on the bounce, so it reaches the sender and says which request it was.
+    fn turned_down(data: &[u8], reason: BounceReason, text: String) -> BookResult {
+        let body = serde_json::from_slice::<serde_json::Value>(data).ok()
+            .and_then(|message| message.as_object()?.values().find(|body| body.is_object()).cloned());
+        let field = |name: &str| body.as_ref().and_then(|body| body.get(name)?.as_str()?.parse::<Uuid>().ok());
+
+        let mut bounce_event = BounceEvent::new(field("id"), field("owner").unwrap_or_else(Uuid::nil), field("client_id"), reason, timestamp());
+        bounce_event.request_id = field("request_id");
+        bounce_event.text = text;
+
+        BookResult::Bounce(bounce_event)
+    }
+
+    // number the events and their envelope and journal it along with the message and request they came from,
+    // nothing is numbered if there is nothing to publish
+    fn record(&mut self, events: Vec<BookResult>, request: Option<RequestKey>) -> io::Result<Option<Events>> {
+        if events.is_empty() {
+            return Ok(None);
+        }
+
+        let first = self.next_event;
+        let events: Vec<SequencedResult> = events.into_iter().zip(first..)
+            .map(|(event, sequence)| SequencedResult { event, sequence })
+            .collect();
+
+        let mut envelope = Events {
+            asset: self.asset.clone(),
+            sequence: self.next_envelope,
+            events,
+            origin: (self.delivery.is_some() || request.is_some()).then(|| Origin {
+                message: self.delivery.clone(),
+                request,
+                timestamp: timestamp(),
+            }),
+            owners: BTreeMap::new(),
+        };
+
+        envelope.owners = envelope.involved().into_iter()
+            .map(|owner| (owner, self.next_owner_envelope.get(&owner).copied().unwrap_or(1)))
+            .collect();
+
+        self.journal.append(&envelope)?;
+
+        if let Some(id) = &self.delivery {
+            self.delivered.insert(id.clone(), first);
+            self.delivered_at.push_back((timestamp(), id.clone()));
+        }
+
+        self.next_owner_envelope.extend(envelope.owners.iter().map(|(owner, sequence)| (*owner, sequence + 1)));
+        Engine::record_trades(&mut self.tape, envelope.events.iter().map(|event| &event.event));
+        self.stats.apply(envelope.events.iter().map(|event| &event.event));
+
+        self.next_event += envelope.events.len() as u64;
+        self.next_envelope += 1;
+
+        Ok(Some(envelope))
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+
+use uuid::Uuid;
+use rust_decimal::prelude::{Decimal, Zero};
+use rust_decimal::RoundingStrategy;
+use serde::{Serialize, Deserialize};
+
+// tiers are picked by what an owner traded over the last 30 days
+const VOLUME_WINDOW: i64 = 30 * 24 * 60 * 60;
+
+// whether an order added liquidity to the book or took it, both sides of an auction trade are takers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+// Rates are a fraction of the traded value, a negative rate is a rebate. An owner gets the tier with the highest
+// min_volume they have reached, nobody pays anything below the first tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeTier {
+    pub(crate) min_volume: Decimal, // traded value over the window
+    pub(crate) maker_rate: Decimal,
+    pub(crate) taker_rate: Decimal,
+}
+
+// The fees of this instrument. Charges are rounded up to the precision and never go below the minimum fee,
+// rebates are rounded down to the precision, so rounding never favors either side of a rebate or charge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    #[serde(default)]
+    pub(crate) tiers: Vec<FeeTier>, // lowest min_volume first
+    #[serde(default)]
+    pub(crate) minimum_fee: Decimal,
+    #[serde(default = "FeeSchedule::default_precision")]
+    pub(crate) precision: u32, // decimal places
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            tiers: Vec::new(),
+            minimum_fee: Decimal::zero(),
+            precision: FeeSchedule::default_precision(),
+        }
+    }
+}
+
+impl FeeSchedule {
+    fn default_precision() -> u32 { 2 }
+
+    fn tier(&self, volume: Decimal) -> Option<&FeeTier> {
+        self.tiers.iter().rev().find(|tier| volume >= tier.min_volume)
+    }
+
+    fn fee(&self, value: Decimal, volume: Decimal, liquidity: Liquidity) -> Decimal {
+        let rate = match (self.tier(volume), liquidity) {
+            (Some(tier), Liquidity::Maker) => tier.maker_rate,
+            (Some(tier), Liquidity::Taker) => tier.taker_rate,
+            (None, _) => return Decimal::zero(),
+        };
+
+        let fee = value * rate;
+
+        if fee > Decimal::zero() {
+            fee.round_dp_with_strategy(self.precision, RoundingStrategy::AwayFromZero).max(self.minimum_fee)
+        } else {
+            fee.round_dp_with_strategy(self.precision, RoundingStrategy::ToZero)
+        }
+    }
+}
+
+// what an owner traded within the window, oldest first
+#[derive(Debug, Default)]
+struct Volume {
+    trades: VecDeque<(i64, Decimal)>,
+    total: Decimal,
+}
+
+#[derive(Debug, Default)]
+pub struct Fees {
+    schedule: FeeSchedule,
+    volumes: HashMap<Uuid, Volume>,
+}
+
+impl Fees {
+    pub fn new() -> Self { Fees::default() }
+
+    pub fn set_schedule(&mut self, schedule: FeeSchedule) {
+        self.schedule = schedule;
+    }
+
+    // The fee for one side of a trade, at the tier the owner was at before it. The trade then counts towards
+    // their volume.
+    pub fn charge(&mut self, owner: Uuid, value: Decimal, liquidity: Liquidity, ts: i64) -> Decimal {
+        let volume = self.volume(owner, ts).total;
+        let fee = self.schedule.fee(value, volume, liquidity);
+        self.record(owner, value, ts);
+        fee
+    }
+
+    pub fn record(&mut self, owner: Uuid, value: Decimal, ts: i64) {
+        let volume = self.volume(owner, ts);
+        volume.trades.push_back((ts, value));
+        volume.total += value;
+    }
+
+    // the owner's volume with anything that has left the window forgotten
+    fn volume(&mut self, owner: Uuid, ts: i64) -> &mut Volume {
+        let volume = self.volumes.entry(owner).or_default();
+
+        while let Some((traded, value)) = volume.trades.front().copied() {
+            if traded + VOLUME_WINDOW > ts {
+                break;
+            }
+
+            volume.total -= value;
+            volume.trades.pop_front();
+        }
+
+        volume
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+use rust_decimal::prelude::{Decimal, Zero};
+use serde::{Serialize, Deserialize};
+
+use crate::orderbook::book::BookResult;
+
+// what a live order has been through since it was first placed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lineage {
+    pub(crate) parents: Vec<Uuid>, // every id the order went by before its current one, oldest first
+    pub(crate) value: Decimal,     // traded value of every fill, for the average price
+}
+
+impl Lineage {
+    // the order itself keeps count of how much it has filled
+    pub fn average_price(&self, filled: Decimal) -> Option<Decimal> {
+        if filled > Decimal::zero() {
+            Some(self.value / filled)
+        } else {
+            None
+        }
+    }
+}
+
+// Fills of the orders on the book, kept by their current id. A child order takes over its parent's lineage when
+// it is opened, so a partially filled order keeps its history however often it is replaced. Orders that are done
+// are forgotten, the history is only there to answer for live orders.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrderHistory {
+    orders: HashMap<Uuid, Lineage>,
+    current: HashMap<Uuid, Uuid>, // root id to current id, only for orders that have been replaced
+}
+
+impl OrderHistory {
+    pub fn new() -> Self { OrderHistory::default() }
+
+    pub fn lineage(&self, id: &Uuid) -> Option<&Lineage> {
+        self.orders.get(id)
+    }
+
+    // the current id of the order placed under root, root itself if it was never replaced
+    pub fn current(&self, root: &Uuid) -> Uuid {
+        self.current.get(root).copied().unwrap_or(*root)
+    }
+
+    // Follow what is about to be published. Trades are counted at the price they happened at, a filled order
+    // hands its lineage on to its child if it has one and is forgotten otherwise.
+    pub fn apply(&mut self, events: &[BookResult]) {
+        let mut filled = Vec::new();
+
+        for event in events {
+            match event {
+                BookResult::Traded(trade_event) => {
+                    for id in [trade_event.buy_order, trade_event.sell_order] {
+                        self.orders.entry(id).or_default().value += trade_event.price.saturating_mul(trade_event.size);
+                    }
+                },
+                BookResult::Filled(filled_event) => filled.push((filled_event.id, filled_event.root)),
+                BookResult::Opened(opened_event) => {
+                    if let Some(parent) = opened_event.parent {
+                        let mut lineage = self.orders.remove(&parent).unwrap_or_default();
+                        lineage.parents.push(parent);
+                        self.orders.insert(opened_event.id, lineage);
+                        self.current.insert(opened_event.root, opened_event.id);
+                    }
+                },
+                // the rest of a market order is canceled under the id of a child that never rested
+                BookResult::Canceled(canceled_event) => {
+                    self.forget(canceled_event.id, canceled_event.root);
+
+                    if let Some(parent) = canceled_event.parent {
+                        self.forget(parent, canceled_event.root);
+                    }
+                },
+                BookResult::Expired(expired_event) => self.forget(expired_event.id, expired_event.root),
+                _ => {},
+            }
+        }
+
+        for (id, root) in filled {
+            self.forget(id, root);
+        }
+    }
+
+    fn forget(&mut self, id: Uuid, root: Uuid) {
+        self.orders.remove(&id);
+
+        // a filled order's child has taken its place already
+        if self.current.get(&root) == Some(&id) {
+            self.current.remove(&root);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::orderbook::engine::Events;
+
+// Append only record of every envelope the engine has published, one JSON envelope per line. On startup it is
+// read through once to find where each envelope is, so sequence numbers carry on where they left off and old
+// envelopes can be read back for retransmission without keeping them all in memory.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    len: u64,          // bytes of whole envelopes, appends go after them
+    index: Vec<Entry>, // one per envelope, in publishing order
+    last: Option<Events>,
+}
+
+// Where an envelope sits in the file and the events it holds, first..=last. An envelope without events holds
+// the empty range after the previous one.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: usize,
+    first: u64,
+    last: u64,
+}
+
+impl Entry {
+    fn new(offset: u64, len: usize, envelope: &Events, previous: Option<&Entry>) -> Self {
+        let after = previous.map_or(0, |entry| entry.last);
+
+        Entry {
+            offset,
+            len,
+            first: envelope.events.first().map_or(after + 1, |event| event.sequence),
+            last: envelope.events.last().map_or(after, |event| event.sequence),
+        }
+    }
+}
+
+impl Journal {
+    // A last line that was only partly written when the engine stopped never made it out, it is cut off so
+    // appends carry on after the last whole envelope. Anything unreadable before that is an error.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut reader = BufReader::new(&file);
+        let mut index: Vec<Entry> = Vec::new();
+        let mut last = None;
+        let mut offset = 0;
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+
+            if read == 0 {
+                break;
+            }
+
+            let whole = line.ends_with(b"\n");
+            let at_end = reader.fill_buf()?.is_empty();
+
+            if line.iter().all(u8::is_ascii_whitespace) && whole {
+                offset += read as u64;
+                continue;
+            }
+
+            match serde_json::from_slice::<Events>(&line) {
+                Ok(envelope) if whole => {
+                    index.push(Entry::new(offset, read, &envelope, index.last()));
+                    last = Some(envelope);
+                },
+                Err(err) if !at_end => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                _ => break, // torn, only the last line can be missing its newline
+            }
+
+            offset += read as u64;
+        }
+
+        drop(reader);
+        file.set_len(offset)?;
+
+        Ok(Journal { path: path.to_path_buf(), file, len: offset, index, last })
+    }
+
+    // Written through to the file but not synced, a whole batch is appended and then synced at once.
+    pub fn append(&mut self, events: &Events) -> io::Result<()> {
+        let mut line = serde_json::to_vec(events).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        line.push(b'\n');
+
+        self.file.write_all(&line)?;
+
+        self.index.push(Entry::new(self.len, line.len(), events, self.index.last()));
+        self.len += line.len() as u64;
+        self.last = Some(events.clone());
+
+        Ok(())
+    }
+
+    // on disk before anything appended is published, so whatever was published can always be retransmitted
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    pub fn last(&self) -> Option<&Events> {
+        self.last.as_ref()
+    }
+
+    // every journaled envelope in publishing order, read from the file as the iterator goes
+    pub fn replay(&self) -> io::Result<impl Iterator<Item = io::Result<Events>>> {
+        let reader = BufReader::new(File::open(&self.path)?).take(self.len);
+
+        Ok(reader.lines()
+            .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .map(|line| serde_json::from_str(&line?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))))
+    }
+
+    // Drop every envelope after the one ending with event sequence, so the journal ends where the book was last
+    // snapshotted. Fails if the journal doesn't reach that far or an envelope straddles it.
+    pub fn rollback(&mut self, sequence: u64) -> io::Result<()> {
+        let keep = self.index.partition_point(|entry| entry.last <= sequence);
+
+        if self.index.last().map_or(0, |entry| entry.last) < sequence || self.index.get(keep).is_some_and(|entry| entry.first <= sequence) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the journal has no envelope ending with event {}", sequence)));
+        }
+
+        let len = self.index.get(keep).map_or(self.len, |entry| entry.offset);
+        self.file.set_len(len)?;
+        self.file.sync_data()?;
+
+        self.index.truncate(keep);
+        self.len = len;
+        self.last = match self.index.last() {
+            Some(entry) => Some(self.read(entry)?),
+            None => None,
+        };
+
+        Ok(())
+    }
+
+    // every envelope holding at least one event with a sequence number in from..=to, in publishing order
+    pub fn range(&self, from: u64, to: u64) -> io::Result<Vec<Events>> {
+        let start = self.index.partition_point(|entry| entry.last < from);
+        let mut envelopes = Vec::new();
+
+        for entry in self.index[start..].iter().take_while(|entry| entry.first <= to).filter(|entry| entry.first <= entry.last) {
+            envelopes.push(self.read(entry)?);
+        }
+
+        Ok(envelopes)
+    }
+
+    fn read(&self, entry: &Entry) -> io::Result<Events> {
+        let mut file = &self.file;
+        let mut line = vec![0; entry.len];
+
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.read_exact(&mut line)?;
+
+        serde_json::from_slice(&line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
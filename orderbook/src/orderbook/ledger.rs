@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+use rust_decimal::prelude::{Decimal, Zero};
+use serde::{Serialize, Deserialize};
+
+use crate::orderbook::order::OrderDirection;
+use crate::orderbook::book::{BookResult, BounceReason};
+
+// An owner's cash and holdings of this instrument. Each asset runs in its own process, so cash is whatever has
+// been transferred in for trading this instrument.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    pub(crate) cash: Decimal,
+    pub(crate) holdings: Decimal,
+    pub(crate) reserved_cash: Decimal,     // set aside for open bids
+    pub(crate) reserved_holdings: Decimal, // set aside for open asks
+}
+
+impl Balance {
+    pub fn available_cash(&self) -> Decimal {
+        self.cash - self.reserved_cash
+    }
+
+    pub fn available_holdings(&self) -> Decimal {
+        self.holdings - self.reserved_holdings
+    }
+}
+
+// what is set aside for one open order, bids hold price per unit in cash, asks hold the quantity itself
+#[derive(Debug, Clone, Copy)]
+struct Reservation {
+    owner: Uuid,
+    direction: OrderDirection,
+    price: Decimal,
+    quantity: Decimal,
+}
+
+impl Reservation {
+    fn release(&self, balance: &mut Balance, quantity: Decimal) {
+        match self.direction {
+            OrderDirection::Bid => balance.reserved_cash -= self.price.saturating_mul(quantity),
+            OrderDirection::Ask => balance.reserved_holdings -= quantity,
+        }
+    }
+}
+
+// Cash and holdings of every owner, settled trade by trade. Orders reserve what they could need when they are
+// admitted and give it back as they trade or leave the book. The ledger always keeps count, orders are only
+// rejected for exceeding the available balance once it is enforced.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    enforced: bool,
+    balances: HashMap<Uuid, Balance>,
+    reservations: HashMap<Uuid, Reservation>, // by the id of the order currently holding it
+}
+
+impl Ledger {
+    pub fn new() -> Self { Ledger::default() }
+
+    pub fn enforce(&mut self) {
+        self.enforced = true;
+    }
+
+    pub fn balance(&self, owner: &Uuid) -> Balance {
+        self.balances.get(owner).copied().unwrap_or_default()
+    }
+
+    // Whether the owner can cover an order, price is the most a bid can pay per unit, None if nothing bounds it
+    pub fn check_order(&self, owner: &Uuid, direction: OrderDirection, quantity: Decimal, price: Option<Decimal>) -> Option<BounceReason> {
+        if !self.enforced {
+            return None;
+        }
+
+        let balance = self.balance(owner);
+
+        let (required, available) = match direction {
+            OrderDirection::Bid => match price {
+                // no balance covers a value past what a Decimal holds
+                Some(price) => (price.checked_mul(quantity).unwrap_or(Decimal::MAX), balance.available_cash()),
+                None => return Some(BounceReason::NoPriceBound),
+            },
+            OrderDirection::Ask => (quantity, balance.available_holdings()),
+        };
+
+        if required > available {
+            return Some(BounceReason::InsufficientBalance { required, available });
+        }
+
+        None
+    }
+
+    pub fn reserve(&mut self, id: Uuid, owner: Uuid, direction: OrderDirection, quantity: Decimal, price: Decimal) {
+        let reservation = Reservation { owner, direction, price, quantity };
+
+        let balance = self.balances.entry(owner).or_default();
+        match direction {
+            OrderDirection::Bid => balance.reserved_cash += price.saturating_mul(quantity),
+            OrderDirection::Ask => balance.reserved_holdings += quantity,
+        }
+
+        self.reservations.insert(id, reservation);
+    }
+
+    // give back everything still set aside for an order
+    pub fn release(&mut self, id: &Uuid) {
+        if let Some(reservation) = self.reservations.remove(id) {
+            reservation.release(self.balances.entry(reservation.owner).or_default(), reservation.quantity);
+        }
+    }
+
+    // a withdrawal can't take out more than is available
+    pub fn check_transfer(&self, trader: &Uuid, cash: Decimal, holdings: Decimal) -> Option<BounceReason> {
+        if !self.enforced {
+            return None;
+        }
+
+        let balance = self.balance(trader);
+
+        if cash < Decimal::zero() && balance.available_cash() + cash < Decimal::zero() {
+            return Some(BounceReason::InsufficientBalance { required: -cash, available: balance.available_cash() });
+        }
+
+        if holdings < Decimal::zero() && balance.available_holdings() + holdings < Decimal::zero() {
+            return Some(BounceReason::InsufficientBalance { required: -holdings, available: balance.available_holdings() });
+        }
+
+        None
+    }
+
+    // Follow the book: reservations move to whatever is left of a partially filled order, trades settle and
+    // use up the reservations of both orders, and orders leaving the book give back the rest.
+    pub fn apply(&mut self, events: &[BookResult]) {
+        for event in events {
+            match event {
+                BookResult::Opened(e) => {
+                    if let Some(reservation) = e.parent.and_then(|parent| self.reservations.remove(&parent)) {
+                        self.reservations.insert(e.id, reservation);
+                    }
+                },
+                BookResult::Traded(e) => {
+                    for id in [e.buy_order, e.sell_order] {
+                        self.use_reservation(&id, e.size);
+                    }
+
+                    self.settle(event);
+                },
+                // a market order that couldn't fill completely is canceled under its child's id
+                BookResult::Canceled(e) => {
+                    self.release(&e.id);
+
+                    if let Some(parent) = e.parent {
+                        self.release(&parent);
+                    }
+                },
+                BookResult::Expired(e) => self.release(&e.id),
+                _ => self.settle(event),
+            }
+        }
+    }
+
+    // Apply what moves balances, trades with their fees and transfers, and nothing else. Balances are rebuilt this way from
+    // the journal when the engine restarts, reservations aren't as the orders holding them are gone.
+    pub fn settle(&mut self, event: &BookResult) {
+        match event {
+            BookResult::Traded(e) => {
+                let value = e.price.saturating_mul(e.size);
+
+                let buyer = self.balances.entry(e.buyer).or_default();
+                buyer.cash -= value + e.buyer_fee;
+                buyer.holdings += e.size;
+
+                let seller = self.balances.entry(e.seller).or_default();
+                seller.cash += value - e.seller_fee;
+                seller.holdings -= e.size;
+            },
+            BookResult::Transferred(e) => {
+                let balance = self.balances.entry(e.trader).or_default();
+                balance.cash += e.cash;
+                balance.holdings += e.holdings;
+            },
+            _ => {},
+        }
+    }
+
+    fn use_reservation(&mut self, id: &Uuid, quantity: Decimal) {
+        if let Some(reservation) = self.reservations.get_mut(id) {
+            let used = quantity.min(reservation.quantity);
+            reservation.quantity -= used;
+
+            let reservation = *reservation;
+            reservation.release(self.balances.entry(reservation.owner).or_default(), used);
+
+            if reservation.quantity == Decimal::zero() {
+                self.reservations.remove(id);
+            }
+        }
+    }
+}
@@ -1,5 +1,19 @@
+pub mod allocation;
+pub mod auction;
 pub mod book;
+pub mod candles;
+pub mod engine;
+pub mod fees;
+pub mod history;
+pub mod journal;
+pub mod ledger;
 pub mod order;
+pub mod risk;
+pub mod session;
+pub mod snapshot;
+pub mod stats;
+pub mod stops;
+pub mod tape;
 
 #[macro_export]
 macro_rules! bid {
@@ -12,7 +26,14 @@ macro_rules! bid {
                     size: $size.into(),
                     direction: OrderDirection::Bid,
                     timestamp: 0,
-                    uuid: None
+                    uuid: None,
+                    order_type: OrderType::Limit,
+                    display: None,
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    min_qty: None,
+                    all_or_none: false,
+                    client_id: None,
+                    request_id: None,
                 }
             ),+
         ]
@@ -30,7 +51,14 @@ macro_rules! ask {
                     size: $size.into(),
                     direction: OrderDirection::Ask,
                     timestamp: 0,
-                    uuid: None
+                    uuid: None,
+                    order_type: OrderType::Limit,
+                    display: None,
+                    time_in_force: TimeInForce::GoodTillCancel,
+                    min_qty: None,
+                    all_or_none: false,
+                    client_id: None,
+                    request_id: None,
                 }
             ),+
         ]
@@ -40,9 +68,19 @@ macro_rules! ask {
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
-    use uuid::v1::Context;
     use crate::orderbook::book::*;
     use crate::orderbook::order::*;
+    use crate::orderbook::session::TradingPhase;
+    use crate::orderbook::auction::{equilibrium, Uncross};
+    use crate::orderbook::allocation::ProRata;
+    use crate::orderbook::engine::*;
+    use crate::orderbook::journal::Journal;
+    use crate::orderbook::risk::{RiskCheck, RiskLimits};
+    use crate::orderbook::ledger::Balance;
+    use crate::orderbook::fees::{Fees, FeeSchedule, FeeTier, Liquidity};
+    use crate::orderbook::candles::{Candles, CandleEvent, Interval};
+    use crate::orderbook::tape::Tape;
+    use rust_decimal::prelude::Zero;
     use rust_decimal::prelude::Decimal;
 
     fn trader() -> Uuid {
@@ -89,7 +127,9 @@ mod tests {
         let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
             id,
             owner: trader_id,
-            timestamp: 0
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
         }));
 
         assert_eq!(events.len(), 1);
@@ -111,12 +151,14 @@ mod tests {
         let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
             id, // bogus id's to cancel
             owner: id,
-            timestamp: 0
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
         }));
 
         assert_eq!(events.len(), 1);
 
-        if let BookResult::Bounce(bounce_event) = events[0] {
+        if let BookResult::Bounce(bounce_event) = &events[0] {
             match bounce_event.reason {
                 BounceReason::OrderNotFound => (),
                 _ => panic!("Expected BounceReason to be OrderNotFound"),
@@ -146,7 +188,9 @@ mod tests {
         let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
             id,
             owner: trader_id,
-            timestamp: 0
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
         }));
 
         assert_eq!(events.len(), 1);
@@ -162,12 +206,14 @@ mod tests {
         let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
             id, // same id and trader id
             owner: trader_id,
-            timestamp: 0
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
         }));
 
         assert_eq!(events.len(), 1);
 
-        if let BookResult::Bounce(bounce_event) = events[0] {
+        if let BookResult::Bounce(bounce_event) = &events[0] {
             match bounce_event.reason {
                 BounceReason::OrderNotFound => (),
                 _ => panic!("Expected BounceReason to be OrderNotFound"),
@@ -194,7 +240,7 @@ mod tests {
             _ => panic!("Expected opened event"),
         };
 
-        let events = orderbook.process_request(BookRequest::Open(ask));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask)));
 
         // should get 1 order opened and 2 order filled events
         // should be in the order of
@@ -243,7 +289,7 @@ mod tests {
             _ => panic!("Expected opened event"),
         };
 
-        let events = orderbook.process_request(BookRequest::Open(ask));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask)));
 
         // should get 1 order opened and 2 order filled events
         // should be in the order of
@@ -306,7 +352,7 @@ mod tests {
            }
         }).collect();
 
-        let events = orderbook.process_request(BookRequest::Open(ask));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask)));
 
         // should get 1 order opened and 2 order filled events
         // should be in the order of
@@ -367,7 +413,7 @@ mod tests {
             }
         }).collect();
 
-        let events = orderbook.process_request(BookRequest::Open(ask));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask)));
 
         // should get 1 order opened and 2 order filled events
         // should be in the order of
@@ -439,7 +485,7 @@ mod tests {
             }
         }).collect();
 
-        let events = orderbook.process_request(BookRequest::Open(ask));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask)));
 
         // should get 1 order opened and 2 order filled events
         // should be in the order of
@@ -512,7 +558,7 @@ mod tests {
             }
         }).collect();
 
-        let events = orderbook.process_request(BookRequest::Open(bid));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid)));
 
         // 1) OPEN - BID
         // 2) FILLED - ASK_2_0
@@ -569,4 +615,3287 @@ mod tests {
             _ => panic!("Expected 5th result to be FilledEvent for bid"),
         };
     }
-}
\ No newline at end of file
+
+    fn set_phase(orderbook: &mut OrderBook, phase: TradingPhase) -> Vec<BookResult> {
+        orderbook.process_request(BookRequest::Admin(AdminRequest::SetPhase(PhaseEvent{
+            owner: trader(),
+            phase,
+            timestamp: 0,
+        })))
+    }
+
+    #[test]
+    fn phase_change_announced() {
+        let mut orderbook = OrderBook::new();
+
+        let events = set_phase(&mut orderbook, TradingPhase::Halted);
+
+        assert_eq!(events.len(), 1);
+
+        match events[0] {
+            BookResult::PhaseChanged(phase_event) => {
+                assert_eq!(phase_event.previous, TradingPhase::Continuous);
+                assert_eq!(phase_event.phase, TradingPhase::Halted);
+            },
+            _ => panic!("Expected PhaseChanged event"),
+        }
+    }
+
+    #[test]
+    fn invalid_phase_transition() {
+        let mut orderbook = OrderBook::new();
+
+        // can't go back to pre-open without closing first
+        let events = set_phase(&mut orderbook, TradingPhase::PreOpen);
+
+        assert_eq!(events.len(), 1);
+
+        match &events[0] {
+            BookResult::Bounce(bounce_event) => match bounce_event.reason {
+                BounceReason::InvalidPhaseTransition => (),
+                _ => panic!("Expected BounceReason to be InvalidPhaseTransition"),
+            },
+            _ => panic!("Expected bounce"),
+        }
+    }
+
+    #[test]
+    fn closed_bounces_orders() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_id = trader();
+
+        set_phase(&mut orderbook, TradingPhase::Closed);
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_id, [(10, 1)])[0]));
+
+        assert_eq!(events.len(), 1);
+
+        match &events[0] {
+            BookResult::Bounce(bounce_event) => {
+                match bounce_event.reason {
+                    BounceReason::MarketClosed => (),
+                    _ => panic!("Expected BounceReason to be MarketClosed"),
+                }
+                assert_eq!(bounce_event.owner, trader_id);
+                assert!(bounce_event.id.is_some());
+            },
+            _ => panic!("Expected bounce"),
+        }
+    }
+
+    #[test]
+    fn halted_accepts_without_matching() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        set_phase(&mut orderbook, TradingPhase::Halted);
+
+        let bid_events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        let ask_events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]));
+
+        // both orders rest on the book even though they cross
+        assert_eq!(bid_events.len(), 1);
+        assert_eq!(ask_events.len(), 1);
+        assert!(matches!(bid_events[0], BookResult::Opened(_)));
+        assert!(matches!(ask_events[0], BookResult::Opened(_)));
+    }
+
+    #[test]
+    fn resume_uncrosses_book() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        set_phase(&mut orderbook, TradingPhase::Halted);
+
+        let bid_id = match orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 2)])[0]))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected opened event"),
+        };
+
+        let ask_id = match orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected opened event"),
+        };
+
+        let events = without_trades(set_phase(&mut orderbook, TradingPhase::Continuous));
+
+        // which of the two acts as the aggressor depends on arrival order, but either way
+        // both get filled and the remainder of the bid goes back on the book
+        assert_eq!(events.len(), 4);
+
+        assert!(matches!(events[0], BookResult::PhaseChanged(_)));
+
+        for event in events[1..].iter() {
+            match event.clone() {
+                BookResult::Filled(filled_event) => {
+                    assert!(filled_event.id == bid_id || filled_event.id == ask_id);
+                    assert_eq!(filled_event.size, Decimal::from(1));
+                    assert_eq!(filled_event.price, Decimal::from(10));
+                },
+                BookResult::Opened(opened_event) => {
+                    assert_eq!(opened_event.parent.unwrap(), bid_id);
+                    assert_eq!(opened_event.size, Decimal::from(1));
+                },
+                _ => panic!("Expected only fills and the bid remainder"),
+            }
+        }
+
+        // the book is no longer crossed, so a new ask at 11 just rests
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask!(trader_b, [(11, 1)])[0])));
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn resume_matches_what_uncross_left_crossed() {
+        let mut orderbook = OrderBook::new();
+
+        let buyer = trader();
+        let seller = trader();
+
+        // the uncross executes the iceberg from its reserve too, all in the one auction trade
+        set_phase(&mut orderbook, TradingPhase::Halted);
+        orderbook.process_request(BookRequest::Open(iceberg(bid!(buyer, [(10, 10)])[0], 1)));
+        orderbook.process_request(BookRequest::Open(ask!(seller, [(10, 5)])[0]));
+
+        let events = set_phase(&mut orderbook, TradingPhase::Continuous);
+        let traded: Vec<(Decimal, Option<OrderDirection>)> = events.iter().filter_map(|event| match event {
+            BookResult::Traded(trade_event) => Some((trade_event.size, trade_event.aggressor)),
+            _ => None,
+        }).collect();
+
+        assert_eq!(traded, vec![(Decimal::from(5), None)]);
+        assert_eq!(orderbook.depth(OrderDirection::Ask).orders, 0);
+        assert_eq!((orderbook.depth(OrderDirection::Bid).orders, orderbook.depth(OrderDirection::Bid).size), (1, Decimal::from(1)));
+
+        // all or none orders sit the uncross out and trade once matching resumes
+        set_phase(&mut orderbook, TradingPhase::Halted);
+        orderbook.process_request(BookRequest::Open(all_or_none(ask!(seller, [(9, 3)])[0])));
+
+        let events = set_phase(&mut orderbook, TradingPhase::Continuous);
+        assert!(events.iter().any(|event| matches!(event, BookResult::Traded(trade_event) if trade_event.size == Decimal::from(3))));
+        assert_eq!(orderbook.depth(OrderDirection::Ask).orders, 0);
+    }
+
+    #[test]
+    fn cancel_while_closed() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_id = trader();
+
+        let id = match orderbook.process_request(BookRequest::Open(bid!(trader_id, [(10, 1)])[0]))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected BookResult::Opened"),
+        };
+
+        set_phase(&mut orderbook, TradingPhase::Closed);
+
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id,
+            owner: trader_id,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        assert!(matches!(events[0], BookResult::Canceled(_)));
+    }
+
+    fn levels(levels: &[(i64, i64)]) -> Vec<(Decimal, Decimal)> {
+        levels.iter().map(|(price, size)| (Decimal::from(*price), Decimal::from(*size))).collect()
+    }
+
+    #[test]
+    fn equilibrium_max_volume() {
+        // price | demand | supply | volume
+        //   99  |   60   |   15   |   15
+        //  100  |   60   |   35   |   35  <-
+        //  101  |   30   |   60   |   30
+        //  102  |   10   |   60   |   10
+        let bids = levels(&[(100, 30), (101, 20), (102, 10)]);
+        let asks = levels(&[(99, 15), (100, 20), (101, 25)]);
+
+        assert_eq!(equilibrium(&bids, &asks, None), Some(Uncross{
+            price: Decimal::from(100),
+            volume: Decimal::from(35),
+            surplus: Decimal::from(25),
+        }));
+    }
+
+    #[test]
+    fn equilibrium_min_surplus() {
+        // price | demand | supply | volume | surplus
+        //  100  |   20   |   10   |   10   |   10
+        //  101  |   10   |   15   |   10   |   -5  <-
+        let bids = levels(&[(100, 10), (101, 10)]);
+        let asks = levels(&[(100, 10), (101, 5)]);
+
+        assert_eq!(equilibrium(&bids, &asks, None), Some(Uncross{
+            price: Decimal::from(101),
+            volume: Decimal::from(10),
+            surplus: Decimal::from(-5),
+        }));
+    }
+
+    #[test]
+    fn equilibrium_market_pressure() {
+        // price | demand | supply | volume | surplus
+        //  100  |   20   |   10   |   10   |   10
+        //  101  |   20   |   10   |   10   |   10  <- buy pressure takes the highest price
+        let bids = levels(&[(101, 20)]);
+        let asks = levels(&[(100, 10)]);
+
+        assert_eq!(equilibrium(&bids, &asks, None).unwrap().price, Decimal::from(101));
+
+        // price | demand | supply | volume | surplus
+        //  100  |   10   |   20   |   10   |  -10  <- sell pressure takes the lowest price
+        //  101  |   10   |   20   |   10   |  -10
+        let bids = levels(&[(101, 10)]);
+        let asks = levels(&[(100, 20)]);
+
+        assert_eq!(equilibrium(&bids, &asks, None).unwrap().price, Decimal::from(100));
+    }
+
+    #[test]
+    fn equilibrium_reference_price() {
+        // price | demand | supply | volume | surplus
+        //  100  |   10   |   10   |   10   |    0
+        //  105  |   10   |   10   |   10   |    0
+        let bids = levels(&[(105, 10)]);
+        let asks = levels(&[(100, 10)]);
+
+        assert_eq!(equilibrium(&bids, &asks, Some(Decimal::from(110))).unwrap().price, Decimal::from(105));
+        assert_eq!(equilibrium(&bids, &asks, Some(Decimal::from(90))).unwrap().price, Decimal::from(100));
+        assert_eq!(equilibrium(&bids, &asks, Some(Decimal::from(102))).unwrap().price, Decimal::from(100));
+        assert_eq!(equilibrium(&bids, &asks, Some(Decimal::from(103))).unwrap().price, Decimal::from(105));
+
+        // equidistant from the reference and without one the lowest price wins
+        assert_eq!(equilibrium(&bids, &asks, Some(Decimal::new(1025, 1))).unwrap().price, Decimal::from(100));
+        assert_eq!(equilibrium(&bids, &asks, None).unwrap().price, Decimal::from(100));
+    }
+
+    #[test]
+    fn equilibrium_not_crossed() {
+        let bids = levels(&[(99, 10)]);
+        let asks = levels(&[(100, 10)]);
+
+        assert_eq!(equilibrium(&bids, &asks, None), None);
+        assert_eq!(equilibrium(&bids, &[], None), None);
+    }
+
+    #[test]
+    fn opening_auction() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        set_phase(&mut orderbook, TradingPhase::Closed);
+        set_phase(&mut orderbook, TradingPhase::PreOpen);
+
+        let bid_ids: Vec<Uuid> = bid!(trader_a, [(102, 10), (101, 20), (100, 30)]).iter().map(|b| {
+            let events = without_trades(orderbook.process_request(BookRequest::Open(*b)));
+
+            // every order during the call is followed by the indicative uncrossing
+            assert_eq!(events.len(), 2);
+            assert!(matches!(events[1], BookResult::Indicative(_)));
+
+            match events[0] {
+                BookResult::Opened(opened_event) => opened_event.id,
+                _ => panic!("Expected Opened BookResult"),
+            }
+        }).collect();
+
+        let ask_ids: Vec<Uuid> = ask!(trader_b, [(99, 15), (100, 20), (101, 25)]).iter().map(|a| {
+            match orderbook.process_request(BookRequest::Open(*a))[0] {
+                BookResult::Opened(opened_event) => opened_event.id,
+                _ => panic!("Expected Opened BookResult"),
+            }
+        }).collect();
+
+        // check the last indicative after a cancel that changes nothing
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id: trader(),
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        match events[1] {
+            BookResult::Indicative(indicative_event) => {
+                assert_eq!(indicative_event.price, Some(Decimal::from(100)));
+                assert_eq!(indicative_event.volume, Decimal::from(35));
+                assert_eq!(indicative_event.surplus, Decimal::from(25));
+            },
+            _ => panic!("Expected Indicative BookResult"),
+        }
+
+        let events = without_trades(set_phase(&mut orderbook, TradingPhase::Continuous));
+
+        // 1) PHASE CHANGED
+        // 2) FILLED - BID 102 x 10
+        // 3) FILLED - BID 101 x 20
+        // 4) FILLED - BID 100 x 5
+        // 5) OPEN - BID 100 x 25
+        // 6) FILLED - ASK 99 x 15
+        // 7) FILLED - ASK 100 x 20
+        assert_eq!(events.len(), 7);
+
+        assert!(matches!(events[0], BookResult::PhaseChanged(_)));
+
+        let expected_fills = [(1, bid_ids[0], 10), (2, bid_ids[1], 20), (3, bid_ids[2], 5), (5, ask_ids[0], 15), (6, ask_ids[1], 20)];
+
+        for (idx, id, size) in expected_fills {
+            match events[idx].clone() {
+                BookResult::Filled(filled_event) => {
+                    assert_eq!(filled_event.id, id);
+                    assert_eq!(filled_event.size, Decimal::from(size));
+                    assert_eq!(filled_event.price, Decimal::from(100)); // everyone trades at the uncrossing price
+                },
+                _ => panic!("Expected FilledEvent at {}", idx),
+            }
+        }
+
+        match events[4] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.parent.unwrap(), bid_ids[2]);
+                assert_eq!(opened_event.size, Decimal::from(25));
+                assert_eq!(opened_event.price, Decimal::from(100));
+            },
+            _ => panic!("Expected OpenedEvent for the bid remainder"),
+        }
+
+        // the 101 ask was not crossing, so a bid at 101 trades against it in continuous trading
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_a, [(101, 1)])[0])));
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn closing_auction() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        set_phase(&mut orderbook, TradingPhase::ClosingAuction);
+
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(105, 10)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_b, [(100, 10)])[0]));
+
+        let events = without_trades(set_phase(&mut orderbook, TradingPhase::Closed));
+
+        // 1) PHASE CHANGED
+        // 2) FILLED - BID
+        // 3) FILLED - ASK
+        assert_eq!(events.len(), 3);
+
+        match events[0] {
+            BookResult::PhaseChanged(phase_event) => {
+                assert_eq!(phase_event.previous, TradingPhase::ClosingAuction);
+                assert_eq!(phase_event.phase, TradingPhase::Closed);
+            },
+            _ => panic!("Expected PhaseChanged event"),
+        }
+
+        for event in events[1..].iter() {
+            match event.clone() {
+                BookResult::Filled(filled_event) => {
+                    assert_eq!(filled_event.size, Decimal::from(10));
+                    assert_eq!(filled_event.price, Decimal::from(100)); // no reference price, so the lowest
+                },
+                _ => panic!("Expected FilledEvent"),
+            }
+        }
+    }
+
+    fn with_type(mut open_event: OpenEvent, order_type: OrderType) -> OpenEvent {
+        open_event.order_type = order_type;
+        open_event
+    }
+
+    #[test]
+    fn market_order_sweeps_and_cancels_rest() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        for a in ask!(trader_a, [(10, 1), (12, 1)]) {
+            orderbook.process_request(BookRequest::Open(a));
+        }
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(with_type(bid!(trader_b, [(0, 3)])[0], OrderType::Market))));
+
+        // 1) OPEN - MARKET BID
+        // 2) FILLED - ASK 10
+        // 3) FILLED - ASK 12
+        // 4) FILLED - MARKET BID
+        // 5) CANCELED - MARKET BID remainder
+        assert_eq!(events.len(), 5);
+
+        let bid_id = match events[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the market bid"),
+        };
+
+        match events[3].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, bid_id);
+                assert_eq!(filled_event.size, Decimal::from(2));
+                assert_eq!(filled_event.price, Decimal::from(12));
+            },
+            _ => panic!("Expected FilledEvent for the market bid"),
+        }
+
+        match events[4] {
+            BookResult::Canceled(canceled_event) => assert_eq!(canceled_event.parent.unwrap(), bid_id),
+            _ => panic!("Expected CanceledEvent for the market bid remainder"),
+        }
+
+        // nothing left to trade against, so the whole order is canceled
+        let events = without_trades(orderbook.process_request(BookRequest::Open(with_type(bid!(trader_b, [(0, 1)])[0], OrderType::Market))));
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[1], BookResult::Canceled(_)));
+    }
+
+    #[test]
+    fn market_order_bounced_outside_continuous() {
+        let mut orderbook = OrderBook::new();
+
+        set_phase(&mut orderbook, TradingPhase::Halted);
+
+        let events = orderbook.process_request(BookRequest::Open(with_type(bid!(trader(), [(0, 1)])[0], OrderType::Market)));
+
+        match &events[0] {
+            BookResult::Bounce(bounce_event) => match bounce_event.reason {
+                BounceReason::TradingHalted => (),
+                _ => panic!("Expected BounceReason to be TradingHalted"),
+            },
+            _ => panic!("Expected bounce"),
+        }
+    }
+
+    #[test]
+    fn stop_triggered_by_trade() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        for a in ask!(trader_a, [(10, 1), (11, 1)]) {
+            orderbook.process_request(BookRequest::Open(a));
+        }
+
+        let stop = with_type(bid!(trader_b, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(10) });
+
+        // nothing has traded yet, so the stop just waits
+        let events = without_trades(orderbook.process_request(BookRequest::Open(stop)));
+        assert_eq!(events.len(), 1);
+
+        let stop_id = match events[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the stop"),
+        };
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 1)])[0])));
+
+        // 1) OPEN - BID
+        // 2) FILLED - ASK 10
+        // 3) FILLED - BID
+        // 4) TRIGGERED - STOP
+        // 5) OPEN - STOP as market
+        // 6) FILLED - ASK 11
+        // 7) FILLED - STOP
+        assert_eq!(events.len(), 7);
+
+        match events[3] {
+            BookResult::Triggered(triggered_event) => {
+                assert_eq!(triggered_event.id, stop_id);
+                assert_eq!(triggered_event.trigger, Decimal::from(10));
+                assert_eq!(triggered_event.last_price, Decimal::from(10));
+            },
+            _ => panic!("Expected TriggeredEvent for the stop"),
+        }
+
+        match events[4] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.id, stop_id);
+                assert_eq!(opened_event.order_type, OrderType::Market);
+            },
+            _ => panic!("Expected OpenedEvent for the activated stop"),
+        }
+
+        match events[6].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, stop_id);
+                assert_eq!(filled_event.price, Decimal::from(11));
+            },
+            _ => panic!("Expected FilledEvent for the stop"),
+        }
+    }
+
+    #[test]
+    fn stop_limit_rests_after_trigger() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        for b in bid!(trader_a, [(10, 1), (8, 1)]) {
+            orderbook.process_request(BookRequest::Open(b));
+        }
+
+        // sell if the price drops to 10, but not below 9
+        let stop = with_type(ask!(trader_b, [(9, 1)])[0], OrderType::StopLimit { trigger: Decimal::from(10) });
+        orderbook.process_request(BookRequest::Open(stop));
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0])));
+
+        // 1) OPEN - ASK
+        // 2) FILLED - BID 10
+        // 3) FILLED - ASK
+        // 4) TRIGGERED - STOP LIMIT
+        // 5) OPEN - STOP LIMIT as a resting ask at 9
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[3], BookResult::Triggered(_)));
+
+        match events[4] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.order_type, OrderType::Limit);
+                assert_eq!(opened_event.price, Decimal::from(9));
+            },
+            _ => panic!("Expected OpenedEvent for the activated stop limit"),
+        }
+    }
+
+    #[test]
+    fn stop_cascade() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        for b in bid!(trader_a, [(10, 1), (9, 1), (8, 1)]) {
+            orderbook.process_request(BookRequest::Open(b));
+        }
+
+        let stops: Vec<Uuid> = [9, 10].iter().map(|trigger| {
+            let stop = with_type(ask!(trader_b, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(*trigger) });
+
+            match orderbook.process_request(BookRequest::Open(stop))[0] {
+                BookResult::Opened(opened_event) => opened_event.id,
+                _ => panic!("Expected OpenedEvent for the stop"),
+            }
+        }).collect();
+
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]));
+
+        // the ask trades at 10 which triggers the stop at 10, that one trades at 9 and triggers the stop at 9
+        let triggered: Vec<(Uuid, Decimal)> = events.iter().filter_map(|event| match event {
+            BookResult::Triggered(triggered_event) => Some((triggered_event.id, triggered_event.last_price)),
+            _ => None,
+        }).collect();
+
+        assert_eq!(triggered, vec![(stops[1], Decimal::from(10)), (stops[0], Decimal::from(9))]);
+
+        let fills: Vec<Decimal> = events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) if filled_event.owner == trader_a => Some(filled_event.price),
+            _ => None,
+        }).collect();
+
+        assert_eq!(fills, vec![Decimal::from(10), Decimal::from(9), Decimal::from(8)]);
+    }
+
+    #[test]
+    fn cancel_stop() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_id = trader();
+
+        let stop = with_type(bid!(trader_id, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(10) });
+
+        let id = match orderbook.process_request(BookRequest::Open(stop))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the stop"),
+        };
+
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id,
+            owner: trader_id,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        match events[0] {
+            BookResult::Canceled(canceled_event) => assert_eq!(canceled_event.id, id),
+            _ => panic!("Expected canceled event"),
+        }
+    }
+
+    fn iceberg(mut open_event: OpenEvent, peak: i64) -> OpenEvent {
+        open_event.display = Some(Decimal::from(peak));
+        open_event
+    }
+
+    #[test]
+    fn iceberg_replenishes() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(iceberg(ask!(trader_a, [(10, 10)])[0], 3))));
+
+        // only the peak is shown
+        let ask_id = match events[0] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.size, Decimal::from(3));
+                opened_event.id
+            },
+            _ => panic!("Expected OpenedEvent for the iceberg"),
+        };
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 5)])[0])));
+
+        // 1) OPEN - BID
+        // 2) FILLED - ASK slice x 3
+        // 3) OPEN - ASK replenished slice x 3
+        // 4) FILLED - ASK replenished slice x 2
+        // 5) OPEN - ASK rest of the slice x 1
+        // 6) FILLED - BID
+        assert_eq!(events.len(), 6);
+
+        match events[1].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, ask_id);
+                assert_eq!(filled_event.size, Decimal::from(3));
+            },
+            _ => panic!("Expected FilledEvent for the first slice"),
+        }
+
+        let slice_id = match events[2] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.parent.unwrap(), ask_id);
+                assert_eq!(opened_event.size, Decimal::from(3));
+                opened_event.id
+            },
+            _ => panic!("Expected OpenedEvent for the replenished slice"),
+        };
+
+        match events[3].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, slice_id);
+                assert_eq!(filled_event.size, Decimal::from(2));
+            },
+            _ => panic!("Expected FilledEvent for the replenished slice"),
+        }
+
+        match events[4] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.parent.unwrap(), slice_id);
+                assert_eq!(opened_event.size, Decimal::from(1));
+            },
+            _ => panic!("Expected OpenedEvent for the rest of the slice"),
+        }
+
+        match events[5].clone() {
+            BookResult::Filled(filled_event) => assert_eq!(filled_event.size, Decimal::from(5)),
+            _ => panic!("Expected FilledEvent for the bid"),
+        }
+
+        // 1 shown + 4 in reserve are left, all of it trades
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 6)])[0])));
+
+        let filled: Decimal = events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) if filled_event.owner == trader_a => Some(filled_event.size),
+            _ => None,
+        }).sum();
+
+        assert_eq!(filled, Decimal::from(5));
+    }
+
+    #[test]
+    fn iceberg_loses_priority_on_replenish() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(iceberg(ask!(trader_a, [(10, 2)])[0], 1)));
+        orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]));
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 2)])[0])));
+
+        // 1) OPEN - BID
+        // 2) FILLED - ICEBERG slice
+        // 3) OPEN - ICEBERG replenished slice, behind the plain ask
+        // 4) FILLED - PLAIN ASK
+        // 5) FILLED - BID
+        assert_eq!(events.len(), 5);
+
+        match events[3].clone() {
+            BookResult::Filled(filled_event) => assert_eq!(filled_event.owner, trader_b),
+            _ => panic!("Expected FilledEvent for the plain ask"),
+        }
+    }
+
+    #[test]
+    fn aggressive_iceberg_rests_peak() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 3)])[0]));
+
+        // the whole iceberg can trade on entry, not just the peak
+        let events = without_trades(orderbook.process_request(BookRequest::Open(iceberg(bid!(trader_b, [(10, 10)])[0], 2))));
+
+        // 1) OPEN - BID peak
+        // 2) FILLED - ASK
+        // 3) FILLED - BID
+        // 4) OPEN - BID remainder, showing the peak
+        assert_eq!(events.len(), 4);
+
+        match events[2].clone() {
+            BookResult::Filled(filled_event) => assert_eq!(filled_event.size, Decimal::from(3)),
+            _ => panic!("Expected FilledEvent for the bid"),
+        }
+
+        match events[3] {
+            BookResult::Opened(opened_event) => assert_eq!(opened_event.size, Decimal::from(2)),
+            _ => panic!("Expected OpenedEvent for the bid remainder"),
+        }
+    }
+
+    fn with_tif(mut open_event: OpenEvent, time_in_force: TimeInForce) -> OpenEvent {
+        open_event.time_in_force = time_in_force;
+        open_event
+    }
+
+    #[test]
+    fn good_till_date_expires() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_id = trader();
+        let expiry = timestamp() + 60;
+
+        let bid = with_tif(bid!(trader_id, [(10, 1)])[0], TimeInForce::GoodTillDate { expiry });
+
+        let id = match orderbook.process_request(BookRequest::Open(bid))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected BookResult::Opened"),
+        };
+
+        // not yet
+        assert!(orderbook.expire_orders(expiry - 1).is_empty());
+
+        let events = orderbook.expire_orders(expiry);
+
+        assert_eq!(events.len(), 1);
+
+        match events[0] {
+            BookResult::Expired(expired_event) => {
+                assert_eq!(expired_event.id, id);
+                assert_eq!(expired_event.owner, trader_id);
+            },
+            _ => panic!("Expected BookResult::Expired"),
+        }
+
+        // the order is gone from the book
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_id, [(10, 1)])[0]));
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn expired_on_entry_bounces() {
+        let mut orderbook = OrderBook::new();
+
+        let bid = with_tif(bid!(trader(), [(10, 1)])[0], TimeInForce::GoodTillDate { expiry: 0 });
+
+        match &orderbook.process_request(BookRequest::Open(bid))[0] {
+            BookResult::Bounce(bounce_event) => match bounce_event.reason {
+                BounceReason::AlreadyExpired => (),
+                _ => panic!("Expected BounceReason to be AlreadyExpired"),
+            },
+            _ => panic!("Expected bounce"),
+        }
+    }
+
+    #[test]
+    fn day_orders_expire_at_close() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_id = trader();
+
+        let day_id = match orderbook.process_request(BookRequest::Open(with_tif(bid!(trader_id, [(10, 1)])[0], TimeInForce::Day)))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected BookResult::Opened"),
+        };
+
+        // good till cancel stays on the book
+        orderbook.process_request(BookRequest::Open(bid!(trader_id, [(9, 1)])[0]));
+
+        let events = set_phase(&mut orderbook, TradingPhase::Closed);
+
+        // 1) PHASE CHANGED
+        // 2) EXPIRED - DAY BID
+        assert_eq!(events.len(), 2);
+
+        match events[1] {
+            BookResult::Expired(expired_event) => assert_eq!(expired_event.id, day_id),
+            _ => panic!("Expected BookResult::Expired"),
+        }
+    }
+
+    fn peg(open_event: OpenEvent, reference: PegReference, offset: i64, cap: Option<Decimal>) -> OpenEvent {
+        with_type(open_event, OrderType::Peg { reference, offset: Decimal::from(offset), cap })
+    }
+
+    #[test]
+    fn primary_peg_follows_best_bid() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(12, 1)])[0]));
+
+        let events = orderbook.process_request(BookRequest::Open(peg(bid!(trader_b, [(0, 1)])[0], PegReference::Primary, 0, None)));
+
+        let peg_id = match events[0] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.price, Decimal::from(10));
+                opened_event.id
+            },
+            _ => panic!("Expected OpenedEvent for the peg"),
+        };
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(11, 1)])[0]));
+
+        // 1) OPEN - BID 11
+        // 2) REPLACED - PEG 10 -> 11
+        assert_eq!(events.len(), 2);
+
+        match events[1] {
+            BookResult::Replaced(replaced_event) => {
+                assert_eq!(replaced_event.id, peg_id);
+                assert_eq!(replaced_event.previous_price, Decimal::from(10));
+                assert_eq!(replaced_event.price, Decimal::from(11));
+            },
+            _ => panic!("Expected ReplacedEvent for the peg"),
+        }
+
+        // nothing about the top of the book changes, so nothing moves
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(9, 1)])[0]));
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn midpoint_peg_capped() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(12, 1)])[0]));
+
+        let uncapped = peg(bid!(trader_a, [(0, 1)])[0], PegReference::Midpoint, 0, None);
+        let capped = peg(bid!(trader_a, [(0, 1)])[0], PegReference::Midpoint, 0, Some(Decimal::new(105, 1)));
+
+        match orderbook.process_request(BookRequest::Open(uncapped))[0] {
+            BookResult::Opened(opened_event) => assert_eq!(opened_event.price, Decimal::from(11)),
+            _ => panic!("Expected OpenedEvent for the uncapped peg"),
+        }
+
+        match orderbook.process_request(BookRequest::Open(capped))[0] {
+            BookResult::Opened(opened_event) => assert_eq!(opened_event.price, Decimal::new(105, 1)),
+            _ => panic!("Expected OpenedEvent for the capped peg"),
+        }
+    }
+
+    #[test]
+    fn market_peg_trades_on_entry() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(12, 1)])[0]));
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(peg(bid!(trader_b, [(0, 1)])[0], PegReference::Market, 0, None))));
+
+        // 1) OPEN - PEG at the best ask
+        // 2) FILLED - ASK
+        // 3) FILLED - PEG
+        assert_eq!(events.len(), 3);
+
+        match events[2].clone() {
+            BookResult::Filled(filled_event) => assert_eq!(filled_event.price, Decimal::from(12)),
+            _ => panic!("Expected FilledEvent for the peg"),
+        }
+    }
+
+    #[test]
+    fn repriced_peg_trades_when_crossing() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(12, 1)])[0]));
+
+        // one better than the best bid
+        let events = without_trades(orderbook.process_request(BookRequest::Open(peg(bid!(trader_b, [(0, 1)])[0], PegReference::Primary, 1, None))));
+
+        let peg_id = match events[0] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.price, Decimal::from(11));
+                opened_event.id
+            },
+            _ => panic!("Expected OpenedEvent for the peg"),
+        };
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(bid!(trader_a, [(11, 1)])[0])));
+
+        // 1) OPEN - BID 11
+        // 2) REPLACED - PEG 11 -> 12
+        // 3) FILLED - ASK 12
+        // 4) FILLED - PEG
+        assert_eq!(events.len(), 4);
+
+        match events[1] {
+            BookResult::Replaced(replaced_event) => assert_eq!(replaced_event.price, Decimal::from(12)),
+            _ => panic!("Expected ReplacedEvent for the peg"),
+        }
+
+        match events[3].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, peg_id);
+                assert_eq!(filled_event.price, Decimal::from(12));
+            },
+            _ => panic!("Expected FilledEvent for the peg"),
+        }
+    }
+
+    #[test]
+    fn peg_without_reference_bounces() {
+        let mut orderbook = OrderBook::new();
+
+        let events = orderbook.process_request(BookRequest::Open(peg(bid!(trader(), [(0, 1)])[0], PegReference::Midpoint, 0, None)));
+
+        match &events[0] {
+            BookResult::Bounce(bounce_event) => match bounce_event.reason {
+                BounceReason::NoReferencePrice => (),
+                _ => panic!("Expected BounceReason to be NoReferencePrice"),
+            },
+            _ => panic!("Expected bounce"),
+        }
+    }
+    #[test]
+    fn peg_offset_kept_to_valid_prices() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let best_id = match orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected BookResult::Opened"),
+        };
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(4, 1)])[0]));
+
+        // an offset no price could take is turned away before it is added to anything
+        let huge = with_type(bid!(trader_b, [(0, 1)])[0], OrderType::Peg { reference: PegReference::Primary, offset: Decimal::MAX, cap: None });
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(huge))), Some(BounceReason::InvalidPrice)));
+
+        // and one that would put the peg at or below zero is bounced when it is placed
+        let below = peg(bid!(trader_b, [(0, 1)])[0], PegReference::Primary, -90, None);
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(below))), Some(BounceReason::InvalidPrice)));
+
+        match orderbook.process_request(BookRequest::Open(peg(bid!(trader_b, [(0, 1)])[0], PegReference::Primary, -5, None)))[0] {
+            BookResult::Opened(opened_event) => assert_eq!(opened_event.price, Decimal::from(5)),
+            _ => panic!("Expected OpenedEvent for the peg"),
+        }
+
+        // the best bid falls to 4, which would take the peg to -1, so it stays at 5
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id: best_id,
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        assert_eq!(events.len(), 1);
+        assert!(orderbook.snapshot().orders.iter().all(|order| order.price > Decimal::zero()));
+    }
+
+    fn trigger_moves(events: &[BookResult]) -> Vec<(Decimal, Decimal)> {
+        events.iter().filter_map(|event| match event {
+            BookResult::TriggerMoved(moved_event) => Some((moved_event.trigger, moved_event.watermark)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn trailing_stop_follows_high_water_mark() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(8, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(bid!(trader_b, [(8, 1)])[0]));
+
+        // sell if the price falls 2 below the highest trade since now
+        let stop = with_type(ask!(trader_b, [(0, 1)])[0], OrderType::TrailingStop { trail: Trail::Amount(Decimal::from(2)) });
+        let events = orderbook.process_request(BookRequest::Open(stop));
+
+        // 1) OPEN - TRAILING STOP
+        // 2) TRIGGER MOVED - 6 off the last trade at 8
+        assert_eq!(events.len(), 2);
+        assert_eq!(trigger_moves(&events), vec![(Decimal::from(6), Decimal::from(8))]);
+
+        let stop_id = match events[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the trailing stop"),
+        };
+
+        // the price rises to 10 and the trigger follows
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 1)])[0]));
+        assert_eq!(trigger_moves(&events), vec![(Decimal::from(8), Decimal::from(10))]);
+
+        for b in bid!(trader_a, [(9, 1), (8, 1), (7, 1)]) {
+            let events = orderbook.process_request(BookRequest::Open(b));
+            assert!(trigger_moves(&events).is_empty());
+        }
+
+        // trading down through 9 leaves the trigger where it is, reaching 8 triggers the stop
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(8, 2)])[0]));
+        assert!(trigger_moves(&events).is_empty());
+
+        let triggered: Vec<(Uuid, Decimal, Decimal)> = events.iter().filter_map(|event| match event {
+            BookResult::Triggered(triggered_event) => Some((triggered_event.id, triggered_event.trigger, triggered_event.last_price)),
+            _ => None,
+        }).collect();
+
+        assert_eq!(triggered, vec![(stop_id, Decimal::from(8), Decimal::from(8))]);
+
+        let fills: Vec<Decimal> = events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) if filled_event.id == stop_id => Some(filled_event.price),
+            _ => None,
+        }).collect();
+
+        assert_eq!(fills, vec![Decimal::from(7)]);
+    }
+
+    #[test]
+    fn trailing_stop_percent_follows_low_water_mark() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        // nothing has traded, so there is no trigger until the first trade
+        let stop = with_type(bid!(trader_b, [(0, 1)])[0], OrderType::TrailingStop { trail: Trail::Percent(Decimal::from(10)) });
+        let events = orderbook.process_request(BookRequest::Open(stop));
+        assert_eq!(events.len(), 1);
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        assert_eq!(trigger_moves(&events), vec![(Decimal::from(11), Decimal::from(10))]);
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(8, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(8, 1)])[0]));
+        assert_eq!(trigger_moves(&events), vec![(Decimal::new(88, 1), Decimal::from(8))]);
+
+        // buy stops trail the low, a higher trade short of the trigger leaves it alone
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(Decimal::new(85, 1), 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(Decimal::new(85, 1), 1)])[0]));
+        assert!(trigger_moves(&events).is_empty());
+        assert!(!events.iter().any(|event| matches!(event, BookResult::Triggered(_))));
+    }
+
+    fn min_qty(mut open_event: OpenEvent, min_qty: i64) -> OpenEvent {
+        open_event.min_qty = Some(Decimal::from(min_qty));
+        open_event
+    }
+
+    fn all_or_none(mut open_event: OpenEvent) -> OpenEvent {
+        open_event.all_or_none = true;
+        open_event
+    }
+
+    fn opened_id(events: &[BookResult]) -> Uuid {
+        events.iter().find_map(|event| match event {
+            BookResult::Opened(opened_event) => Some(opened_event.id),
+            _ => None,
+        }).expect("Expected an OpenedEvent")
+    }
+
+    // every event but the trades, for checking what happened to each order
+    fn without_trades(events: Vec<BookResult>) -> Vec<BookResult> {
+        events.into_iter().filter(|event| !matches!(event, BookResult::Traded(_))).collect()
+    }
+
+    fn fills(events: &[BookResult]) -> Vec<(Uuid, Decimal, Decimal)> {
+        events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) => Some((filled_event.id, filled_event.price, filled_event.size)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn min_qty_trades_when_enough_is_executable() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let asks: Vec<Uuid> = ask!(trader_a, [(10, 1), (11, 2)]).into_iter()
+            .map(|a| opened_id(&orderbook.process_request(BookRequest::Open(a))))
+            .collect();
+
+        let events = without_trades(orderbook.process_request(BookRequest::Open(min_qty(bid!(trader_b, [(11, 5)])[0], 3))));
+        let bid_id = opened_id(&events);
+
+        // 1) OPEN - BID
+        // 2) FILLED - ASK 10
+        // 3) FILLED - ASK 11
+        // 4) FILLED - BID 3 of 5
+        // 5) OPEN - rest of the BID
+        assert_eq!(events.len(), 5);
+        assert_eq!(fills(&events), vec![
+            (asks[0], Decimal::from(10), Decimal::from(1)),
+            (asks[1], Decimal::from(11), Decimal::from(2)),
+            (bid_id, Decimal::from(11), Decimal::from(3)),
+        ]);
+
+        match events[4] {
+            BookResult::Opened(opened_event) => {
+                assert_eq!(opened_event.parent, Some(bid_id));
+                assert_eq!(opened_event.size, Decimal::from(2));
+            },
+            _ => panic!("Expected OpenedEvent for the rest of the bid"),
+        }
+    }
+
+    #[test]
+    fn min_qty_rests_untouched_when_not_enough_is_executable() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+
+        // only 1 is available but the bid wants at least 2 per execution
+        let events = without_trades(orderbook.process_request(BookRequest::Open(min_qty(bid!(trader_b, [(10, 5)])[0], 2))));
+        assert_eq!(events.len(), 1);
+        let bid_id = opened_id(&events);
+
+        // once resting the minimum doesn't apply, a small ask trades against the bid
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0])));
+
+        // 1) OPEN - ASK
+        // 2) FILLED - BID 1 of 5
+        // 3) OPEN - rest of the BID
+        // 4) FILLED - ASK
+        assert_eq!(events.len(), 4);
+
+        match events[1].clone() {
+            BookResult::Filled(filled_event) => {
+                assert_eq!(filled_event.id, bid_id);
+                assert_eq!(filled_event.size, Decimal::from(1));
+            },
+            _ => panic!("Expected FilledEvent for the bid"),
+        }
+    }
+
+    #[test]
+    fn min_qty_clamped_to_order_size() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 2)])[0]));
+
+        // a minimum above the size of the order just means the whole order
+        let events = orderbook.process_request(BookRequest::Open(min_qty(bid!(trader_b, [(10, 2)])[0], 5)));
+        let bid_id = opened_id(&events);
+
+        assert!(fills(&events).contains(&(bid_id, Decimal::from(10), Decimal::from(2))));
+    }
+
+    #[test]
+    fn min_qty_market_order_canceled_when_not_enough_is_executable() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1), (11, 1)])[0]));
+
+        let market = min_qty(with_type(bid!(trader_b, [(0, 3)])[0], OrderType::Market), 2);
+        let events = orderbook.process_request(BookRequest::Open(market));
+
+        // 1) OPEN - MARKET
+        // 2) CANCELED - MARKET, only 1 was available
+        assert_eq!(events.len(), 2);
+        assert!(fills(&events).is_empty());
+        assert!(matches!(events[1], BookResult::Canceled(_)));
+    }
+
+    #[test]
+    fn min_qty_counts_iceberg_reserve() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let ask_id = opened_id(&orderbook.process_request(BookRequest::Open(iceberg(ask!(trader_a, [(10, 3)])[0], 1))));
+
+        let events = orderbook.process_request(BookRequest::Open(min_qty(bid!(trader_b, [(10, 3)])[0], 3)));
+        let bid_id = opened_id(&events);
+
+        let fills = fills(&events);
+        assert_eq!(fills.len(), 4);
+        assert_eq!(fills[0], (ask_id, Decimal::from(10), Decimal::from(1)));
+        assert_eq!(fills[3], (bid_id, Decimal::from(10), Decimal::from(3)));
+    }
+
+    #[test]
+    fn all_or_none_aggressor_rests_until_fully_executable() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+
+        // 2 available, the bid won't take a partial fill
+        let events = orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_b, [(10, 3)])[0])));
+        assert_eq!(events.len(), 1);
+        let bid_id = opened_id(&events);
+
+        // resting, it is still too big for a single ask of 1
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+        assert_eq!(events.len(), 1);
+        assert!(fills(&events).is_empty());
+
+        // an ask big enough takes the whole bid
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 3)])[0]));
+        let ask_id = opened_id(&events);
+
+        assert_eq!(fills(&events), vec![
+            (bid_id, Decimal::from(10), Decimal::from(3)),
+            (ask_id, Decimal::from(10), Decimal::from(3)),
+        ]);
+    }
+
+    #[test]
+    fn all_or_none_resting_skipped_without_losing_priority() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let aon_id = opened_id(&orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_a, [(11, 5)])[0]))));
+        let bids: Vec<Uuid> = bid!(trader_a, [(10, 1), (9, 1)]).into_iter()
+            .map(|b| opened_id(&orderbook.process_request(BookRequest::Open(b))))
+            .collect();
+
+        // the best bid is all or none and too big, the ask trades with the bids behind it
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(9, 2)])[0]));
+        let ask_id = opened_id(&events);
+
+        assert_eq!(fills(&events), vec![
+            (bids[0], Decimal::from(10), Decimal::from(1)),
+            (bids[1], Decimal::from(9), Decimal::from(1)),
+            (ask_id, Decimal::from(9), Decimal::from(2)),
+        ]);
+
+        // and it is still first in line for an ask it fits
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(11, 5)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(11, 5)])[0]));
+
+        assert_eq!(fills(&events)[0], (aon_id, Decimal::from(11), Decimal::from(5)));
+    }
+
+    #[test]
+    fn all_or_none_resting_skipped_once_partially_filled_aggressor_is_too_small() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bid_id = opened_id(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(11, 1)])[0])));
+        orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_a, [(10, 2)])[0])));
+
+        // the ask fits the all or none bid when it arrives, but not after the first fill
+        let events = without_trades(orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 2)])[0])));
+        let ask_id = opened_id(&events);
+
+        // 1) OPEN - ASK
+        // 2) FILLED - BID 11
+        // 3) FILLED - ASK 1 of 2
+        // 4) OPEN - rest of the ASK
+        assert_eq!(events.len(), 4);
+        assert_eq!(fills(&events), vec![
+            (bid_id, Decimal::from(11), Decimal::from(1)),
+            (ask_id, Decimal::from(10), Decimal::from(1)),
+        ]);
+
+        match events[3] {
+            BookResult::Opened(opened_event) => assert_eq!(opened_event.size, Decimal::from(1)),
+            _ => panic!("Expected OpenedEvent for the rest of the ask"),
+        }
+    }
+
+    #[test]
+    fn all_or_none_aggressor_ignores_resting_all_or_none_too_big_for_it() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(all_or_none(ask!(trader_a, [(10, 3)])[0])));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(11, 1)])[0]));
+
+        // 4 on offer, but the ask at 10 only goes all at once and the bid only wants 2
+        let events = orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_b, [(11, 2)])[0])));
+        assert_eq!(events.len(), 1);
+
+        // an all or none aggressor that fits fills the resting all or none order completely
+        let events = orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_b, [(10, 3)])[0])));
+        assert_eq!(fills(&events).len(), 2);
+    }
+
+    #[test]
+    fn all_or_none_iceberg_fills_in_one_execution() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let ask_id = opened_id(&orderbook.process_request(BookRequest::Open(all_or_none(iceberg(ask!(trader_a, [(10, 3)])[0], 1)))));
+
+        // the shown slice is 1, that is not enough for the whole order
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 2)])[0]));
+        assert!(fills(&events).is_empty());
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 3)])[0]));
+        let bid_id = opened_id(&events);
+
+        // no replenished slices, the reserve executes with the peak
+        assert_eq!(fills(&events), vec![
+            (ask_id, Decimal::from(10), Decimal::from(3)),
+            (bid_id, Decimal::from(10), Decimal::from(3)),
+        ]);
+    }
+
+    #[test]
+    fn all_or_none_sits_out_auctions() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        set_phase(&mut orderbook, TradingPhase::Closed);
+        set_phase(&mut orderbook, TradingPhase::PreOpen);
+
+        orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_a, [(10, 5)])[0])));
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]));
+
+        match events[1] {
+            BookResult::Indicative(indicative_event) => assert_eq!(indicative_event.price, None),
+            _ => panic!("Expected IndicativeEvent"),
+        }
+
+        let events = set_phase(&mut orderbook, TradingPhase::Continuous);
+        assert!(fills(&events).is_empty());
+    }
+
+    fn pro_rata(top_order: bool, min_allocation: i64) -> OrderBook {
+        OrderBook::with_allocation(Box::new(ProRata {
+            top_order,
+            min_allocation: Decimal::from(min_allocation),
+        }))
+    }
+
+    // open every bid at the same price and return their ids in time priority
+    fn bid_level(orderbook: &mut OrderBook, owner: Uuid, sizes: &[i64]) -> Vec<Uuid> {
+        sizes.iter()
+            .map(|size| opened_id(&orderbook.process_request(BookRequest::Open(bid!(owner, [(10, *size)])[0]))))
+            .collect()
+    }
+
+    fn resting_fills(events: &[BookResult], owner: Uuid) -> Vec<(Uuid, Decimal)> {
+        events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) if filled_event.owner == owner => Some((filled_event.id, filled_event.size)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn pro_rata_shares_level_by_size() {
+        let mut orderbook = pro_rata(false, 0);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bids = bid_level(&mut orderbook, trader_a, &[10, 30]);
+
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 8)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![(bids[0], Decimal::from(2)), (bids[1], Decimal::from(6))]);
+    }
+
+    #[test]
+    fn pro_rata_rounding_left_over_goes_by_time() {
+        let mut orderbook = pro_rata(false, 0);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bids = bid_level(&mut orderbook, trader_a, &[1, 1, 1]);
+
+        // every share rounds down to nothing, so the first two in the queue get a unit each
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 2)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![(bids[0], Decimal::from(1)), (bids[1], Decimal::from(1))]);
+    }
+
+    #[test]
+    fn pro_rata_top_order_priority() {
+        let mut orderbook = pro_rata(true, 0);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bids = bid_level(&mut orderbook, trader_a, &[5, 5, 10]);
+
+        // the first bid is filled first, the other 5 are shared 5:10 as 1 and 3, the last unit goes by time
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 10)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![
+            (bids[0], Decimal::from(5)),
+            (bids[1], Decimal::from(2)),
+            (bids[2], Decimal::from(3)),
+        ]);
+    }
+
+    #[test]
+    fn pro_rata_minimum_allocation() {
+        let mut orderbook = pro_rata(false, 2);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bids = bid_level(&mut orderbook, trader_a, &[10, 3]);
+
+        // the second bid's share of 1 is below the minimum, that unit goes to the first bid by time priority
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 6)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![(bids[0], Decimal::from(6))]);
+    }
+
+    #[test]
+    fn pro_rata_all_or_none_not_shared() {
+        let mut orderbook = pro_rata(false, 0);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(all_or_none(bid!(trader_a, [(10, 4)])[0])));
+        let bids = bid_level(&mut orderbook, trader_a, &[4]);
+
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 4)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![(bids[0], Decimal::from(4))]);
+    }
+
+    #[test]
+    fn pro_rata_walks_levels() {
+        let mut orderbook = pro_rata(false, 0);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bids = bid_level(&mut orderbook, trader_a, &[1, 3]);
+        let lower = opened_id(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(9, 2)])[0])));
+
+        // the best level is used up completely before anything trades at 9
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(9, 5)])[0]));
+
+        assert_eq!(resting_fills(&events, trader_a), vec![
+            (bids[0], Decimal::from(1)),
+            (bids[1], Decimal::from(3)),
+            (lower, Decimal::from(1)),
+        ]);
+    }
+
+    #[test]
+    fn priority_follows_arrival_order() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        // far more orders than can arrive in distinct seconds, or be told apart by their uuids
+        let bids = bid_level(&mut orderbook, trader_a, &[1; 50]);
+
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 50)])[0]));
+
+        let filled: Vec<Uuid> = resting_fills(&events, trader_a).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(filled, bids);
+    }
+
+    #[test]
+    fn partial_fill_keeps_priority_replenish_does_not() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let first = opened_id(&orderbook.process_request(BookRequest::Open(iceberg(bid!(trader_a, [(10, 2)])[0], 1))));
+        let second = bid_level(&mut orderbook, trader_a, &[2])[0];
+
+        // the iceberg's slice is used up and replenished behind the second bid, which is partially filled
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 2)])[0]));
+        assert_eq!(resting_fills(&events, trader_a), vec![(first, Decimal::from(1)), (second, Decimal::from(1))]);
+
+        let second_rest = events.iter().find_map(|event| match event {
+            BookResult::Opened(opened_event) if opened_event.parent == Some(second) => Some(opened_event.id),
+            _ => None,
+        }).expect("Expected OpenedEvent for the rest of the second bid");
+
+        // the rest of the second bid is still ahead of the replenished slice
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 1)])[0]));
+        assert_eq!(resting_fills(&events, trader_a), vec![(second_rest, Decimal::from(1))]);
+    }
+
+    fn journal_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}.journal", trader()))
+    }
+
+    fn engine(path: &std::path::Path) -> Engine {
+        Engine::new("TEST".to_string(), OrderBook::new(), Journal::open(path).unwrap(), 60, 100).unwrap()
+    }
+
+    fn drop_copy(publications: Vec<Publication>) -> Vec<Events> {
+        publications.into_iter()
+            .filter(|publication| publication.route == Route::DropCopy)
+            .map(|publication| publication.events)
+            .collect()
+    }
+
+    fn sequences(envelopes: &[Events]) -> Vec<(u64, Vec<u64>)> {
+        envelopes.iter()
+            .map(|envelope| (envelope.sequence, envelope.events.iter().map(|event| event.sequence).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn events_are_sequenced_without_gaps() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let trader_a = trader();
+
+        let mut envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]))).unwrap());
+        envelopes.append(&mut drop_copy(engine.process_request(Request::Book(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]))).unwrap()));
+
+        // 1) OPEN - ASK
+        // then OPEN - BID, TRADED, FILLED - ASK, FILLED - BID
+        assert_eq!(sequences(&envelopes), vec![(1, vec![1]), (2, vec![2, 3, 4, 5])]);
+
+        // nothing to expire, nothing is published and no numbers are used up
+        assert!(engine.expire_orders(timestamp()).unwrap().is_empty());
+
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Cancel(CancelEvent{
+            id: trader(),
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }))).unwrap());
+
+        assert_eq!(sequences(&envelopes), vec![(3, vec![6])]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sequenced_events_keep_their_variant_name() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(10, 1)])[0]))).unwrap());
+        let json: serde_json::Value = serde_json::to_value(&envelopes[0]).unwrap();
+
+        assert_eq!(json["sequence"], 1);
+        assert_eq!(json["events"][0]["sequence"], 1);
+        assert!(json["events"][0]["Opened"].is_object());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn retransmit_from_journal() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let trader_a = trader();
+
+        for request in [BookRequest::Open(ask!(trader_a, [(10, 1)])[0]), BookRequest::Open(bid!(trader_a, [(10, 1)])[0]), BookRequest::Open(ask!(trader_a, [(11, 1)])[0])] {
+            drop_copy(engine.process_request(Request::Book(request)).unwrap());
+        }
+
+        // the range overlaps the second envelope only, which goes out whole
+        let request: Request = serde_json::from_str(r#"{"Retransmit": {"from": 3, "to": 4}}"#).unwrap();
+        let envelopes = drop_copy(engine.process_request(request).unwrap());
+
+        assert_eq!(sequences(&envelopes), vec![(2, vec![2, 3, 4, 5])]);
+
+        // retransmitting doesn't publish anything new
+        let envelopes = drop_copy(engine.process_request(Request::Engine(EngineRequest::Retransmit(RetransmitRequest{ from: 1, to: 100, owner: None }))).unwrap());
+        assert_eq!(envelopes.len(), 3);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sequence_numbers_survive_restart() {
+        let path = journal_path();
+
+        {
+            let mut engine = engine(&path);
+            drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(10, 1)])[0]))).unwrap());
+            drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(11, 1)])[0]))).unwrap());
+        }
+
+        let mut engine = engine(&path);
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(12, 1)])[0]))).unwrap());
+        assert_eq!(sequences(&envelopes), vec![(3, vec![3])]);
+
+        // the journal read back still answers retransmissions from before the restart
+        let envelopes = drop_copy(engine.process_request(Request::Engine(EngineRequest::Retransmit(RetransmitRequest{ from: 1, to: 1, owner: None }))).unwrap());
+        assert_eq!(sequences(&envelopes), vec![(1, vec![1])]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn torn_journal_tail_cut_off() {
+        use std::io::Write;
+
+        let path = journal_path();
+
+        {
+            let mut engine = engine(&path);
+            drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(10, 1)])[0]))).unwrap());
+            drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(11, 1)])[0]))).unwrap());
+        }
+
+        // the engine stopped halfway through writing the third envelope
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(br#"{"asset":"TEST","sequence":3,"ev"#).unwrap();
+
+        let mut engine = engine(&path);
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(12, 1)])[0]))).unwrap());
+        assert_eq!(sequences(&envelopes), vec![(3, vec![3])]);
+
+        // the new envelope went after the last whole one and reads back like any other
+        let envelopes = drop_copy(engine.process_request(Request::Engine(EngineRequest::Retransmit(RetransmitRequest{ from: 2, to: 3, owner: None }))).unwrap());
+        assert_eq!(sequences(&envelopes), vec![(2, vec![2]), (3, vec![3])]);
+        assert_eq!(Journal::open(&path).unwrap().replay().unwrap().count(), 3);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn with_client_id(mut open_event: OpenEvent, client_id: Uuid) -> OpenEvent {
+        open_event.client_id = Some(client_id);
+        open_event
+    }
+
+    #[test]
+    fn client_id_echoed_on_every_event() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+        let bid_client_id = trader();
+        let ask_client_id = trader();
+
+        orderbook.process_request(BookRequest::Open(with_client_id(bid!(trader_a, [(10, 2)])[0], bid_client_id)));
+        let events = without_trades(orderbook.process_request(BookRequest::Open(with_client_id(ask!(trader_b, [(10, 1)])[0], ask_client_id))));
+
+        // 1) OPEN - ASK
+        // 2) FILLED - BID
+        // 3) OPEN - rest of the BID
+        // 4) FILLED - ASK
+        assert_eq!(events.len(), 4);
+
+        let client_ids: Vec<Option<Uuid>> = events.iter().map(|event| match event {
+            BookResult::Opened(opened_event) => opened_event.client_id,
+            BookResult::Filled(filled_event) => filled_event.client_id,
+            _ => panic!("Expected only opened and filled events"),
+        }).collect();
+
+        assert_eq!(client_ids, vec![Some(ask_client_id), Some(bid_client_id), Some(bid_client_id), Some(ask_client_id)]);
+
+        let rest_id = opened_id(&events[2..]);
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id: rest_id,
+            owner: trader_a,
+            timestamp: 0,
+            client_id: Some(bid_client_id),
+            request_id: None,
+        }));
+
+        match events[0] {
+            BookResult::Canceled(canceled_event) => assert_eq!(canceled_event.client_id, Some(bid_client_id)),
+            _ => panic!("Expected CanceledEvent"),
+        }
+    }
+
+    #[test]
+    fn duplicate_open_bounced() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let trader_a = trader();
+        let client_id = trader();
+        let open = with_client_id(bid!(trader_a, [(10, 1)])[0], client_id);
+
+        drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+
+        match &envelopes[0].events[0].event {
+            BookResult::Bounce(bounce_event) => {
+                assert_eq!(bounce_event.client_id, Some(client_id));
+                assert!(matches!(bounce_event.reason, BounceReason::DuplicateRequest));
+            },
+            _ => panic!("Expected BounceEvent for the repeated open"),
+        }
+
+        // only one bid made it onto the book
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(ask!(trader(), [(10, 2)])[0]))).unwrap());
+        let events: Vec<BookResult> = envelopes[0].events.iter().map(|event| event.event.clone()).collect();
+        assert_eq!(resting_fills(&events, trader_a).len(), 1);
+
+        // another owner can use the same client order id
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(with_client_id(bid!(trader(), [(10, 1)])[0], client_id)))).unwrap());
+        assert!(matches!(envelopes[0].events[0].event, BookResult::Opened(_)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn request_id_deduplicates_cancels() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let trader_a = trader();
+        let cancel = CancelEvent{
+            id: trader(),
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: Some(trader()),
+        };
+
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Cancel(cancel))).unwrap());
+        match &envelopes[0].events[0].event {
+            BookResult::Bounce(bounce_event) => assert!(matches!(bounce_event.reason, BounceReason::OrderNotFound)),
+            _ => panic!("Expected BounceEvent for the unknown order"),
+        }
+
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Cancel(cancel))).unwrap());
+        match &envelopes[0].events[0].event {
+            BookResult::Bounce(bounce_event) => assert!(matches!(bounce_event.reason, BounceReason::DuplicateRequest)),
+            _ => panic!("Expected BounceEvent for the repeated cancel"),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn requests_forgotten_after_window() {
+        let path = journal_path();
+        let mut engine = Engine::new("TEST".to_string(), OrderBook::new(), Journal::open(&path).unwrap(), 0, 100).unwrap();
+
+        let mut open = bid!(trader(), [(10, 1)])[0];
+        open.request_id = Some(trader());
+
+        for _ in 0..2 {
+            let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+            assert!(matches!(envelopes[0].events[0].event, BookResult::Opened(_)));
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn requests_remembered_across_restart() {
+        let path = journal_path();
+
+        let mut open = bid!(trader(), [(10, 1)])[0];
+        open.request_id = Some(trader());
+
+        {
+            let mut engine = engine(&path);
+            let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+            assert!(matches!(envelopes[0].events[0].event, BookResult::Opened(_)));
+        }
+
+        // redelivered to the engine that took over from the one that handled it
+        let mut engine = engine(&path);
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+        assert!(matches!(bounce_reason(&envelopes[0].events.iter().map(|event| event.event.clone()).collect::<Vec<_>>()), Some(BounceReason::DuplicateRequest)));
+
+        // a restart past the window has forgotten it
+        let mut engine = Engine::new("TEST".to_string(), OrderBook::new(), Journal::open(&path).unwrap(), 0, 100).unwrap();
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(open))).unwrap());
+        assert!(matches!(envelopes[0].events[0].event, BookResult::Opened(_)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn routed(publications: &[Publication], route: Route) -> Vec<(u64, Vec<u64>)> {
+        let envelopes: Vec<Events> = publications.iter()
+            .filter(|publication| publication.route == route)
+            .map(|publication| publication.events.clone())
+            .collect();
+
+        sequences(&envelopes)
+    }
+
+    #[test]
+    fn execution_reports_routed_to_owners() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        engine.process_request(Request::Book(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]))).unwrap();
+        let publications = engine.process_request(Request::Book(BookRequest::Open(with_client_id(ask!(trader_b, [(10, 1)])[0], trader())))).unwrap();
+
+        // 2) OPEN - ASK, 3) TRADED, 4) FILLED - BID, 5) FILLED - ASK
+        assert_eq!(routed(&publications, Route::DropCopy), vec![(2, vec![2, 3, 4, 5])]);
+        // each owner numbers the envelopes concerning them on their own, without gaps
+        assert_eq!(routed(&publications, Route::Owner(trader_b)), vec![(1, vec![2, 5])]);
+        assert_eq!(routed(&publications, Route::Owner(trader_a)), vec![(2, vec![4])]);
+        assert_eq!(routed(&publications, Route::Public), vec![(2, vec![2, 3, 4, 5])]);
+
+        // a retransmission for an owner goes out on their route as it was first published
+        let retransmit = RetransmitRequest{ from: 1, to: 5, owner: Some(trader_a) };
+        let retransmitted = engine.process_request(Request::Engine(EngineRequest::Retransmit(retransmit))).unwrap();
+        assert_eq!(routed(&retransmitted, Route::Owner(trader_a)), vec![(1, vec![1]), (2, vec![4])]);
+        assert_eq!(retransmitted.len(), 2);
+
+        // and carries on after a restart
+        drop(engine);
+        let mut engine = self::engine(&path);
+        let later = engine.process_request(Request::Book(BookRequest::Open(bid!(trader_b, [(9, 1)])[0]))).unwrap();
+        assert_eq!(routed(&later, Route::Owner(trader_b)), vec![(2, vec![6])]);
+
+        // nobody can be identified from the public stream
+        let public = publications.iter().find(|publication| publication.route == Route::Public).unwrap();
+        for event in public.events.events.iter() {
+            match &event.event {
+                BookResult::Opened(opened_event) => assert_eq!((opened_event.owner, opened_event.client_id), (Uuid::nil(), None)),
+                BookResult::Filled(filled_event) => assert_eq!((filled_event.owner, filled_event.client_id), (Uuid::nil(), None)),
+                BookResult::Traded(trade_event) => assert_eq!((trade_event.buyer, trade_event.seller), (Uuid::nil(), Uuid::nil())),
+                _ => panic!("Expected only opened, filled and traded events"),
+            }
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn private_events_kept_off_public_stream() {
+        let path = journal_path();
+        let mut orderbook = OrderBook::new();
+        orderbook.set_fees(FeeSchedule {
+            tiers: vec![fee_tier(0, Decimal::new(-1, 3), Decimal::new(2, 3))],
+            minimum_fee: Decimal::zero(),
+            precision: 2,
+        });
+        let mut engine = Engine::new("TEST".to_string(), orderbook, Journal::open(&path).unwrap(), 60, 100).unwrap();
+
+        let trader_a = trader();
+
+        // a bounce only goes to its owner, the public stream still sees the envelope number
+        let publications = engine.process_request(Request::Book(BookRequest::Cancel(CancelEvent{
+            id: trader(),
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }))).unwrap();
+
+        assert_eq!(routed(&publications, Route::Public), vec![(1, vec![])]);
+        assert_eq!(routed(&publications, Route::Owner(trader_a)), vec![(1, vec![1])]);
+
+        // resting stops aren't on the book
+        let stop = with_type(bid!(trader_a, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(10) });
+        let publications = engine.process_request(Request::Book(BookRequest::Open(stop))).unwrap();
+
+        assert_eq!(routed(&publications, Route::Public), vec![(2, vec![])]);
+        assert_eq!(routed(&publications, Route::Owner(trader_a)), vec![(2, vec![2])]);
+
+        // retransmissions only go to the drop copy
+        let publications = engine.process_request(Request::Engine(EngineRequest::Retransmit(RetransmitRequest{ from: 1, to: 2, owner: None }))).unwrap();
+        assert!(publications.iter().all(|publication| publication.route == Route::DropCopy));
+
+        // fees are only told to whoever paid or earned them, not on the public stream or the tape
+        let trader_b = trader();
+        let trader_c = trader();
+        engine.process_request(Request::Book(BookRequest::Open(ask!(trader_b, [(20, 5)])[0]))).unwrap();
+        let publications = engine.process_request(Request::Book(BookRequest::Open(bid!(trader_c, [(20, 5)])[0]))).unwrap();
+
+        let fees = |route: Route| -> Vec<Decimal> {
+            publications.iter()
+                .filter(|publication| publication.route == route)
+                .flat_map(|publication| publication.events.events.iter())
+                .flat_map(|event| match &event.event {
+                    BookResult::Traded(trade_event) => vec![trade_event.buyer_fee, trade_event.seller_fee],
+                    BookResult::Filled(filled_event) => vec![filled_event.fee],
+                    _ => vec![],
+                })
+                .collect()
+        };
+
+        assert!(fees(Route::Public).iter().all(Decimal::is_zero));
+        assert!(!fees(Route::Public).is_empty());
+        assert!(fees(Route::Owner(trader_c)).iter().any(|fee| !fee.is_zero()));
+
+        let request: Request = serde_json::from_str(&format!(r#"{{"Trades": {{"owner": "{}"}}}}"#, trader_c)).unwrap();
+        let reply = engine.process_request(request).unwrap().into_iter().find(|publication| publication.route == Route::Owner(trader_c)).unwrap();
+        match &reply.events.events[0].event {
+            BookResult::Trades(trades_event) => assert!(!trades_event.trades.is_empty() && trades_event.trades.iter().all(|trade| trade.buyer_fee.is_zero() && trade.seller_fee.is_zero())),
+            _ => panic!("Expected a TradesEvent"),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn limits(orderbook: &mut OrderBook, limited: Option<Uuid>, limits: RiskLimits) -> Vec<BookResult> {
+        orderbook.process_request(BookRequest::Admin(AdminRequest::SetLimits(LimitsEvent{
+            owner: trader(),
+            trader: limited,
+            limits,
+            timestamp: 0,
+        })))
+    }
+
+    fn bounce_reason(events: &[BookResult]) -> Option<BounceReason> {
+        events.iter().find_map(|event| match event {
+            BookResult::Bounce(bounce_event) => Some(bounce_event.reason),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn order_size_and_notional_limits() {
+        let mut orderbook = OrderBook::new();
+        limits(&mut orderbook, None, RiskLimits {
+            max_order_size: Some(Decimal::from(5)),
+            max_notional: Some(Decimal::from(100)),
+            ..RiskLimits::default()
+        });
+
+        let trader_a = trader();
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(1, 6)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::OrderSizeLimit { limit }) if limit == Decimal::from(5)));
+
+        // exactly at the limit is fine
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(20, 5)])[0]));
+        assert!(bounce_reason(&events).is_none());
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(21, 5)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::NotionalLimit { limit }) if limit == Decimal::from(100)));
+    }
+
+    #[test]
+    fn market_order_notional_valued_at_what_it_would_trade_at() {
+        let mut orderbook = OrderBook::new();
+        limits(&mut orderbook, None, RiskLimits { max_notional: Some(Decimal::from(50)), ..RiskLimits::default() });
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        // the book is empty and nothing has traded yet, so there is nothing to value a market order at
+        let market = with_type(bid!(trader_b, [(0, 10)])[0], OrderType::Market);
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(market))).is_none());
+
+        for a in ask!(trader_a, [(10, 1), (10, 5)]) {
+            orderbook.process_request(BookRequest::Open(a));
+        }
+        orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 1)])[0]));
+
+        let events = orderbook.process_request(BookRequest::Open(market));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::NotionalLimit { .. })));
+    }
+
+    #[test]
+    fn open_order_limit_frees_up_when_orders_leave_the_book() {
+        let mut orderbook = OrderBook::new();
+        limits(&mut orderbook, None, RiskLimits { max_open_orders: Some(2), ..RiskLimits::default() });
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let first = opened_id(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0])));
+        orderbook.process_request(BookRequest::Open(bid!(trader_a, [(9, 2)])[0]));
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(8, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::OpenOrderLimit { limit: 2 })));
+
+        orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id: first,
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(8, 1)])[0]));
+        assert!(bounce_reason(&events).is_none());
+
+        // the rest of a partial fill is still one open order
+        orderbook.process_request(BookRequest::Open(ask!(trader_b, [(9, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(7, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::OpenOrderLimit { limit: 2 })));
+
+        // a complete fill takes it off the book
+        orderbook.process_request(BookRequest::Open(ask!(trader_b, [(9, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(7, 1)])[0]));
+        assert!(bounce_reason(&events).is_none());
+    }
+
+    #[test]
+    fn position_limit_only_blocks_growing_the_position() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        // only trader_a is limited
+        limits(&mut orderbook, Some(trader_a), RiskLimits { max_position: Some(Decimal::from(2)), ..RiskLimits::default() });
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_b, [(10, 5)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 2)])[0]));
+        assert_eq!(fills(&events).len(), 2);
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { limit }) if limit == Decimal::from(2)));
+
+        // selling 3 leaves a smaller position, short 1
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_a, [(11, 3)])[0]));
+        assert!(bounce_reason(&events).is_none());
+
+        // trader_b is short 2 with no limit of their own
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_b, [(12, 100)])[0]));
+        assert!(bounce_reason(&events).is_none());
+    }
+
+    #[test]
+    fn risk_counts_resting_orders_and_ignores_client_prices() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+        let trader_c = trader();
+
+        limits(&mut orderbook, Some(trader_a), RiskLimits { max_position: Some(Decimal::from(5)), ..RiskLimits::default() });
+        limits(&mut orderbook, Some(trader_b), RiskLimits { max_notional: Some(Decimal::from(100)), ..RiskLimits::default() });
+
+        // the first bid could fill up to the limit, so a second one can't be let on too
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 5)])[0]))).is_none());
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(9, 5)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { .. })));
+
+        // an iceberg counts with its reserve
+        let events = orderbook.process_request(BookRequest::Open(iceberg(ask!(trader_a, [(60, 6)])[0], 1)));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { .. })));
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(ask!(trader_a, [(60, 5)])[0]))).is_none());
+
+        // orders without a limit are valued at what they could trade at, not the price sent with them
+        orderbook.process_request(BookRequest::Open(ask!(trader_c, [(50, 5)])[0]));
+        let cheap = Decimal::new(1, 6);
+
+        let mut market = with_type(bid!(trader_b, [(0, 5)])[0], OrderType::Market);
+        market.price = cheap;
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(market))), Some(BounceReason::NotionalLimit { .. })));
+
+        let mut capped = with_type(bid!(trader_b, [(0, 5)])[0], OrderType::Peg { reference: PegReference::Market, offset: Decimal::zero(), cap: Some(Decimal::from(50)) });
+        capped.price = cheap;
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(capped))), Some(BounceReason::NotionalLimit { .. })));
+    }
+
+    #[test]
+    fn limits_changed_at_runtime() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 100)])[0]))).is_none());
+
+        let new_limits = RiskLimits { max_order_size: Some(Decimal::from(10)), ..RiskLimits::default() };
+        let events = limits(&mut orderbook, Some(trader_a), new_limits);
+
+        match &events[..] {
+            [BookResult::LimitsChanged(limits_event)] => {
+                assert_eq!(limits_event.trader, Some(trader_a));
+                assert_eq!(limits_event.limits, new_limits);
+                assert_eq!(events[0].owner(), Some(trader_a));
+                assert!(events[0].anonymized().is_none());
+            },
+            _ => panic!("Expected a single LimitsChangedEvent"),
+        }
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 100)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::OrderSizeLimit { .. })));
+
+        // lifting the limit again
+        limits(&mut orderbook, Some(trader_a), RiskLimits::default());
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 100)])[0]))).is_none());
+    }
+
+    #[test]
+    fn positions_and_limits_survive_restart() {
+        let path = journal_path();
+        let snapshot = snapshot_path();
+        let mut engine = recovered(&path, &snapshot);
+
+        let seller = trader();
+        let buyer = trader();
+
+        engine_events(&mut engine, BookRequest::Admin(AdminRequest::SetLimits(LimitsEvent{
+            owner: trader(),
+            trader: None,
+            limits: RiskLimits { max_position: Some(Decimal::from(5)), ..RiskLimits::default() },
+            timestamp: 0,
+        })));
+        engine_events(&mut engine, BookRequest::Open(ask!(seller, [(10, 5)])[0]));
+        engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(10, 5)])[0]));
+
+        let events = engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(10, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { .. })));
+
+        engine.checkpoint(&snapshot).unwrap();
+        drop(engine);
+
+        // still long and short 5 under the limit set at runtime
+        let mut engine = recovered(&path, &snapshot);
+        let events = engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(10, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { .. })));
+
+        let events = engine_events(&mut engine, BookRequest::Open(ask!(seller, [(11, 1)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::PositionLimit { .. })));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(snapshot).unwrap();
+    }
+
+    #[test]
+    fn message_rate_limit_per_second() {
+        let mut risk = RiskCheck::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        risk.set_limits(None, RiskLimits { max_messages_per_sec: Some(2), ..RiskLimits::default() });
+
+        assert!(risk.check_message(trader_a, 100).is_none());
+        assert!(risk.check_message(trader_a, 100).is_none());
+        assert!(matches!(risk.check_message(trader_a, 100), Some(BounceReason::MessageRateLimit { limit: 2 })));
+
+        // every owner has their own budget, and it starts over every second
+        assert!(risk.check_message(trader_b, 100).is_none());
+        assert!(risk.check_message(trader_a, 101).is_none());
+    }
+
+    fn trades(events: &[BookResult]) -> Vec<TradeEvent> {
+        events.iter().filter_map(|event| match event {
+            BookResult::Traded(trade_event) => Some(*trade_event),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn sweep_reports_each_execution_at_its_price() {
+        let mut orderbook = OrderBook::new();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let mut ask_ids = Vec::new();
+        for a in ask!(trader_a, [(10, 1), (11, 2)]) {
+            ask_ids.push(opened_id(&orderbook.process_request(BookRequest::Open(a))));
+        }
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(12, 3)])[0]));
+        let bid_id = opened_id(&events);
+
+        let trades = trades(&events);
+        assert!(trades.iter().all(|trade| (trade.buyer, trade.seller, trade.aggressor) == (trader_b, trader_a, Some(OrderDirection::Bid))));
+
+        let executions: Vec<(Decimal, Decimal, Uuid, Uuid)> = trades.iter()
+            .map(|trade| (trade.price, trade.size, trade.buy_order, trade.sell_order))
+            .collect();
+
+        assert_eq!(executions, vec![
+            (Decimal::from(10), Decimal::from(1), bid_id, ask_ids[0]),
+            (Decimal::from(11), Decimal::from(2), bid_id, ask_ids[1]),
+        ]);
+    }
+
+    #[test]
+    fn auction_pairs_both_sides_in_priority_order() {
+        let mut orderbook = OrderBook::new();
+        set_phase(&mut orderbook, TradingPhase::Closed);
+        set_phase(&mut orderbook, TradingPhase::PreOpen);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let bid_ids: Vec<Uuid> = bid!(trader_a, [(11, 2), (10, 2)]).into_iter()
+            .map(|b| opened_id(&orderbook.process_request(BookRequest::Open(b))))
+            .collect();
+        let ask_ids: Vec<Uuid> = ask!(trader_b, [(9, 3)]).into_iter()
+            .map(|a| opened_id(&orderbook.process_request(BookRequest::Open(a))))
+            .collect();
+
+        let events = set_phase(&mut orderbook, TradingPhase::Continuous);
+
+        let trades: Vec<(Decimal, Uuid, Uuid, Option<OrderDirection>)> = trades(&events).iter()
+            .map(|trade| (trade.size, trade.buy_order, trade.sell_order, trade.aggressor))
+            .collect();
+
+        assert_eq!(trades, vec![
+            (Decimal::from(2), bid_ids[0], ask_ids[0], None),
+            (Decimal::from(1), bid_ids[1], ask_ids[0], None),
+        ]);
+        assert!(events.iter().all(|event| !matches!(event, BookResult::Traded(trade_event) if trade_event.price != Decimal::from(10))));
+    }
+
+    fn transfer(orderbook: &mut OrderBook, account: Uuid, cash: i64, holdings: i64) -> Vec<BookResult> {
+        orderbook.process_request(BookRequest::Admin(AdminRequest::Transfer(TransferEvent{
+            owner: trader(),
+            trader: account,
+            cash: Decimal::from(cash),
+            holdings: Decimal::from(holdings),
+            timestamp: 0,
+        })))
+    }
+
+    // a transfer of nothing reports the balance
+    fn balance(orderbook: &mut OrderBook, account: Uuid) -> Balance {
+        match transfer(orderbook, account, 0, 0)[..] {
+            [BookResult::Transferred(transferred_event)] => transferred_event.balance,
+            _ => panic!("Expected a TransferredEvent"),
+        }
+    }
+
+    fn ledger_book() -> OrderBook {
+        let mut orderbook = OrderBook::new();
+        orderbook.enforce_balances();
+        orderbook
+    }
+
+    #[test]
+    fn bid_reserves_cash_until_canceled() {
+        let mut orderbook = ledger_book();
+
+        let trader_a = trader();
+        transfer(&mut orderbook, trader_a, 100, 0);
+
+        let id = opened_id(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 6)])[0])));
+        assert_eq!(balance(&mut orderbook, trader_a).available_cash(), Decimal::from(40));
+
+        // 50 needed, only 40 left
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 5)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::InsufficientBalance { required, available })
+            if required == Decimal::from(50) && available == Decimal::from(40)));
+
+        orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id,
+            owner: trader_a,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        assert_eq!(balance(&mut orderbook, trader_a), Balance { cash: Decimal::from(100), ..Balance::default() });
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(bid!(trader_a, [(10, 5)])[0]))).is_none());
+    }
+
+    #[test]
+    fn cannot_sell_what_is_not_held() {
+        let mut orderbook = ledger_book();
+
+        let trader_a = trader();
+        transfer(&mut orderbook, trader_a, 0, 2);
+
+        let events = orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 3)])[0]));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::InsufficientBalance { .. })));
+
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 2)])[0]))).is_none());
+        assert_eq!(balance(&mut orderbook, trader_a).available_holdings(), Decimal::zero());
+
+        // nor withdraw what is reserved
+        let events = transfer(&mut orderbook, trader_a, 0, -1);
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::InsufficientBalance { .. })));
+    }
+
+    #[test]
+    fn trades_settle_both_sides() {
+        let mut orderbook = ledger_book();
+
+        let trader_a = trader();
+        let trader_b = trader();
+        transfer(&mut orderbook, trader_a, 0, 5);
+        transfer(&mut orderbook, trader_b, 100, 0);
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(8, 2)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(9, 2)])[0]));
+
+        // bids 10 for 3, pays 8 * 2 + 9 * 1 and the price improvement comes back, the rest of the bid stays reserved
+        orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 4)])[0]));
+
+        assert_eq!(balance(&mut orderbook, trader_b), Balance {
+            cash: Decimal::from(66),
+            holdings: Decimal::from(4),
+            reserved_cash: Decimal::zero(),
+            reserved_holdings: Decimal::zero(),
+        });
+        assert_eq!(balance(&mut orderbook, trader_a), Balance {
+            cash: Decimal::from(34),
+            holdings: Decimal::from(1),
+            reserved_cash: Decimal::zero(),
+            reserved_holdings: Decimal::zero(),
+        });
+
+        // a partially filled bid keeps the rest of its reservation
+        orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 3)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 1)])[0]));
+
+        let balance_b = balance(&mut orderbook, trader_b);
+        assert_eq!((balance_b.cash, balance_b.holdings, balance_b.reserved_cash), (Decimal::from(56), Decimal::from(5), Decimal::from(20)));
+    }
+
+    #[test]
+    fn market_bid_reserved_against_the_book() {
+        let mut orderbook = ledger_book();
+
+        let trader_a = trader();
+        let trader_b = trader();
+        transfer(&mut orderbook, trader_a, 0, 5);
+        transfer(&mut orderbook, trader_b, 25, 0);
+
+        for a in ask!(trader_a, [(5, 2), (10, 3)]) {
+            orderbook.process_request(BookRequest::Open(a));
+        }
+
+        // 3 would reach the 10 level, 30 is more than there is
+        let market = with_type(bid!(trader_b, [(0, 3)])[0], OrderType::Market);
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(market))), Some(BounceReason::InsufficientBalance { .. })));
+
+        // 2 stays on the 5 level, whatever isn't spent is given back
+        let market = with_type(bid!(trader_b, [(0, 2)])[0], OrderType::Market);
+        orderbook.process_request(BookRequest::Open(market));
+        assert_eq!(balance(&mut orderbook, trader_b), Balance { cash: Decimal::from(15), holdings: Decimal::from(2), ..Balance::default() });
+
+        // a stop bid becomes a market order at whatever the book looks like then
+        let stop = with_type(bid!(trader_b, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(11) });
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(stop))), Some(BounceReason::NoPriceBound)));
+    }
+
+    #[test]
+    fn balances_survive_restart() {
+        let path = journal_path();
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        {
+            let mut engine = engine(&path);
+            for request in [
+                BookRequest::Admin(AdminRequest::Transfer(TransferEvent{ owner: trader(), trader: trader_b, cash: Decimal::from(50), holdings: Decimal::zero(), timestamp: 0 })),
+                BookRequest::Open(ask!(trader_a, [(10, 2)])[0]),
+                BookRequest::Open(bid!(trader_b, [(10, 2)])[0]),
+            ] {
+                engine.process_request(Request::Book(request)).unwrap();
+            }
+        }
+
+        let mut orderbook = ledger_book();
+        for envelope in Journal::open(&path).unwrap().replay().unwrap() {
+            orderbook.restore(envelope.unwrap().events.iter().map(|event| &event.event));
+        }
+
+        assert_eq!(balance(&mut orderbook, trader_b), Balance { cash: Decimal::from(30), holdings: Decimal::from(2), ..Balance::default() });
+        assert_eq!(balance(&mut orderbook, trader_a), Balance { cash: Decimal::from(20), holdings: Decimal::from(-2), ..Balance::default() });
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn fee_tier(min_volume: i64, maker_rate: Decimal, taker_rate: Decimal) -> FeeTier {
+        FeeTier { min_volume: Decimal::from(min_volume), maker_rate, taker_rate }
+    }
+
+    fn filled_fees(events: &[BookResult]) -> Vec<(Uuid, Decimal)> {
+        events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) => Some((filled_event.id, filled_event.fee)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn taker_pays_and_maker_earns_rebate() {
+        let mut orderbook = OrderBook::new();
+        orderbook.set_fees(FeeSchedule {
+            tiers: vec![fee_tier(0, Decimal::new(-1, 3), Decimal::new(2, 3))],
+            minimum_fee: Decimal::zero(),
+            precision: 2,
+        });
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        let ask_id = opened_id(&orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 5)])[0])));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 3)])[0]));
+        let bid_id = opened_id(&events);
+
+        // 30 traded, 0.2% taker fee and 0.1% maker rebate
+        let trade = trades(&events)[0];
+        assert_eq!((trade.buyer_fee, trade.seller_fee), (Decimal::new(6, 2), Decimal::new(-3, 2)));
+        assert_eq!(filled_fees(&events), vec![(ask_id, Decimal::new(-3, 2)), (bid_id, Decimal::new(6, 2))]);
+
+        // fees come out of cash, rebates go into it
+        assert_eq!(balance(&mut orderbook, trader_b).cash, Decimal::new(-3006, 2));
+        assert_eq!(balance(&mut orderbook, trader_a).cash, Decimal::new(3003, 2));
+    }
+
+    #[test]
+    fn fees_rounded_against_the_owner_with_a_minimum() {
+        let mut orderbook = OrderBook::new();
+        orderbook.set_fees(FeeSchedule {
+            tiers: vec![fee_tier(0, Decimal::new(-1, 3), Decimal::new(2, 3))],
+            minimum_fee: Decimal::new(5, 2),
+            precision: 2,
+        });
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        // 10.5 traded, the fee of 0.021 is below the minimum, the rebate of 0.0105 rounds down to 0.01
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(Decimal::new(105, 1), 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(11, 1)])[0]));
+
+        let trade = trades(&events)[0];
+        assert_eq!((trade.buyer_fee, trade.seller_fee), (Decimal::new(5, 2), Decimal::new(-1, 2)));
+
+        // 262.5 traded, the fee of 0.525 rounds up to 0.53
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(Decimal::new(105, 1), 25)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(11, 25)])[0]));
+
+        assert_eq!(trades(&events)[0].buyer_fee, Decimal::new(53, 2));
+    }
+
+    #[test]
+    fn aggressor_pays_for_every_level_it_takes() {
+        let mut orderbook = OrderBook::new();
+        orderbook.set_fees(FeeSchedule {
+            tiers: vec![fee_tier(0, Decimal::zero(), Decimal::new(1, 2))],
+            minimum_fee: Decimal::new(1, 1),
+            precision: 2,
+        });
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        for a in ask!(trader_a, [(10, 1), (20, 1)]) {
+            orderbook.process_request(BookRequest::Open(a));
+        }
+
+        // 0.10 and 0.20 for each trade, the minimum applies to each
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(20, 2)])[0]));
+        let bid_id = opened_id(&events);
+
+        assert!(filled_fees(&events).contains(&(bid_id, Decimal::new(3, 1))));
+    }
+
+    #[test]
+    fn tier_follows_rolling_volume() {
+        let mut fees = Fees::new();
+        fees.set_schedule(FeeSchedule {
+            tiers: vec![fee_tier(0, Decimal::zero(), Decimal::new(2, 2)), fee_tier(1000, Decimal::zero(), Decimal::new(1, 2))],
+            minimum_fee: Decimal::zero(),
+            precision: 2,
+        });
+
+        let trader_a = trader();
+        let day = 24 * 60 * 60;
+
+        assert_eq!(fees.charge(trader_a, Decimal::from(1000), Liquidity::Taker, 0), Decimal::from(20));
+        // the trade that reaches the tier still paid the old rate, the next one doesn't
+        assert_eq!(fees.charge(trader_a, Decimal::from(100), Liquidity::Taker, day), Decimal::from(1));
+        // the first trade has left the 30 day window, only 100 is left
+        assert_eq!(fees.charge(trader_a, Decimal::from(100), Liquidity::Taker, 30 * day), Decimal::from(2));
+        // makers pay the maker rate of their tier
+        assert_eq!(fees.charge(trader_a, Decimal::from(100), Liquidity::Maker, 30 * day), Decimal::zero());
+    }
+
+    #[test]
+    fn candle_aggregates_trades_in_its_interval() {
+        let mut candles = Candles::new(&[Interval::Minute]);
+
+        assert!(candles.trade(Decimal::from(10), Decimal::from(1), 60).is_empty());
+        assert!(candles.trade(Decimal::from(12), Decimal::from(2), 60).is_empty());
+        assert!(candles.trade(Decimal::from(9), Decimal::from(1), 119).is_empty());
+
+        assert_eq!(candles.close(120), vec![CandleEvent {
+            interval: Interval::Minute,
+            start: 60,
+            open: Decimal::from(10),
+            high: Decimal::from(12),
+            low: Decimal::from(9),
+            close: Decimal::from(9),
+            volume: Decimal::from(4),
+            vwap: Some(Decimal::new(1075, 2)), // (10 + 24 + 9) / 4
+            trades: 3,
+        }]);
+    }
+
+    #[test]
+    fn empty_intervals_are_flat_at_the_last_close() {
+        let mut candles = Candles::new(&[Interval::Second, Interval::Minute]);
+
+        // nothing is published before the first trade
+        assert!(candles.close(100).is_empty());
+
+        candles.trade(Decimal::from(10), Decimal::from(1), 100);
+        candles.trade(Decimal::from(11), Decimal::from(1), 100);
+
+        let bars: Vec<(i64, Decimal, Decimal, Decimal, Option<Decimal>, u64)> = candles.trade(Decimal::from(12), Decimal::from(1), 103).iter()
+            .map(|candle| (candle.start, candle.open, candle.close, candle.volume, candle.vwap, candle.trades))
+            .collect();
+
+        assert_eq!(bars, vec![
+            (100, Decimal::from(10), Decimal::from(11), Decimal::from(2), Some(Decimal::new(105, 1)), 2),
+            (101, Decimal::from(11), Decimal::from(11), Decimal::zero(), None, 0),
+            (102, Decimal::from(11), Decimal::from(11), Decimal::zero(), None, 0),
+        ]);
+
+        // the minute bar has both seconds' trades
+        let minute = candles.close(120);
+        assert_eq!(minute.iter().map(|candle| candle.interval).collect::<Vec<Interval>>(), vec![Interval::Second; 17].into_iter().chain([Interval::Minute]).collect::<Vec<Interval>>());
+        assert_eq!((minute[16].start, minute[17].start, minute[17].trades), (119, 60, 3));
+    }
+
+    #[test]
+    fn candles_published_from_the_book() {
+        let mut orderbook = OrderBook::new();
+        orderbook.set_candle_intervals(&[Interval::Hour]);
+
+        let trader_a = trader();
+        let trader_b = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(trader_a, [(10, 2)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader_b, [(10, 2)])[0]));
+        let traded_at = trades(&events)[0].timestamp;
+
+        // the sweep finishes the hour even though nothing else trades
+        let events = orderbook.expire_orders(traded_at + Interval::Hour.secs());
+
+        match &events[..] {
+            [BookResult::Candle(candle)] => {
+                assert_eq!((candle.close, candle.volume, candle.trades), (Decimal::from(10), Decimal::from(2), 1));
+                assert!(events[0].anonymized().is_some());
+            },
+            _ => panic!("Expected a single CandleEvent"),
+        }
+    }
+
+    fn tape_trade(price: i64, timestamp: i64) -> TradeEvent {
+        TradeEvent {
+            id: trader(),
+            price: Decimal::from(price),
+            size: Decimal::from(1),
+            buy_order: trader(),
+            sell_order: trader(),
+            buyer: Uuid::nil(),
+            seller: Uuid::nil(),
+            aggressor: Some(OrderDirection::Bid),
+            timestamp,
+            buyer_fee: Decimal::zero(),
+            seller_fee: Decimal::zero(),
+        }
+    }
+
+    fn tape_prices(trades: &[TradeEvent]) -> Vec<Decimal> {
+        trades.iter().map(|trade| trade.price).collect()
+    }
+
+    #[test]
+    fn tape_queried_by_time_and_last() {
+        let mut tape = Tape::new(3);
+
+        for (price, timestamp) in [(1, 10), (2, 20), (3, 20), (4, 30)] {
+            tape.record(tape_trade(price, timestamp));
+        }
+
+        // the first trade has made room for the last
+        let (trades, truncated, next) = tape.query(None, None, None, None, 10);
+        assert_eq!((tape_prices(&trades), truncated, next), (vec![Decimal::from(2), Decimal::from(3), Decimal::from(4)], true, None));
+
+        let (trades, truncated, _) = tape.query(Some(20), Some(20), None, None, 10);
+        assert_eq!((tape_prices(&trades), truncated), (vec![Decimal::from(2), Decimal::from(3)], false));
+
+        let (trades, truncated, _) = tape.query(None, Some(25), Some(1), None, 10);
+        assert_eq!((tape_prices(&trades), truncated), (vec![Decimal::from(3)], false));
+
+        // asking for more than is kept from before the oldest trade
+        let (trades, truncated, _) = tape.query(Some(5), None, Some(10), None, 10);
+        assert_eq!((trades.len(), truncated), (3, true));
+
+        assert!(tape.query(Some(31), None, None, None, 10).0.is_empty());
+
+        // a page at a time, each carrying on after the last trade of the one before
+        let (trades, truncated, next) = tape.query(Some(20), None, None, None, 2);
+        assert_eq!((tape_prices(&trades), truncated, next), (vec![Decimal::from(2), Decimal::from(3)], false, Some(trades[1].id)));
+
+        let (trades, truncated, next) = tape.query(Some(20), None, None, next, 2);
+        assert_eq!((tape_prices(&trades), truncated, next), (vec![Decimal::from(4)], false, None));
+    }
+
+    #[test]
+    fn trades_query_answered_privately() {
+        let path = journal_path();
+        let trader_a = trader();
+        let trader_b = trader();
+        let analyst = trader();
+
+        {
+            let mut engine = engine(&path);
+            for request in [BookRequest::Open(ask!(trader_a, [(10, 1), (11, 1)])[0]), BookRequest::Open(ask!(trader_a, [(11, 1)])[0]), BookRequest::Open(bid!(trader_b, [(11, 2)])[0])] {
+                engine.process_request(Request::Book(request)).unwrap();
+            }
+        }
+
+        // the tape is loaded back from the journal
+        let mut engine = engine(&path);
+        let request: Request = serde_json::from_str(&format!(r#"{{"Trades": {{"owner": "{}", "last": 1}}}}"#, analyst)).unwrap();
+        let publications = engine.process_request(request).unwrap();
+
+        assert_eq!(routed(&publications, Route::Public), vec![(4, vec![])]);
+
+        let reply = publications.iter().find(|publication| publication.route == Route::Owner(analyst)).unwrap();
+        match &reply.events.events[0].event {
+            BookResult::Trades(trades_event) => {
+                assert_eq!(tape_prices(&trades_event.trades), vec![Decimal::from(11)]);
+                assert_eq!((trades_event.trades[0].buyer, trades_event.trades[0].seller), (Uuid::nil(), Uuid::nil()));
+                assert!(!trades_event.truncated);
+            },
+            _ => panic!("Expected a TradesEvent"),
+        }
+
+        // queries count against the message rate like orders do
+        let limited = RiskLimits { max_messages_per_sec: Some(1), ..RiskLimits::default() };
+        engine.process_request(Request::Book(BookRequest::Admin(AdminRequest::SetLimits(LimitsEvent{ owner: trader(), trader: Some(analyst), limits: limited, timestamp: 0 })))).unwrap();
+        let request: Request = serde_json::from_str(&format!(r#"{{"Trades": {{"owner": "{}"}}}}"#, analyst)).unwrap();
+        let mut answers = Vec::new();
+
+        // three in a row, at least two of them within the same second
+        for _ in 0..3 {
+            let publications = engine.process_request(request.clone()).unwrap();
+            let reply = publications.into_iter().find(|publication| publication.route == Route::Owner(analyst)).unwrap();
+            answers.push(reply.events.events[0].event.clone());
+        }
+
+        assert!(answers.iter().any(|answer| matches!(answer, BookResult::Bounce(BounceEvent { reason: BounceReason::MessageRateLimit { limit: 1 }, .. }))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn order_status(orderbook: &mut OrderBook, owner: Uuid, id: Uuid) -> Result<OrderState, BounceReason> {
+        match &orderbook.process_request(BookRequest::OrderStatus(OrderStatusRequest{ owner, id }))[..] {
+            [BookResult::OrderStatus(status_event)] => Ok(status_event.order.clone()),
+            [BookResult::Bounce(bounce_event)] => Err(bounce_event.reason),
+            _ => panic!("Expected an OrderStatusEvent or a bounce"),
+        }
+    }
+
+    #[test]
+    fn order_status_follows_partial_fills() {
+        let mut orderbook = OrderBook::new();
+        let buyer = trader();
+        let seller = trader();
+
+        for ask in ask!(seller, [(10, 1), (11, 1)]) {
+            orderbook.process_request(BookRequest::Open(ask));
+        }
+
+        let events = orderbook.process_request(BookRequest::Open(bid!(buyer, [(12, 3)])[0]));
+        let id = match events[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the bid"),
+        };
+
+        // the rest of the bid rests under a new id, either id finds it
+        for lookup in [id, child_of(&events, id)] {
+            let order = order_status(&mut orderbook, buyer, lookup).unwrap();
+            assert_eq!(order.id, child_of(&events, id));
+            assert_eq!(order.parents, vec![id]);
+            assert_eq!((order.remaining, order.filled), (Decimal::from(1), Decimal::from(2)));
+            assert_eq!(order.average_price, Some(Decimal::new(105, 1)));
+        }
+
+        // nobody else gets to see it
+        assert!(matches!(order_status(&mut orderbook, seller, id), Err(BounceReason::NotOwner)));
+
+        orderbook.process_request(BookRequest::Open(ask!(seller, [(12, 1)])[0]));
+        assert!(matches!(order_status(&mut orderbook, buyer, id), Err(BounceReason::OrderNotFound)));
+    }
+
+    fn child_of(events: &[BookResult], parent: Uuid) -> Uuid {
+        events.iter().find_map(|event| match event {
+            BookResult::Opened(opened_event) if opened_event.parent == Some(parent) => Some(opened_event.id),
+            _ => None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn open_orders_by_owner() {
+        let mut orderbook = OrderBook::new();
+        let owner = trader();
+        let other = trader();
+
+        orderbook.process_request(BookRequest::Open(bid!(owner, [(10, 2)])[0]));
+        orderbook.process_request(BookRequest::Open(iceberg(ask!(owner, [(12, 5)])[0], 1)));
+        orderbook.process_request(BookRequest::Open(with_type(bid!(owner, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(20) })));
+        orderbook.process_request(BookRequest::Open(ask!(other, [(13, 1)])[0]));
+        orderbook.process_request(BookRequest::Open(ask!(other, [(10, 1)])[0]));
+
+        let orders = match &orderbook.process_request(BookRequest::OpenOrders(OpenOrdersRequest{ owner }))[..] {
+            [BookResult::OpenOrders(orders_event)] => orders_event.orders.clone(),
+            _ => panic!("Expected an OpenOrdersEvent"),
+        };
+
+        let summary: Vec<(OrderDirection, Decimal, Decimal, Decimal, usize)> = orders.iter()
+            .map(|order| (order.direction, order.price, order.remaining, order.filled, order.parents.len()))
+            .collect();
+
+        assert_eq!(summary, vec![
+            (OrderDirection::Bid, Decimal::from(10), Decimal::from(1), Decimal::from(1), 1),
+            (OrderDirection::Ask, Decimal::from(12), Decimal::from(5), Decimal::zero(), 0),
+            (OrderDirection::Bid, Decimal::zero(), Decimal::from(1), Decimal::zero(), 0),
+        ]);
+        assert_eq!(orders[0].average_price, Some(Decimal::from(10)));
+        assert_eq!(orders[2].order_type, OrderType::Stop { trigger: Decimal::from(20) });
+    }
+
+    fn root_fills(events: &[BookResult]) -> Vec<(Uuid, Decimal, Decimal, Decimal)> {
+        events.iter().filter_map(|event| match event {
+            BookResult::Filled(filled_event) => Some((filled_event.root, filled_event.size, filled_event.leaves, filled_event.filled)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn cancel_by_root_after_partial_fills() {
+        let mut orderbook = OrderBook::new();
+        let seller = trader();
+        let buyer = trader();
+
+        let root = match orderbook.process_request(BookRequest::Open(iceberg(ask!(seller, [(10, 5)])[0], 2)))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the ask"),
+        };
+
+        // a partial fill, then the rest of the slice and part of a new one from the reserve
+        let first = orderbook.process_request(BookRequest::Open(bid!(buyer, [(10, 1)])[0]));
+        let second = orderbook.process_request(BookRequest::Open(bid!(buyer, [(10, 2)])[0]));
+
+        let seller_fills = |events: &[BookResult]| root_fills(events).into_iter().filter(|fill| fill.0 == root).collect::<Vec<_>>();
+        assert_eq!(seller_fills(&first), vec![(root, Decimal::from(1), Decimal::from(4), Decimal::from(1))]);
+        assert_eq!(seller_fills(&second), vec![
+            (root, Decimal::from(1), Decimal::from(3), Decimal::from(2)),
+            (root, Decimal::from(1), Decimal::from(2), Decimal::from(3)),
+        ]);
+
+        let current = second.iter().rev().find_map(|event| match event {
+            BookResult::Opened(opened_event) if opened_event.root == root => Some(opened_event.id),
+            _ => None,
+        }).unwrap();
+        assert_ne!(current, root);
+
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id: root,
+            owner: seller,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        match events[..] {
+            [BookResult::Canceled(canceled_event)] => assert_eq!((canceled_event.id, canceled_event.root), (current, root)),
+            _ => panic!("Expected canceled event"),
+        }
+    }
+
+    #[test]
+    fn aggressor_keeps_root_and_cumulative_fill() {
+        let mut orderbook = OrderBook::new();
+        let seller = trader();
+        let buyer = trader();
+
+        orderbook.process_request(BookRequest::Open(ask!(seller, [(10, 1)])[0]));
+        let events = orderbook.process_request(BookRequest::Open(bid!(buyer, [(12, 3)])[0]));
+
+        let root = match events[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the bid"),
+        };
+
+        assert!(root_fills(&events).contains(&(root, Decimal::from(1), Decimal::from(2), Decimal::from(1))));
+
+        let child = child_of(&events, root);
+        let order = order_status(&mut orderbook, buyer, root).unwrap();
+        assert_eq!((order.id, order.root, order.filled, order.remaining), (child, root, Decimal::from(1), Decimal::from(2)));
+    }
+
+    #[test]
+    fn public_fills_keep_iceberg_reserve_hidden() {
+        let mut orderbook = OrderBook::new();
+        let seller = trader();
+
+        orderbook.process_request(BookRequest::Open(iceberg(ask!(seller, [(10, 100)])[0], 1)));
+        let events = orderbook.process_request(BookRequest::Open(bid!(trader(), [(10, 1)])[0]));
+
+        let fills = root_fills(&events);
+        assert!(fills.iter().any(|fill| fill.2 == Decimal::from(99)));
+
+        let public: Vec<BookResult> = events.iter().filter_map(BookResult::anonymized).collect();
+        assert_eq!(root_fills(&public).len(), fills.len());
+        assert!(root_fills(&public).iter().all(|fill| fill.2.is_zero() && fill.3.is_zero()));
+    }
+
+    fn bounce(events: &[BookResult]) -> BounceEvent {
+        events.iter().find_map(|event| match event {
+            BookResult::Bounce(bounce_event) => Some(bounce_event.clone()),
+            _ => None,
+        }).expect("Expected a BounceEvent")
+    }
+
+    #[test]
+    fn invalid_orders_bounced() {
+        let mut orderbook = OrderBook::new();
+        let owner = trader();
+
+        let mut no_price = bid!(owner, [(0, 1)])[0];
+        no_price.request_id = Some(trader());
+        let events = orderbook.process_request(BookRequest::Open(no_price));
+        let bounce_event = bounce(&events);
+        assert!(matches!(bounce_event.reason, BounceReason::InvalidPrice));
+        assert_eq!(bounce_event.request_id, no_price.request_id);
+        assert_eq!(bounce_event.text, BounceReason::InvalidPrice.to_string());
+
+        // market orders don't need a price, stops do need a trigger
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(with_type(bid!(owner, [(0, 1)])[0], OrderType::Market)))).is_none());
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(with_type(bid!(owner, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::zero() })))), Some(BounceReason::InvalidPrice)));
+
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(bid!(owner, [(10, 0)])[0]))), Some(BounceReason::InvalidSize)));
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(iceberg(bid!(owner, [(10, 5)])[0], 0)))), Some(BounceReason::InvalidSize)));
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(min_qty(bid!(owner, [(10, 5)])[0], -1)))), Some(BounceReason::InvalidSize)));
+
+        // none of them made it onto the book
+        assert!(matches!(&orderbook.process_request(BookRequest::OpenOrders(OpenOrdersRequest{ owner }))[..], [BookResult::OpenOrders(orders_event)] if orders_event.orders.is_empty()));
+    }
+
+    #[test]
+    fn only_the_owner_cancels() {
+        let mut orderbook = OrderBook::new();
+        let owner = trader();
+        let other = trader();
+
+        let id = opened_id(&orderbook.process_request(BookRequest::Open(bid!(owner, [(10, 1)])[0])));
+
+        let events = orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id,
+            owner: other,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+        assert!(matches!(bounce_reason(&events), Some(BounceReason::NotOwner)));
+        assert_eq!(order_status(&mut orderbook, owner, id).unwrap().remaining, Decimal::from(1));
+    }
+
+    #[test]
+    fn client_ids_unique_among_live_orders() {
+        let mut orderbook = OrderBook::new();
+        let owner = trader();
+        let client_id = trader();
+
+        let id = opened_id(&orderbook.process_request(BookRequest::Open(with_client_id(bid!(owner, [(10, 1)])[0], client_id))));
+        assert!(matches!(bounce_reason(&orderbook.process_request(BookRequest::Open(with_client_id(bid!(owner, [(9, 1)])[0], client_id)))), Some(BounceReason::DuplicateClientId)));
+
+        // another owner can use it, and so can the owner once the order is gone
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(with_client_id(bid!(trader(), [(9, 1)])[0], client_id)))).is_none());
+
+        orderbook.process_request(BookRequest::Cancel(CancelEvent{
+            id,
+            owner,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+        assert!(bounce_reason(&orderbook.process_request(BookRequest::Open(with_client_id(bid!(owner, [(9, 1)])[0], client_id)))).is_none());
+    }
+
+    fn message_bounce(publications: &[Publication]) -> BounceEvent {
+        let envelope = publications.iter().find(|publication| publication.route == Route::DropCopy).unwrap();
+        let events: Vec<BookResult> = envelope.events.events.iter().map(|event| event.event.clone()).collect();
+        bounce(&events)
+    }
+
+    #[test]
+    fn unreadable_messages_bounced_to_sender() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+        let owner = trader();
+        let request_id = trader();
+
+        // the price is no number, but the owner and request can still be made out
+        let message = format!(r#"{{"Open": {{"owner": "{}", "price": "ten", "request_id": "{}"}}}}"#, owner, request_id);
+        let publications = engine.process_message("1", message.as_bytes()).unwrap();
+        let bounce_event = message_bounce(&publications);
+        assert!(matches!(bounce_event.reason, BounceReason::MalformedMessage));
+        assert_eq!((bounce_event.owner, bounce_event.request_id), (owner, Some(request_id)));
+        assert!(bounce_event.text.starts_with(&BounceReason::MalformedMessage.to_string()));
+        assert_eq!(routed(&publications, Route::Owner(owner)).len(), 1);
+
+        // nothing to go on at all, only the drop copy hears of it
+        let publications = engine.process_message("2", b"not json").unwrap();
+        assert!(matches!(message_bounce(&publications).reason, BounceReason::MalformedMessage));
+        assert!(publications.iter().all(|publication| matches!(publication.route, Route::Public | Route::DropCopy)));
+
+        let message = format!(r#"{{"asset": "OTHER", "OpenOrders": {{"owner": "{}"}}}}"#, owner);
+        let bounce_event = message_bounce(&engine.process_message("3", message.as_bytes()).unwrap());
+        assert!(matches!(bounce_event.reason, BounceReason::UnknownInstrument));
+        assert_eq!(bounce_event.owner, owner);
+
+        let message = format!(r#"{{"asset": "TEST", "OpenOrders": {{"owner": "{}"}}}}"#, owner);
+        let publications = engine.process_message("4", message.as_bytes()).unwrap();
+        assert!(matches!(drop_copy(publications)[0].events[0].event, BookResult::OpenOrders(_)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn engine_events(engine: &mut Engine, request: BookRequest) -> Vec<BookResult> {
+        drop_copy(engine.process_request(Request::Book(request)).unwrap()).into_iter()
+            .flat_map(|envelope| envelope.events.into_iter().map(|event| event.event))
+            .collect()
+    }
+
+    fn snapshot_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}.snapshot", trader()))
+    }
+
+    // id, root, parents, remaining, filled and average price
+    type Standing = (Uuid, Uuid, Vec<Uuid>, Decimal, Decimal, Option<Decimal>);
+
+    // what an owner sees of their orders, in a stable order to compare
+    fn standing(engine: &mut Engine, owner: Uuid) -> Vec<Standing> {
+        let mut orders: Vec<_> = match &engine_events(engine, BookRequest::OpenOrders(OpenOrdersRequest{ owner }))[..] {
+            [BookResult::OpenOrders(orders_event)] => orders_event.orders.iter()
+                .map(|order| (order.id, order.root, order.parents.clone(), order.remaining, order.filled, order.average_price))
+                .collect(),
+            _ => panic!("Expected an OpenOrdersEvent"),
+        };
+
+        orders.sort_by_key(|order| order.0);
+        orders
+    }
+
+    fn recovered(path: &std::path::Path, snapshot: &std::path::Path) -> Engine {
+        Engine::recover("TEST".to_string(), OrderBook::new(), Journal::open(path).unwrap(), snapshot, 60, 100).unwrap()
+    }
+
+    #[test]
+    fn snapshot_restores_the_book() {
+        let path = journal_path();
+        let snapshot = snapshot_path();
+        let mut engine = engine(&path);
+
+        let seller = trader();
+        let buyer = trader();
+
+        let root = match engine_events(&mut engine, BookRequest::Open(iceberg(ask!(seller, [(10, 5)])[0], 2)))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the ask"),
+        };
+
+        // the iceberg is partially filled and replenished, so its current id is a child of the root
+        engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(10, 1)])[0]));
+        engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(10, 2)])[0]));
+
+        engine_events(&mut engine, BookRequest::Open(bid!(buyer, [(8, 2)])[0]));
+        engine_events(&mut engine, BookRequest::Open(with_type(bid!(buyer, [(0, 1)])[0], OrderType::Stop { trigger: Decimal::from(20) })));
+        engine_events(&mut engine, BookRequest::Open(with_type(ask!(seller, [(0, 1)])[0], OrderType::TrailingStop { trail: Trail::Amount(Decimal::from(2)) })));
+        engine_events(&mut engine, BookRequest::Admin(AdminRequest::SetPhase(PhaseEvent{ owner: trader(), phase: TradingPhase::Halted, timestamp: 0 })));
+
+        let sellers_orders = standing(&mut engine, seller);
+        let buyers_orders = standing(&mut engine, buyer);
+        assert_eq!(sellers_orders.len(), 2);
+        assert_eq!(buyers_orders.len(), 2);
+
+        engine.checkpoint(&snapshot).unwrap();
+        drop(engine);
+
+        let mut engine = recovered(&path, &snapshot);
+
+        assert_eq!(standing(&mut engine, seller), sellers_orders);
+        assert_eq!(standing(&mut engine, buyer), buyers_orders);
+
+        // still halted
+        let events = engine_events(&mut engine, BookRequest::Open(with_type(bid!(buyer, [(0, 1)])[0], OrderType::Market)));
+        assert!(matches!(bounce(&events).reason, BounceReason::TradingHalted));
+
+        // the iceberg is still found by its root, under its current id
+        let events = engine_events(&mut engine, BookRequest::Cancel(CancelEvent{
+            id: root,
+            owner: seller,
+            timestamp: 0,
+            client_id: None,
+            request_id: None,
+        }));
+
+        match &events[..] {
+            [BookResult::Canceled(canceled_event)] => {
+                assert_eq!(canceled_event.root, root);
+                assert_ne!(canceled_event.id, root);
+            },
+            _ => panic!("Expected CanceledEvent for the iceberg"),
+        }
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(snapshot).unwrap();
+    }
+
+    #[test]
+    fn journal_rolled_back_to_snapshot() {
+        let path = journal_path();
+        let snapshot = snapshot_path();
+        let mut engine = recovered(&path, &snapshot);
+
+        let owner = trader();
+        engine_events(&mut engine, BookRequest::Open(bid!(owner, [(10, 1)])[0]));
+        engine.checkpoint(&snapshot).unwrap();
+
+        // journaled after the snapshot was taken, the engine stopped before publishing it or acking its message
+        let late = bid!(owner, [(9, 1)])[0];
+        engine_events(&mut engine, BookRequest::Open(late));
+        drop(engine);
+
+        // handled again when redelivered, under the numbers it had
+        let mut engine = recovered(&path, &snapshot);
+        let envelopes = drop_copy(engine.process_request(Request::Book(BookRequest::Open(late))).unwrap());
+        assert_eq!(sequences(&envelopes), vec![(2, vec![2])]);
+        assert_eq!(standing(&mut engine, owner).len(), 2);
+
+        // a journal without the snapshot to go with it isn't carried on from
+        std::fs::remove_file(&snapshot).unwrap();
+        assert!(Engine::recover("TEST".to_string(), OrderBook::new(), Journal::open(&path).unwrap(), &snapshot, 60, 100).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redelivered_message_published_again() {
+        let path = journal_path();
+        let snapshot = snapshot_path();
+        let mut engine = recovered(&path, &snapshot);
+
+        let seller = trader();
+        let buyer = trader();
+        engine_events(&mut engine, BookRequest::Open(ask!(seller, [(10, 2)])[0]));
+
+        // no request id, only the message id tells a redelivery apart
+        let message = serde_json::to_vec(&Request::Book(BookRequest::Open(bid!(buyer, [(10, 1)])[0]))).unwrap();
+        let first = engine.process_message("m1", &message).unwrap();
+        engine.checkpoint(&snapshot).unwrap();
+
+        // publishing failed for good, the engine stopped without acking the message
+        drop(engine);
+
+        let mut engine = recovered(&path, &snapshot);
+        let again = engine.process_message("m1", &message).unwrap();
+        assert_eq!(routed(&again, Route::DropCopy), routed(&first, Route::DropCopy));
+        assert_eq!(routed(&again, Route::Owner(buyer)), routed(&first, Route::Owner(buyer)));
+        assert_eq!(routed(&again, Route::Public), routed(&first, Route::Public));
+
+        // a new message carries on after it, and fills what is left of the ask
+        let publications = engine.process_message("m2", &message).unwrap();
+        assert_eq!(routed(&publications, Route::DropCopy)[0].0, 3);
+        assert!(standing(&mut engine, seller).is_empty());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(snapshot).unwrap();
+    }
+
+    #[test]
+    fn batches_keep_order_and_requests_apart() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let seller = trader();
+        let buyer = trader();
+
+        let mut publications = Vec::new();
+        for request in [
+            BookRequest::Open(ask!(seller, [(10, 2)])[0]),
+            BookRequest::Open(bid!(buyer, [(10, 1)])[0]),
+            BookRequest::Open(bid!(buyer, [(10, 1)])[0]),
+        ] {
+            publications.append(&mut engine.process_request(Request::Book(request)).unwrap());
+        }
+
+        let batches = Batch::of(publications.clone());
+        let routes: Vec<Route> = batches.iter().map(|batch| batch.route).collect();
+        assert_eq!(routes, vec![Route::Public, Route::Owner(seller), Route::DropCopy, Route::Owner(buyer)]);
+
+        // an envelope per request on the drop copy and the public route, in the order they were published
+        for batch in &batches {
+            let envelopes: Vec<u64> = batch.envelopes.iter().map(|envelope| envelope.sequence).collect();
+
+            match batch.route {
+                Route::Public | Route::DropCopy => assert_eq!(envelopes, vec![1, 2, 3]),
+                Route::Owner(owner) if owner == seller => assert_eq!(envelopes, vec![1, 2, 3]),
+                Route::Owner(_) => assert_eq!(envelopes, vec![1, 2]),
+            }
+        }
+
+        let drop_copy = &batches[2];
+        let events: Vec<u64> = drop_copy.envelopes.iter().flat_map(|envelope| envelope.events.iter().map(|event| event.sequence)).collect();
+        assert_eq!(events, (1..=events.len() as u64).collect::<Vec<u64>>());
+
+        // too big to go out together, each envelope goes out on its own and still in order
+        let split = Batch::within(publications, 1);
+        let drop_copy: Vec<u64> = split.iter()
+            .filter(|batch| batch.route == Route::DropCopy)
+            .map(|batch| match &batch.envelopes[..] {
+                [envelope] => envelope.sequence,
+                _ => panic!("Expected a batch per envelope"),
+            })
+            .collect();
+        assert_eq!(drop_copy, vec![1, 2, 3]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stats_count_requests_bounces_and_fills() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+
+        let seller = trader();
+        let buyer = trader();
+
+        let ask = ask!(seller, [(10, 2)])[0];
+        engine.process_request(Request::Book(BookRequest::Open(ask))).unwrap();
+        engine.process_request(Request::Book(BookRequest::Open(bid!(buyer, [(10, 1)])[0]))).unwrap();
+        engine.process_request(Request::Book(BookRequest::Open(bid!(buyer, [(0, 1)])[0]))).unwrap();
+        engine.process_request(Request::Book(BookRequest::OpenOrders(OpenOrdersRequest{ owner: seller }))).unwrap();
+        engine.process_message("1", b"not a request").unwrap();
+
+        // retransmitted events were counted when they were first published
+        engine.process_request(Request::Engine(EngineRequest::Retransmit(RetransmitRequest{ from: 1, to: 100, owner: None }))).unwrap();
+
+        let stats = engine.stats();
+        assert_eq!(stats.requests.iter().map(|(kind, count)| (*kind, *count)).collect::<Vec<_>>(),
+                   vec![("malformed", 1), ("open", 3), ("open_orders", 1), ("retransmit", 1)]);
+        assert_eq!(stats.bounces.iter().map(|(reason, count)| (*reason, *count)).collect::<Vec<_>>(),
+                   vec![("InvalidPrice", 1), ("MalformedMessage", 1)]);
+        assert_eq!((stats.fills, stats.filled_quantity), (1, Decimal::from(1)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn depth_by_side() {
+        let mut orderbook = OrderBook::new();
+        let owner = trader();
+
+        for bid in bid!(owner, [(10, 2), (10, 1), (9, 3)]) {
+            orderbook.process_request(BookRequest::Open(bid));
+        }
+        orderbook.process_request(BookRequest::Open(iceberg(ask!(owner, [(12, 5)])[0], 2)));
+
+        // canceling the only order at a level leaves nothing there
+        let id = match orderbook.process_request(BookRequest::Open(ask!(owner, [(13, 1)])[0]))[0] {
+            BookResult::Opened(opened_event) => opened_event.id,
+            _ => panic!("Expected OpenedEvent for the ask"),
+        };
+        orderbook.process_request(BookRequest::Cancel(CancelEvent{ id, owner, timestamp: 0, client_id: None, request_id: None }));
+
+        let bids = orderbook.depth(OrderDirection::Bid);
+        assert_eq!((bids.levels, bids.size, bids.orders), (2, Decimal::from(6), 3));
+
+        // only the iceberg's visible slice
+        let asks = orderbook.depth(OrderDirection::Ask);
+        assert_eq!((asks.levels, asks.size, asks.orders), (1, Decimal::from(2), 1));
+    }
+
+    #[test]
+    fn oversized_orders_bounced() {
+        let path = journal_path();
+        let mut engine = engine(&path);
+        let owner = trader();
+
+        // the product of price and size would overflow a Decimal
+        let mut huge_price = bid!(owner, [(10, 2)])[0];
+        huge_price.price = Decimal::MAX;
+        let message = serde_json::to_vec(&Request::Book(BookRequest::Open(huge_price))).unwrap();
+        assert!(matches!(message_bounce(&engine.process_message("1", &message).unwrap()).reason, BounceReason::InvalidPrice));
+
+        let mut huge_size = with_type(ask!(owner, [(0, 1)])[0], OrderType::Market);
+        huge_size.size = Decimal::MAX;
+        let message = serde_json::to_vec(&Request::Book(BookRequest::Open(huge_size))).unwrap();
+        assert!(matches!(message_bounce(&engine.process_message("2", &message).unwrap()).reason, BounceReason::InvalidSize));
+
+        // right at the bounds is fine, and trades
+        let mut largest = ask!(owner, [(1, 1)])[0];
+        largest.price = MAX_PRICE;
+        largest.size = MAX_SIZE;
+        assert!(bounce_reason(&engine_events(&mut engine, BookRequest::Open(largest))).is_none());
+
+        let mut crossing = bid!(trader(), [(1, 1)])[0];
+        crossing.price = MAX_PRICE;
+        crossing.size = MAX_SIZE;
+        let events = engine_events(&mut engine, BookRequest::Open(crossing));
+        assert!(events.iter().any(|event| matches!(event, BookResult::Traded(trade_event) if trade_event.size == MAX_SIZE)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
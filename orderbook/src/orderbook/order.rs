@@ -1,17 +1,19 @@
 use std::cmp::Ordering;
-use rust_decimal::prelude::Decimal;
+use rust_decimal::prelude::{Decimal, Zero};
 use chrono;
 
 use uuid::Uuid;
-use uuid::v1::{Context, Timestamp};
+use uuid::v1::Timestamp;
 use serde::{Serialize, Deserialize};
 
+// Every event timestamp, and every expiry, is in whole seconds since the unix epoch (UTC). Timestamps record
+// when something happened, they never decide priority, that is what LimitOrder::sequence is for.
 pub fn timestamp() -> i64 {
     chrono::offset::Utc::now().timestamp()
 }
 
 pub fn timestamp_nanos() -> i64 {
-    chrono::offset::Utc::now().timestamp_nanos()
+    chrono::offset::Utc::now().timestamp_nanos_opt().expect("timestamp out of range")
 }
 
 pub fn generate_uuid(counter: u16) -> Uuid {
@@ -25,15 +27,156 @@ pub enum OrderDirection {
     Ask,
 }
 
-#[derive(Debug, Clone, Copy, Eq)]
+impl OrderDirection {
+    pub fn opposite(&self) -> Self {
+        match self {
+            OrderDirection::Bid => OrderDirection::Ask,
+            OrderDirection::Ask => OrderDirection::Bid,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,                         // trades at any price, whatever doesn't fill right away is canceled
+    Stop { trigger: Decimal },      // inactive until the last price reaches the trigger, then a market order
+    StopLimit { trigger: Decimal }, // inactive until the last price reaches the trigger, then a limit order
+    Peg { reference: PegReference, offset: Decimal, cap: Option<Decimal> }, // priced off the top of the book
+    TrailingStop { trail: Trail },  // a stop whose trigger follows the best price traded since it was placed
+}
+
+// how far a trailing stop's trigger stays behind the high (sell stops) or low (buy stops) water mark
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trail {
+    Amount(Decimal),
+    Percent(Decimal),
+}
+
+// what a pegged order follows, relative to the side of the order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PegReference {
+    Primary,  // the best price on its own side, best bid for bids and best ask for asks
+    Midpoint, // halfway between the best bid and the best ask
+    Market,   // the best price on the other side, best ask for bids and best bid for asks
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel,
+    Day,                            // expires when the session closes
+    GoodTillDate { expiry: i64 },   // expires once the engine clock reaches expiry, same unit as event timestamps
+}
+
+impl TimeInForce {
+    pub fn expired(&self, now: i64, session_closed: bool) -> bool {
+        match self {
+            TimeInForce::GoodTillCancel => false,
+            TimeInForce::Day => session_closed,
+            TimeInForce::GoodTillDate { expiry } => *expiry <= now,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, Serialize, Deserialize)]
 pub struct LimitOrder {
     pub(crate) id: Uuid,
     pub(crate) parent: Option<Uuid>,
+    pub(crate) root: Uuid,     // the id the order was placed under, it stays the same across every child order
     pub(crate) owner: Uuid,
     pub(crate) price: Decimal,
     pub(crate) size: Decimal,
     pub(crate) direction: OrderDirection,
     pub(crate) timestamp: i64,
+    pub(crate) sequence: u64, // engine arrival sequence, the time priority key within a price level
+    pub(crate) order_type: OrderType,
+    pub(crate) display: Option<Decimal>, // iceberg peak, size never goes above it
+    pub(crate) hidden: Decimal,          // iceberg reserve, not on the book and not counted in any level size
+    pub(crate) time_in_force: TimeInForce,
+    pub(crate) min_qty: Option<Decimal>, // smallest execution the order takes when it enters as the aggressor
+    pub(crate) all_or_none: bool,        // the whole order executes at once or not at all, resting or aggressing
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) filled: Decimal, // cumulative quantity executed since the order was placed, the leaves are its total
+}
+
+impl LimitOrder {
+    // the leaves quantity, what is still open of the order
+    pub fn total(&self) -> Decimal {
+        self.size + self.hidden
+    }
+
+    // how much has to be executable before this order trades as the aggressor
+    pub fn required_qty(&self) -> Decimal {
+        if self.all_or_none {
+            self.total()
+        } else {
+            self.min_qty.map_or(Decimal::zero(), |min_qty| min_qty.min(self.total()))
+        }
+    }
+
+    // whether a resting order can be matched against an aggressor with remainder left to fill
+    pub fn accepts_fill(&self, remainder: Decimal) -> bool {
+        !self.all_or_none || self.total() <= remainder
+    }
+
+    pub fn is_pegged(&self) -> bool {
+        matches!(self.order_type, OrderType::Peg { .. })
+    }
+
+    // The price a pegged order should be at given the top of the book, the reference plus the signed offset,
+    // never more aggressive than the cap. None if the order isn't pegged, its reference doesn't exist or the offset
+    // takes it past what a Decimal holds. Whether the price is one the book takes is up to the book.
+    pub fn peg_price(&self, best_bid: Option<Decimal>, best_ask: Option<Decimal>) -> Option<Decimal> {
+        let (reference, offset, cap) = match self.order_type {
+            OrderType::Peg { reference, offset, cap } => (reference, offset, cap),
+            _ => return None,
+        };
+
+        let (same_side, other_side) = match self.direction {
+            OrderDirection::Bid => (best_bid, best_ask),
+            OrderDirection::Ask => (best_ask, best_bid),
+        };
+
+        let price = match reference {
+            PegReference::Primary => same_side?,
+            PegReference::Market => other_side?,
+            PegReference::Midpoint => best_bid?.checked_add(best_ask?)? / Decimal::from(2),
+        }.checked_add(offset)?;
+
+        Some(match (cap, self.direction) {
+            (Some(cap), OrderDirection::Bid) => price.min(cap),
+            (Some(cap), OrderDirection::Ask) => price.max(cap),
+            (None, _) => price,
+        })
+    }
+
+    // child order carrying the rest of this one, icebergs only show up to their peak of it. Whatever isn't carried
+    // over has been executed.
+    pub fn remaining(&self, id: Uuid, total: Decimal, timestamp: i64, sequence: u64) -> LimitOrder {
+        let size = self.display.map_or(total, |peak| peak.min(total));
+
+        LimitOrder {
+            id,
+            parent: Some(self.id),
+            size,
+            hidden: total - size,
+            filled: self.filled + self.total() - total,
+            timestamp,
+            sequence,
+            ..*self
+        }
+    }
+
+    // whether this order is willing to trade against a resting order at price
+    pub fn crosses(&self, price: Decimal) -> bool {
+        match (self.order_type, self.direction) {
+            (OrderType::Market, _) => true,
+            (_, OrderDirection::Bid) => price <= self.price,
+            (_, OrderDirection::Ask) => price >= self.price,
+        }
+    }
 }
 
 impl Ord for LimitOrder {
@@ -44,7 +187,7 @@ impl Ord for LimitOrder {
             _ => {}
         }
 
-        match self.timestamp.cmp(&other.timestamp) {
+        match self.sequence.cmp(&other.sequence) {
             Ordering::Less => { return Ordering::Less }
             Ordering::Greater => { return Ordering:: Greater}
             _ => {}
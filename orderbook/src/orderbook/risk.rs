@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+use rust_decimal::prelude::{Decimal, Zero};
+use serde::{Serialize, Deserialize};
+
+use crate::orderbook::order::OrderDirection;
+use crate::orderbook::book::{BookResult, BounceReason, OpenEvent};
+
+// Pre-trade limits, None leaves that check off. Missing fields deserialize as None so an admin only has to send
+// the limits they care about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RiskLimits {
+    #[serde(default)]
+    pub(crate) max_order_size: Option<Decimal>,
+    #[serde(default)]
+    pub(crate) max_notional: Option<Decimal>,
+    #[serde(default)]
+    pub(crate) max_open_orders: Option<usize>,
+    #[serde(default)]
+    pub(crate) max_position: Option<Decimal>, // absolute net position in the instrument, long or short
+    #[serde(default)]
+    pub(crate) max_messages_per_sec: Option<u32>,
+}
+
+// what an owner has on the book and has traded
+#[derive(Debug, Default)]
+struct Exposure {
+    open_orders: usize,
+    bids: Decimal,     // open quantity of live bids, iceberg reserves included
+    asks: Decimal,
+    position: Decimal, // bought minus sold
+    second: i64,       // the second the message count is for
+    messages: u32,
+}
+
+impl Exposure {
+    fn side(&mut self, direction: OrderDirection) -> &mut Decimal {
+        match direction {
+            OrderDirection::Bid => &mut self.bids,
+            OrderDirection::Ask => &mut self.asks,
+        }
+    }
+}
+
+// Checks every order and cancel before it reaches the book. Exposure is kept up to date from the events the book
+// publishes, so it always agrees with what the owners were told.
+#[derive(Debug, Default)]
+pub struct RiskCheck {
+    limits: RiskLimits,                    // for every owner without limits of their own
+    overrides: HashMap<Uuid, RiskLimits>,
+    exposure: HashMap<Uuid, Exposure>,
+    live: HashMap<Uuid, (Uuid, OrderDirection, Decimal)>, // owner, side and open quantity of every live order by id
+}
+
+impl RiskCheck {
+    pub fn new() -> Self { RiskCheck::default() }
+
+    // None sets the limits for every owner, Some replaces the limits of just that owner
+    pub fn set_limits(&mut self, owner: Option<Uuid>, limits: RiskLimits) {
+        match owner {
+            Some(owner) => { self.overrides.insert(owner, limits); },
+            None => self.limits = limits,
+        }
+    }
+
+    fn limits(&self, owner: &Uuid) -> RiskLimits {
+        self.overrides.get(owner).copied().unwrap_or(self.limits)
+    }
+
+    // Count a message against the owner's rate for the second it arrived in, every request counts whether or not
+    // it goes on to be accepted.
+    pub fn check_message(&mut self, owner: Uuid, ts: i64) -> Option<BounceReason> {
+        let limit = self.limits(&owner).max_messages_per_sec;
+        let exposure = self.exposure.entry(owner).or_default();
+
+        if exposure.second != ts {
+            exposure.second = ts;
+            exposure.messages = 0;
+        }
+
+        exposure.messages += 1;
+
+        match limit {
+            Some(limit) if exposure.messages > limit => Some(BounceReason::MessageRateLimit { limit }),
+            _ => None,
+        }
+    }
+
+    // Check a new order against its owner's limits, valued at price for its notional. The book values orders at
+    // what they could trade at, nothing is checked against an order it can't value yet. The position is projected
+    // as if every live order on the same side filled along with this one, an order that brings that back towards
+    // flat is always allowed however large the position is.
+    pub fn check_order(&mut self, open_event: &OpenEvent, price: Option<Decimal>) -> Option<BounceReason> {
+        if let Some(reason) = self.check_message(open_event.owner, open_event.timestamp) {
+            return Some(reason);
+        }
+
+        let limits = self.limits(&open_event.owner);
+        let exposure = self.exposure.get(&open_event.owner);
+        let open_orders = exposure.map_or(0, |exposure| exposure.open_orders);
+        let position = exposure.map_or(Decimal::zero(), |exposure| exposure.position);
+        let same_side = exposure.map_or(Decimal::zero(), |exposure| match open_event.direction {
+            OrderDirection::Bid => exposure.bids,
+            OrderDirection::Ask => exposure.asks,
+        });
+
+        if let Some(limit) = limits.max_order_size.filter(|limit| open_event.size > *limit) {
+            return Some(BounceReason::OrderSizeLimit { limit });
+        }
+
+        if let Some(limit) = limits.max_notional.filter(|limit| price.is_some_and(|price| price.checked_mul(open_event.size).is_none_or(|notional| notional > *limit))) {
+            return Some(BounceReason::NotionalLimit { limit });
+        }
+
+        if let Some(limit) = limits.max_open_orders.filter(|limit| open_orders >= *limit) {
+            return Some(BounceReason::OpenOrderLimit { limit });
+        }
+
+        let projected = position + RiskCheck::signed(open_event.direction, same_side + open_event.size);
+        if let Some(limit) = limits.max_position.filter(|limit| projected.abs() > *limit && projected.abs() > position.abs()) {
+            return Some(BounceReason::PositionLimit { limit });
+        }
+
+        None
+    }
+
+    // Follow the book: opened orders count until they are filled, canceled or expired (whatever is left of a
+    // partial fill is opened again as a new order) and fills move the position.
+    pub fn apply(&mut self, events: &[BookResult]) {
+        for event in events {
+            match event {
+                BookResult::Opened(e) => self.open(e.id, e.owner, e.direction, e.size + e.hidden),
+                BookResult::Filled(e) => {
+                    if let Some((owner, direction)) = self.close(&e.id) {
+                        self.exposure.entry(owner).or_default().position += RiskCheck::signed(direction, e.size);
+                    }
+                },
+                BookResult::Canceled(e) => { self.close(&e.id); },
+                BookResult::Expired(e) => { self.close(&e.id); },
+                _ => {},
+            }
+        }
+    }
+
+    // stops are opened again with the same id when they trigger, they still only count once
+    fn open(&mut self, id: Uuid, owner: Uuid, direction: OrderDirection, quantity: Decimal) {
+        let previous = self.live.insert(id, (owner, direction, quantity));
+        let exposure = self.exposure.entry(owner).or_default();
+
+        match previous {
+            Some((_, _, previous)) => *exposure.side(direction) -= previous,
+            None => exposure.open_orders += 1,
+        }
+
+        *exposure.side(direction) += quantity;
+    }
+
+    fn close(&mut self, id: &Uuid) -> Option<(Uuid, OrderDirection)> {
+        let (owner, direction, quantity) = self.live.remove(id)?;
+
+        if let Some(exposure) = self.exposure.get_mut(&owner) {
+            exposure.open_orders -= 1;
+            *exposure.side(direction) -= quantity;
+        }
+
+        Some((owner, direction))
+    }
+
+    fn signed(direction: OrderDirection, size: Decimal) -> Decimal {
+        match direction {
+            OrderDirection::Bid => size,
+            OrderDirection::Ask => -size,
+        }
+    }
+}
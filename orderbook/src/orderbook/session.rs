@@ -0,0 +1,51 @@
+use serde::{Serialize, Deserialize};
+
+// an order book that has never been told otherwise trades continuously
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TradingPhase {
+    PreOpen,        // opening call, orders are accepted and rest on the book, nothing is matched
+    #[default]
+    Continuous,     // orders are matched as they arrive
+    ClosingAuction, // closing call, same as the opening call but uncrosses into closed
+    Halted,         // trading is suspended, orders are accepted but not matched
+    Closed,         // new orders are bounced, cancels are still accepted
+}
+
+impl TradingPhase {
+    pub fn accepts_orders(&self) -> bool {
+        *self != TradingPhase::Closed
+    }
+
+    pub fn matches_orders(&self) -> bool {
+        *self == TradingPhase::Continuous
+    }
+
+    // call phases publish indicative uncrossing prices as orders arrive
+    pub fn is_call(&self) -> bool {
+        matches!(self, TradingPhase::PreOpen | TradingPhase::ClosingAuction)
+    }
+
+    pub fn can_transition_to(&self, next: TradingPhase) -> bool {
+        matches!((self, next),
+            (TradingPhase::PreOpen, TradingPhase::Continuous)
+            | (TradingPhase::PreOpen, TradingPhase::Halted)
+            | (TradingPhase::PreOpen, TradingPhase::Closed)
+            | (TradingPhase::Continuous, TradingPhase::ClosingAuction)
+            | (TradingPhase::Continuous, TradingPhase::Halted)
+            | (TradingPhase::Continuous, TradingPhase::Closed)
+            | (TradingPhase::ClosingAuction, TradingPhase::Halted)
+            | (TradingPhase::ClosingAuction, TradingPhase::Closed)
+            | (TradingPhase::Halted, TradingPhase::Continuous)
+            | (TradingPhase::Halted, TradingPhase::Closed)
+            | (TradingPhase::Closed, TradingPhase::PreOpen))
+    }
+
+    // whether the book is uncrossed with an auction when moving to the next phase,
+    // resuming after a halt re-opens with an auction just like the open does
+    pub fn uncrosses_into(&self, next: TradingPhase) -> bool {
+        matches!((self, next),
+            (TradingPhase::PreOpen, TradingPhase::Continuous)
+            | (TradingPhase::Halted, TradingPhase::Continuous)
+            | (TradingPhase::ClosingAuction, TradingPhase::Closed))
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use rust_decimal::prelude::Decimal;
+use serde::{Serialize, Deserialize};
+
+use crate::orderbook::order::LimitOrder;
+use crate::orderbook::session::TradingPhase;
+use crate::orderbook::history::OrderHistory;
+
+// What only lives in the book's memory: the orders on it, the stops waiting to trigger and where the session
+// is. Balances, trading volumes and the tape are rebuilt from the journal, so they aren't part of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookSnapshot {
+    pub(crate) phase: TradingPhase,
+    pub(crate) last_price: Option<Decimal>,
+    pub(crate) orders: Vec<LimitOrder>,                 // resting on the book, bids then asks in book order
+    pub(crate) stops: Vec<(LimitOrder, Option<Decimal>)>, // with the watermark of trailing stops
+    pub(crate) history: OrderHistory,
+}
+
+// The book as of the last journaled event, taken after every batch so the next start carries on with the same
+// orders. Whatever the journal holds past sequence is rolled back on that start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub(crate) sequence: u64,
+    pub(crate) book: BookSnapshot,
+}
+
+impl Snapshot {
+    // written next to the path first and then moved over it, so a crash half way never leaves a torn snapshot
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let partial = path.with_extension("partial");
+
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        fs::rename(partial, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
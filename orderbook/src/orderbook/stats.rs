@@ -0,0 +1,36 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::prelude::Decimal;
+
+use crate::orderbook::book::BookResult;
+
+// Running totals since the engine started, for monitoring. Requests are counted by kind as they come in,
+// bounces and fills from the events as they are journaled, so retransmissions aren't counted twice.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub(crate) requests: BTreeMap<&'static str, u64>,
+    pub(crate) bounces: BTreeMap<&'static str, u64>, // by reason
+    pub(crate) fills: u64,                           // trades, each filling a buy and a sell order
+    pub(crate) filled_quantity: Decimal,
+}
+
+impl Stats {
+    pub fn new() -> Self { Stats::default() }
+
+    pub fn request(&mut self, kind: &'static str) {
+        *self.requests.entry(kind).or_default() += 1;
+    }
+
+    pub fn apply<'a>(&mut self, events: impl Iterator<Item = &'a BookResult>) {
+        for event in events {
+            match event {
+                BookResult::Bounce(bounce_event) => *self.bounces.entry(bounce_event.reason.name()).or_default() += 1,
+                BookResult::Traded(trade_event) => {
+                    self.fills += 1;
+                    self.filled_quantity += trade_event.size;
+                },
+                _ => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+use rust_decimal::prelude::Decimal;
+
+use crate::orderbook::order::{OrderDirection, Trail, LimitOrder};
+
+// Inactive stop orders waiting for the last traded price to reach their trigger.
+// Each trigger price keeps its orders in arrival order, trailing stops are kept in arrival order.
+#[derive(Debug, Default)]
+pub struct StopBook {
+    buy_stops: BTreeMap<Decimal, Vec<LimitOrder>>,  // trigger once the last price rises to the trigger
+    sell_stops: BTreeMap<Decimal, Vec<LimitOrder>>, // trigger once the last price falls to the trigger
+    trailing: Vec<TrailingStop>,
+}
+
+#[derive(Debug)]
+pub struct TrailingStop {
+    pub(crate) order: LimitOrder,
+    pub(crate) trail: Trail,
+    pub(crate) watermark: Option<Decimal>, // None until there is a trade to follow
+}
+
+impl TrailingStop {
+    pub fn trigger(&self) -> Option<Decimal> {
+        let watermark = self.watermark?;
+
+        let distance = match self.trail {
+            Trail::Amount(amount) => amount,
+            Trail::Percent(percent) => watermark * percent / Decimal::from(100),
+        };
+
+        Some(match self.order.direction {
+            OrderDirection::Bid => watermark + distance,
+            OrderDirection::Ask => watermark - distance,
+        })
+    }
+
+    // follow an execution price, sell stops trail the high and buy stops trail the low, true if the mark moved
+    fn track(&mut self, price: Decimal) -> bool {
+        let moved = match (self.watermark, self.order.direction) {
+            (None, _) => true,
+            (Some(watermark), OrderDirection::Ask) => price > watermark,
+            (Some(watermark), OrderDirection::Bid) => price < watermark,
+        };
+
+        if moved {
+            self.watermark = Some(price);
+        }
+
+        moved
+    }
+
+    fn triggered(&self, last_price: Decimal) -> bool {
+        match (self.trigger(), self.order.direction) {
+            (Some(trigger), OrderDirection::Bid) => last_price >= trigger,
+            (Some(trigger), OrderDirection::Ask) => last_price <= trigger,
+            (None, _) => false,
+        }
+    }
+}
+
+impl StopBook {
+    pub fn new() -> Self { StopBook::default() }
+
+    pub fn insert(&mut self, trigger: Decimal, order: LimitOrder) {
+        self.side_mut(order.direction).entry(trigger).or_default().push(order);
+    }
+
+    pub fn insert_trailing(&mut self, trail: Trail, order: LimitOrder, last_price: Option<Decimal>) -> &TrailingStop {
+        self.trailing.push(TrailingStop {
+            order,
+            trail,
+            watermark: last_price,
+        });
+
+        self.trailing.last().unwrap()
+    }
+
+    // Follow an execution price with every trailing stop, returns the stops whose trigger moved.
+    pub fn track(&mut self, price: Decimal) -> Vec<&TrailingStop> {
+        self.trailing.iter_mut()
+            .filter_map(|stop| if stop.track(price) { Some(&*stop) } else { None })
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<LimitOrder> {
+        if let Some(idx) = self.trailing.iter().position(|stop| stop.order.id == *id) {
+            return Some(self.trailing.remove(idx).order);
+        }
+
+        for side in [&mut self.buy_stops, &mut self.sell_stops] {
+            let found = side.iter().find_map(|(trigger, orders)| {
+                orders.iter().position(|order| order.id == *id).map(|idx| (*trigger, idx))
+            });
+
+            if let Some((trigger, idx)) = found {
+                let orders = side.get_mut(&trigger).unwrap();
+                let order = orders.remove(idx);
+
+                if orders.is_empty() {
+                    side.remove(&trigger);
+                }
+
+                return Some(order);
+            }
+        }
+
+        None
+    }
+
+    // the mark a trailing stop follows, None for other stops and trailing stops that haven't seen a trade
+    pub fn watermark(&self, id: &Uuid) -> Option<Decimal> {
+        self.trailing.iter().find(|stop| stop.order.id == *id).and_then(|stop| stop.watermark)
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &LimitOrder> {
+        self.buy_stops.values().chain(self.sell_stops.values()).flatten()
+            .chain(self.trailing.iter().map(|stop| &stop.order))
+    }
+
+    // Take every stop triggered by the last price, paired with its trigger.
+    // Buy stops come first from the lowest trigger up, then sell stops from the highest trigger down,
+    // i.e. in the order the price would have passed through them. Ties keep arrival order.
+    // Triggered trailing stops follow in arrival order.
+    pub fn take_triggered(&mut self, last_price: Decimal) -> Vec<(Decimal, LimitOrder)> {
+        let mut triggered = Vec::new();
+
+        let buy_triggers: Vec<Decimal> = self.buy_stops.range(..=last_price).map(|(trigger, _)| *trigger).collect();
+        let sell_triggers: Vec<Decimal> = self.sell_stops.range(last_price..).rev().map(|(trigger, _)| *trigger).collect();
+
+        for trigger in buy_triggers {
+            let orders = self.buy_stops.remove(&trigger).unwrap_or_default();
+            triggered.extend(orders.into_iter().map(|order| (trigger, order)));
+        }
+
+        for trigger in sell_triggers {
+            let orders = self.sell_stops.remove(&trigger).unwrap_or_default();
+            triggered.extend(orders.into_iter().map(|order| (trigger, order)));
+        }
+
+        let (trailing_triggered, trailing): (Vec<TrailingStop>, Vec<TrailingStop>) = self.trailing.drain(..)
+            .partition(|stop| stop.triggered(last_price));
+
+        self.trailing = trailing;
+        triggered.extend(trailing_triggered.into_iter().map(|stop| (stop.trigger().unwrap(), stop.order)));
+
+        triggered
+    }
+
+    fn side_mut(&mut self, direction: OrderDirection) -> &mut BTreeMap<Decimal, Vec<LimitOrder>> {
+        match direction {
+            OrderDirection::Bid => &mut self.buy_stops,
+            OrderDirection::Ask => &mut self.sell_stops,
+        }
+    }
+}
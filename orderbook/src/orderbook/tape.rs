@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+
+use uuid::Uuid;
+
+use crate::orderbook::book::TradeEvent;
+
+// Time and sales, the most recent executions oldest first. Only the last capacity trades are kept, so answering
+// a query never goes back to the journal.
+#[derive(Debug)]
+pub struct Tape {
+    capacity: usize,
+    trades: VecDeque<TradeEvent>,
+    evicted: bool, // whether older trades have been dropped to make room
+}
+
+impl Tape {
+    pub fn new(capacity: usize) -> Self {
+        Tape {
+            capacity,
+            trades: VecDeque::new(),
+            evicted: false,
+        }
+    }
+
+    pub fn record(&mut self, trade: TradeEvent) {
+        if self.trades.len() == self.capacity {
+            self.trades.pop_front();
+            self.evicted = true;
+        }
+
+        if self.capacity > 0 {
+            self.trades.push_back(trade);
+        }
+    }
+
+    // The trades from..=to (either end open when None), only the last ones if asked for, oldest first and at most
+    // limit of them. Trades are kept in timestamp order, so the range is found by binary search. Also tells
+    // whether trades in the range may have been dropped already, and the trade to carry on after when the range
+    // holds more than limit. A query carrying on after a trade no longer kept starts at the oldest one.
+    pub fn query(&self, from: Option<i64>, to: Option<i64>, last: Option<usize>, after: Option<Uuid>, limit: usize) -> (Vec<TradeEvent>, bool, Option<Uuid>) {
+        let start = from.map_or(0, |from| self.trades.partition_point(|trade| trade.timestamp < from));
+        let end = to.map_or(self.trades.len(), |to| self.trades.partition_point(|trade| trade.timestamp <= to));
+        let end = end.max(start);
+        let start = last.map_or(start, |last| start.max(end.saturating_sub(last)));
+        let resumed = after.and_then(|after| self.trades.iter().position(|trade| trade.id == after));
+        let start = resumed.map_or(start, |resumed| start.max(resumed + 1)).min(end);
+
+        // dropped trades matter if the range reaches back past the oldest trade kept and more were asked for
+        let truncated = self.evicted
+            && start == 0
+            && from.is_none_or(|from| self.trades.front().is_none_or(|oldest| from < oldest.timestamp))
+            && last.is_none_or(|last| end - start < last);
+
+        let page = end.min(start + limit);
+        let next = (page < end).then(|| self.trades[page - 1].id);
+
+        (self.trades.range(start..page).copied().collect(), truncated, next)
+    }
+}
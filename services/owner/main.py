@@ -31,20 +31,66 @@ def execute_sql(query, params=None, mode='select', db=None):
             conn.commit()
 
 
-def callback(message):
-    data = json.loads(bytes.decode(message.data))
-    print('Data:', data)
+ORDER_STATUSES = ('Opened', 'Filled', 'Canceled', 'Expired')
+
+# per asset, the next event sequence number expected from the engine and any still missing
+next_sequence = {}
+missing_sequences = {}
+
+publisher = pubsub_v1.PublisherClient()
+
+
+# the engine goes back over at most this many events per retransmission request
+MAX_RETRANSMIT = 1000
+
+
+def request_retransmit(asset, start, end):
+    topic = f'projects/{os.getenv("GOOGLE_CLOUD_PROJECT") or "project-steelieman"}/topics/{asset}'
+
+    for chunk in range(start, end + 1, MAX_RETRANSMIT):
+        request = {'Retransmit': {'from': chunk, 'to': min(chunk + MAX_RETRANSMIT - 1, end)}}
+        publisher.publish(topic, json.dumps(request).encode()).result()
+
+
+def unseen_events(asset, events):
+    """Drop events already processed and ask the engine again for any that were skipped."""
+    expected = next_sequence.get(asset)
+    missing = missing_sequences.setdefault(asset, set())
+    unseen = []
 
+    for event in events:
+        sequence = event['sequence']
+
+        if expected is None or sequence == expected:
+            unseen.append(event)
+        elif sequence > expected:
+            print(f'WARNING: {asset} events {expected} to {sequence - 1} are missing, requesting retransmission')
+            missing.update(range(expected, sequence))
+            request_retransmit(asset, expected, sequence - 1)
+            unseen.append(event)
+        elif sequence in missing:
+            missing.discard(sequence)
+            unseen.append(event)
+        else:
+            continue
+
+        expected = max(expected or 0, sequence + 1)
+
+    next_sequence[asset] = expected
+    return unseen
+
+
+def process_envelope(data):
     asset = data['asset']
 
     if 'events' not in data:
         print(f'WARNING: Message does not contain any events ---- {data}')
-        message.ack()
         return
 
-    # filter out bounce events
-    for event in filter(lambda x: 'Bounce' not in x, data['events']):
-        status, event = list(event.items())[0]
+    # only order lifecycle events update accounts, skip bounces and market wide events (phase changes, etc.)
+    for event in filter(lambda x: any(status in x for status in ORDER_STATUSES), unseen_events(asset, data['events'])):
+        status = next(status for status in ORDER_STATUSES if status in event)
+        event = event[status]
 
         owner = uuid.UUID(event['owner']).int
         order = uuid.UUID(event['id']).int
@@ -69,6 +115,15 @@ def callback(message):
 
         execute_sql(query, params=(owner, order, parent, asset, price, size, direction, status), mode='commit', db='owner')
 
+
+def callback(message):
+    data = json.loads(bytes.decode(message.data))
+    print('Data:', data)
+
+    # the engine publishes its envelopes in batches, in the order they were published
+    for envelope in data if isinstance(data, list) else [data]:
+        process_envelope(envelope)
+
     message.ack()
 
 
@@ -31,40 +31,70 @@ def dict_update(d, k, v):
     return d
 
 
-def callback(message):
-    data = json.loads(bytes.decode(message.data))
-    print('Data:', data)
+# price tables already known to have the candle schema
+migrated_tables = set()
+
+
+def migrate_price_table(table):
+    """Bring a price table from before candles, one row of trades per second, up to one bar per interval."""
+    if table in migrated_tables:
+        return
+
+    columns = execute_sql("""
+        SELECT column_name FROM information_schema.columns
+        WHERE table_schema = DATABASE() AND table_name = %s;
+    """, params=(f'{table}_price',), mode='select', db='price')
+
+    if columns and 'resolution' not in {column[0].lower() for column in columns}:
+        print(f'Migrating {table}_price to candles')
+        query = """
+        ALTER TABLE {}_price
+            ADD COLUMN resolution varchar(2) not null default '1s' FIRST,
+            MODIFY COLUMN volume float not null,
+            ADD COLUMN vwap float null,
+            ADD COLUMN trades bigint not null default 0,
+            DROP PRIMARY KEY,
+            ADD PRIMARY KEY (resolution, market_time);
+        """.format(table)
+
+        execute_sql(query, mode='commit', db='price')
+
+    migrated_tables.add(table)
 
+
+def process_envelope(data):
     table = data['asset']
 
     if 'events' not in data:
         print(f'WARNING: Message does not contain any events ---- {data}')
-        message.ack()
         return
 
     events = data['events']
+    migrate_price_table(table.lower())
 
-    # must be 3 events for a fill to have occured (1 open, 2 fills (bid / ask))
-    if len(events) >= 3 and 'Filled' in events[-2]:
-        fill_events = list(map(lambda x: x['Filled'], filter(lambda x: 'Filled' in x, events)))
-
-        vol = int(fill_events[-1]['size'])
-        ts = int(fill_events[0]['timestamp'])
-        price = sum(map(lambda x: float(x['price']) * float(x['size']), fill_events)) / (2 * float(vol))
-
+    # the engine publishes a finished bar for every interval, empty intervals included
+    for candle in (event['Candle'] for event in events if 'Candle' in event):
         query = """
-        INSERT INTO {}_price
-            (market_time, open, low, high, close, volume)
+        REPLACE INTO {}_price
+            (resolution, market_time, open, low, high, close, volume, vwap, trades)
         VALUES
-            (%s, %s, %s, %s, %s, %s)
-        ON DUPLICATE KEY UPDATE
-            low = LEAST(low, VALUES(low)),
-            high = GREATEST(high, VALUES(high)),
-            close = VALUES(close),
-            volume = volume + VALUES(volume);
+            (%s, %s, %s, %s, %s, %s, %s, %s, %s);
         """.format(table.lower())
 
-        execute_sql(query, params=(ts, price, price, price, price, vol), mode='commit', db='price')
+        vwap = float(candle['vwap']) if candle['vwap'] is not None else None
+        params = (candle['interval'], int(candle['start']), float(candle['open']), float(candle['low']),
+                  float(candle['high']), float(candle['close']), float(candle['volume']), vwap, int(candle['trades']))
+
+        execute_sql(query, params=params, mode='commit', db='price')
+
+
+def callback(message):
+    data = json.loads(bytes.decode(message.data))
+    print('Data:', data)
+
+    # the engine publishes its envelopes in batches, in the order they were published
+    for envelope in data if isinstance(data, list) else [data]:
+        process_envelope(envelope)
 
     message.ack()
 
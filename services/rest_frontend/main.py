@@ -15,6 +15,9 @@ import mysql.connector
 
 app = Flask(__name__)
 
+# candle intervals published by the engine
+CANDLE_INTERVALS = ('1s', '1m', '5m', '1h')
+
 db_config = json.load(open('/etc/secret-volume/db_config.json'))
 
 
@@ -136,6 +139,23 @@ def _parse_order(data, errs):
     return order
 
 
+def _parse_optional_uuid(data, errs, name):
+    value = data.get(name)
+
+    if value is None:
+        return None
+
+    if not isinstance(value, str):
+        errs.append(f'Expected field `{name}` for be of type str')
+    else:
+        try:
+            uuid.UUID(value)
+        except ValueError:
+            errs.append(f'Field `{name}` must be a valid UUID.')
+
+    return value
+
+
 def _parse_timestamp(data, errs, name=None):
     ts = None
 
@@ -187,6 +207,8 @@ def submit_order():
     direction = _parse_direction(data, errs)
     price = _parse_price(data, errs)
     size = _parse_size(data, errs)
+    client_id = _parse_optional_uuid(data, errs, 'client_id')
+    request_id = _parse_optional_uuid(data, errs, 'request_id')
 
     if len(errs):
         # return errors
@@ -203,7 +225,10 @@ def submit_order():
                 'direction': direction,
                 'timestamp': 0,
                 'uuid': None,
-            }
+                'client_id': client_id,
+                'request_id': request_id,
+            },
+            'asset': asset,
         }
 
         res = publisher.publish(topic, json.dumps(order).encode()).result()
@@ -222,6 +247,8 @@ def cancel_order():
     owner = _parse_owner(data, errs)
     asset = _parse_asset(data, errs)
     order = _parse_order(data, errs)
+    client_id = _parse_optional_uuid(data, errs, 'client_id')
+    request_id = _parse_optional_uuid(data, errs, 'request_id')
 
     if len(errs):
         # return errors
@@ -235,7 +262,10 @@ def cancel_order():
                 'owner': owner,
                 'id': order,
                 'timestamp': 0,
-            }
+                'client_id': client_id,
+                'request_id': request_id,
+            },
+            'asset': asset,
         }
 
         res = publisher.publish(topic, json.dumps(cancel).encode()).result()
@@ -285,8 +315,11 @@ def price():
     asset = _parse_asset(data, errs)
     start_time = _parse_timestamp(data, errs, name='start_time')
     end_time = _parse_timestamp(data, errs, name='end_time')
+    resolution = data.get('interval', '1m')
 
-    if start_time > end_time:
+    if resolution not in CANDLE_INTERVALS:
+        errs.append(f'Field `interval` must be one of {list(CANDLE_INTERVALS)}')
+    elif start_time > end_time:
         errs.append('start_time must be leq end_time.')
     else:
         query = """
@@ -295,11 +328,11 @@ def price():
             FROM
                 {}_price
             WHERE
-                market_time >= %s AND market_time <= %s;
+                resolution = %s AND market_time >= %s AND market_time <= %s;
         """.format(asset.lower())
 
         try:
-            res = execute_sql(query, params=(start_time, end_time), mode='select', db='price')
+            res = execute_sql(query, params=(resolution, start_time, end_time), mode='select', db='price')
         except Exception as e:
             errs.append(str(e))
 